@@ -4,6 +4,7 @@
 //! transactions to the Bitcoin P2P network.
 
 use bitcoin::BlockHash;
+use kyoto::db::memory::{headers::MemoryHeaderStore, peers::MemoryPeerStore};
 use kyoto::node::messages::NodeMessage;
 use kyoto::{chain::checkpoints::HeaderCheckpoint, node::builder::NodeBuilder};
 use std::collections::HashSet;
@@ -43,8 +44,8 @@ async fn main() {
         ))
         // The number of connections we would like to maintain
         .num_required_peers(1)
-        // Create the node and client without the usual SQL databases
-        .build_node_with_custom_databases((), ())
+        // Create the node and client, keeping peers and headers in memory instead of the usual SQL databases
+        .build_node_with_custom_databases(MemoryPeerStore::new(), MemoryHeaderStore::new())
         .await;
     // Run the node
     tokio::task::spawn(async move { node.run().await });
@@ -54,7 +55,7 @@ async fn main() {
     let (mut sender, mut receiver) = client.split();
     // Continually listen for events until the node is synced to its peers.
     loop {
-        if let Ok(message) = receiver.recv().await {
+        if let Some(message) = receiver.recv().await {
             match message {
                 NodeMessage::Dialog(d) => tracing::info!("{}", d),
                 NodeMessage::Warning(e) => tracing::warn!("{}", e),
@@ -63,9 +64,18 @@ async fn main() {
                 NodeMessage::BlocksDisconnected(r) => {
                     let _ = r;
                 }
-                NodeMessage::TxBroadcastFailure => {
-                    tracing::error!("The transaction could not be broadcast.")
+                NodeMessage::TxSent(t) => tracing::info!("Transaction sent: {}", t),
+                NodeMessage::TxSentPrivately(t) => {
+                    tracing::info!("Transaction sent privately: {}", t)
                 }
+                NodeMessage::TxSeen(t) => tracing::info!("Transaction relayed: {}", t),
+                NodeMessage::TxConfirmed(t) => {
+                    tracing::info!("Transaction confirmed in block {}", t.height)
+                }
+                NodeMessage::TxBroadcastFailure(f) => {
+                    tracing::error!("The transaction {} could not be broadcast.", f.txid)
+                }
+                NodeMessage::Lagged(n) => tracing::warn!("{} log messages were dropped", n),
                 NodeMessage::Synced(tip) => {
                     tracing::info!("Synced chain up to block {}", tip.height,);
                     tracing::info!("Chain tip: {}", tip.hash.to_string(),);
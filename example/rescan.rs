@@ -53,7 +53,7 @@ async fn main() {
     let (mut sender, mut receiver) = client.split();
     // Sync with the single script added
     loop {
-        if let Ok(message) = receiver.recv().await {
+        if let Some(message) = receiver.recv().await {
             match message {
                 NodeMessage::Dialog(d) => tracing::info!("{}", d),
                 NodeMessage::Warning(e) => tracing::warn!("{}", e),
@@ -81,7 +81,7 @@ async fn main() {
     sender.rescan().await.unwrap();
     tracing::info!("Starting rescan");
     loop {
-        if let Ok(message) = receiver.recv().await {
+        if let Some(message) = receiver.recv().await {
             match message {
                 NodeMessage::Dialog(d) => tracing::info!("{}", d),
                 NodeMessage::Warning(e) => tracing::warn!("{}", e),
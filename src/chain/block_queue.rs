@@ -1,41 +1,247 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
 
 use bitcoin::BlockHash;
 
+// The number of blocks a single peer may be asked for at once
+const MAX_BLOCKS_PER_PEER: usize = 2;
+// A block may be up to 4MB, so peers on slow connections are given some time
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct BlockRequest {
+    peer: u32,
+    sent: Instant,
+}
+
+// Blocks that must be downloaded, and the peers they were requested from
 #[derive(Debug)]
 pub(crate) struct BlockQueue {
     queue: VecDeque<BlockHash>,
-    want: usize,
+    in_flight: HashMap<BlockHash, BlockRequest>,
+    // The peers that did not deliver a block are not asked for it again until the timeout passes
+    failed: HashMap<BlockHash, HashMap<u32, Instant>>,
 }
 
 impl BlockQueue {
     pub(crate) fn new() -> Self {
         Self {
             queue: VecDeque::new(),
-            want: 0,
+            in_flight: HashMap::new(),
+            failed: HashMap::new(),
         }
     }
 
     pub(crate) fn add(&mut self, block: BlockHash) {
         if !self.contains(&block) {
-            self.want += 1;
             self.queue.push_front(block)
         }
     }
 
-    pub(crate) fn contains(&mut self, block: &BlockHash) -> bool {
-        self.queue.contains(block)
+    pub(crate) fn contains(&self, block: &BlockHash) -> bool {
+        self.queue.contains(block) || self.in_flight.contains_key(block)
     }
 
-    pub(crate) fn pop(&mut self) -> Option<BlockHash> {
-        self.queue.pop_back()
+    // Request the blocks in the queue from the peers with the fewest blocks in flight, preferring the peers
+    // that come first. Returns the peer each block should be requested from.
+    pub(crate) fn assign(&mut self, peers: &[u32], now: Instant) -> Vec<(u32, BlockHash)> {
+        // Blocks requested from peers that are no longer connected must be sent elsewhere
+        let gone: Vec<u32> = self
+            .in_flight
+            .values()
+            .map(|request| request.peer)
+            .filter(|peer| !peers.contains(peer))
+            .collect();
+        for peer in gone {
+            self.release(peer);
+        }
+        let mut load: HashMap<u32, usize> = peers.iter().map(|peer| (*peer, 0)).collect();
+        for request in self.in_flight.values() {
+            if let Some(count) = load.get_mut(&request.peer) {
+                *count += 1;
+            }
+        }
+        let mut assigned = Vec::new();
+        let mut skipped = VecDeque::new();
+        while let Some(block) = self.queue.pop_back() {
+            let failed = self.failed.get(&block);
+            let peer = peers
+                .iter()
+                .enumerate()
+                .filter(|(_, peer)| load[peer] < MAX_BLOCKS_PER_PEER)
+                .filter(|(_, peer)| {
+                    failed
+                        .and_then(|failed| failed.get(peer))
+                        .map_or(true, |failed_at| now.ge(&(*failed_at + BLOCK_TIMEOUT)))
+                })
+                .min_by_key(|(preference, peer)| (load[peer], *preference))
+                .map(|(_, peer)| *peer);
+            match peer {
+                Some(peer) => {
+                    *load.entry(peer).or_default() += 1;
+                    self.in_flight
+                        .insert(block, BlockRequest { peer, sent: now });
+                    assigned.push((peer, block));
+                }
+                None => skipped.push_front(block),
+            }
+        }
+        self.queue = skipped;
+        assigned
+    }
+
+    // A block was received from a peer. Returns false if the block is not one we are waiting for.
+    pub(crate) fn received(&mut self, block: &BlockHash) -> bool {
+        let requested = self.in_flight.remove(block).is_some();
+        let queued = self.queue.contains(block);
+        self.queue.retain(|queued| queued.ne(block));
+        self.failed.remove(block);
+        requested || queued
     }
 
-    pub(crate) fn receive_one(&mut self) {
-        self.want = self.want.saturating_sub(1);
+    // The peer failed to deliver a valid block, so the block is requested from another peer
+    pub(crate) fn failed(&mut self, peer: u32, block: BlockHash, now: Instant) {
+        self.in_flight.remove(&block);
+        self.failed.entry(block).or_default().insert(peer, now);
+        if !self.queue.contains(&block) {
+            self.queue.push_back(block);
+        }
+    }
+
+    // The peer does not have these blocks. Returns true if any of them were requested from the peer.
+    pub(crate) fn not_found(&mut self, peer: u32, blocks: &[BlockHash], now: Instant) -> bool {
+        let mut requested = false;
+        for block in blocks {
+            if self
+                .in_flight
+                .get(block)
+                .map_or(false, |request| request.peer.eq(&peer))
+            {
+                self.failed(peer, *block, now);
+                requested = true;
+            }
+        }
+        requested
+    }
+
+    // Requeue the blocks that were not delivered in time, returning the peers that failed to deliver them
+    pub(crate) fn timed_out(&mut self, now: Instant) -> Vec<u32> {
+        let late: Vec<(u32, BlockHash)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| {
+                now.saturating_duration_since(request.sent)
+                    .gt(&BLOCK_TIMEOUT)
+            })
+            .map(|(block, request)| (request.peer, *block))
+            .collect();
+        let mut peers = Vec::new();
+        for (peer, block) in late {
+            self.failed(peer, block, now);
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+
+    fn release(&mut self, peer: u32) {
+        let blocks: Vec<BlockHash> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.peer.eq(&peer))
+            .map(|(block, _)| *block)
+            .collect();
+        for block in blocks {
+            self.in_flight.remove(&block);
+            self.queue.push_back(block);
+        }
     }
 
     pub(crate) fn complete(&self) -> bool {
-        self.want.eq(&0) && self.queue.is_empty()
+        self.queue.is_empty() && self.in_flight.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    fn block(byte: u8) -> BlockHash {
+        BlockHash::from_byte_array([byte; 32])
+    }
+
+    #[test]
+    fn test_spreads_blocks_across_peers() {
+        let mut queue = BlockQueue::new();
+        for byte in 0..6 {
+            queue.add(block(byte));
+        }
+        let now = Instant::now();
+        let assigned = queue.assign(&[1, 2], now);
+        // Blocks are requested in the order they were found, at most two from each peer
+        assert_eq!(
+            assigned,
+            vec![(1, block(0)), (2, block(1)), (1, block(2)), (2, block(3))]
+        );
+        assert!(queue.assign(&[1, 2], now).is_empty());
+        assert!(queue.received(&block(0)));
+        // A block is only scanned once
+        assert!(!queue.received(&block(0)));
+        assert_eq!(queue.assign(&[1, 2], now), vec![(1, block(4))]);
+        assert!(!queue.complete());
+    }
+
+    #[test]
+    fn test_prefers_peers_that_come_first() {
+        let mut queue = BlockQueue::new();
+        for byte in 0..3 {
+            queue.add(block(byte));
+        }
+        let now = Instant::now();
+        assert_eq!(
+            queue.assign(&[3, 1], now),
+            vec![(3, block(0)), (1, block(1)), (3, block(2))]
+        );
+    }
+
+    #[test]
+    fn test_retries_blocks_on_other_peers() {
+        let mut queue = BlockQueue::new();
+        queue.add(block(0));
+        queue.add(block(1));
+        let now = Instant::now();
+        assert_eq!(queue.assign(&[1], now).len(), 2);
+        // A peer is only at fault for blocks we asked it for
+        assert!(!queue.not_found(2, &[block(0)], now));
+        assert!(queue.not_found(1, &[block(0)], now));
+        // The peer that does not have the block is not asked again
+        assert!(queue.assign(&[1], now).is_empty());
+        assert_eq!(queue.assign(&[1, 2], now), vec![(2, block(0))]);
+        let later = now + BLOCK_TIMEOUT + Duration::from_secs(1);
+        let mut late = queue.timed_out(later);
+        late.sort();
+        assert_eq!(late, vec![1, 2]);
+        assert_eq!(queue.assign(&[1, 2, 3], later).len(), 2);
+        assert!(queue.received(&block(0)));
+        assert!(queue.received(&block(1)));
+        assert!(queue.complete());
+    }
+
+    #[test]
+    fn test_requeues_blocks_of_disconnected_peers() {
+        let mut queue = BlockQueue::new();
+        queue.add(block(0));
+        let now = Instant::now();
+        assert_eq!(queue.assign(&[1], now), vec![(1, block(0))]);
+        assert_eq!(queue.assign(&[2], now), vec![(2, block(0))]);
+        // A peer that sent an invalid block is asked again once the timeout passes
+        queue.failed(2, block(0), now);
+        assert!(queue.assign(&[2], now).is_empty());
+        assert_eq!(queue.assign(&[2], now + BLOCK_TIMEOUT), vec![(2, block(0))]);
     }
 }
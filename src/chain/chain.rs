@@ -1,14 +1,16 @@
 extern crate alloc;
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use bitcoin::{
     block::Header,
     consensus::Params,
     p2p::message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
-    Block, BlockHash, Network, ScriptBuf, TxIn, TxOut, Work,
+    Block, BlockHash, FilterHash, FilterHeader, Network, OutPoint, ScriptBuf, Transaction, Txid,
+    Work,
 };
 use tokio::sync::Mutex;
 
@@ -20,14 +22,15 @@ use super::{
 };
 use crate::{
     chain::header_batch::HeadersBatch,
-    db::traits::HeaderStore,
+    db::traits::{FilterHeaderStore, FilterStore, HeaderStore, OutPointStore},
     filters::{
         cfheader_batch::CFHeaderBatch,
         cfheader_chain::{AppendAttempt, CFHeaderChain, CFHeaderSyncResult},
-        error::{CFHeaderSyncError, CFilterSyncError},
+        dispute::FilterDispute,
+        error::{CFHeaderSyncError, CFilterSyncError, FilterError},
         filter::Filter,
         filter_chain::FilterChain,
-        CF_HEADER_BATCH_SIZE, FILTER_BATCH_SIZE,
+        CF_HEADER_BATCH_SIZE,
     },
     node::{dialog::Dialog, messages::NodeMessage},
     prelude::{params_from_network, MEDIAN_TIME_PAST},
@@ -35,6 +38,10 @@ use crate::{
 };
 
 const MAX_REORG_DEPTH: u32 = 5_000;
+// Load persisted headers from the database in batches
+const HEADER_LOAD_BATCH_SIZE: u32 = 2_000;
+// Load cached filters from the database in batches
+const CACHED_FILTER_BATCH_SIZE: u32 = 1_000;
 
 #[derive(Debug)]
 pub(crate) struct Chain {
@@ -44,13 +51,27 @@ pub(crate) struct Chain {
     checkpoints: HeaderCheckpoints,
     params: Params,
     db: Arc<Mutex<dyn HeaderStore + Send + Sync>>,
+    cf_header_db: Box<dyn FilterHeaderStore + Send + Sync>,
+    filter_db: Box<dyn FilterStore + Send + Sync>,
     best_known_height: Option<u32>,
     scripts: HashSet<ScriptBuf>,
+    // The scripts every filter since the anchor is checked for. Scripts added while syncing are missing
+    // from the filters that were already checked.
+    scanned_scripts: HashSet<ScriptBuf>,
+    outpoints: HashSet<OutPoint>,
+    outpoint_db: Box<dyn OutPointStore + Send + Sync>,
+    // The output scripts of the transactions we broadcast, so the block that confirms one is downloaded
+    // even if it pays none of our scripts
+    broadcast_scripts: HashMap<Txid, HashSet<ScriptBuf>>,
     block_queue: BlockQueue,
+    block_filters: HashMap<BlockHash, Filter>,
+    filter_dispute: Option<FilterDispute>,
+    time_offset: i64,
     dialog: Dialog,
 }
 
 impl Chain {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         network: &Network,
         scripts: HashSet<ScriptBuf>,
@@ -58,64 +79,167 @@ impl Chain {
         mut checkpoints: HeaderCheckpoints,
         mut dialog: Dialog,
         mut db: impl HeaderStore + Send + Sync + 'static,
+        mut cf_header_db: Box<dyn FilterHeaderStore + Send + Sync>,
+        filter_db: Box<dyn FilterStore + Send + Sync>,
+        mut outpoint_db: impl OutPointStore + Send + Sync + 'static,
         quorum_required: usize,
     ) -> Result<Self, HeaderPersistenceError> {
         let params = params_from_network(network);
-        let mut loaded_headers = db
-            .load(anchor.height)
-            .await
-            .map_err(|_| HeaderPersistenceError::SQLite)?;
-        if loaded_headers.len().gt(&0) {
-            if loaded_headers
-                .values()
-                .take(1)
-                .copied()
-                .collect::<Vec<Header>>()
-                .first()
-                .unwrap()
-                .prev_blockhash
-                .ne(&anchor.hash)
-            {
+        let header_chain =
+            Self::load_headers(&mut db, &mut dialog, anchor, &mut checkpoints).await?;
+        let outpoints = match outpoint_db.load().await {
+            Ok(outpoints) => outpoints,
+            Err(_) => {
                 dialog
-                    .send_warning("Checkpoint anchor mismatch".into())
-                    .await;
-                // The header chain did not align, so just start from the anchor
-                loaded_headers = BTreeMap::new();
-            } else if loaded_headers
-                .iter()
-                .zip(loaded_headers.iter().skip(1))
-                .any(|(first, second)| first.1.block_hash().ne(&second.1.prev_blockhash))
-            {
-                dialog
-                    .send_warning("Blockhash pointer mismatch".into())
+                    .send_warning("Could not load the watched outpoints from the database".into())
                     .await;
-                return Err(HeaderPersistenceError::HeadersDoNotLink);
+                HashSet::new()
             }
-            loaded_headers.iter().for_each(|header| {
-                if let Some(checkpoint) = checkpoints.next() {
-                    if header.1.block_hash().eq(&checkpoint.hash) {
-                        checkpoints.advance()
-                    }
-                }
-            })
         };
-        let header_chain = HeaderChain::new(anchor, loaded_headers);
-        let cf_header_chain = CFHeaderChain::new(anchor, quorum_required);
+        let loaded_cf_headers = Self::load_cf_headers(
+            cf_header_db.as_mut(),
+            &mut dialog,
+            anchor,
+            header_chain.height(),
+        )
+        .await;
+        let cf_header_chain = CFHeaderChain::new(anchor, loaded_cf_headers, quorum_required);
         let filter_chain = FilterChain::new(anchor);
         Ok(Chain {
             header_chain,
             checkpoints,
             params,
             db: Arc::new(Mutex::new(db)),
+            cf_header_db,
+            filter_db,
             cf_header_chain,
             filter_chain,
             best_known_height: None,
+            scanned_scripts: scripts.clone(),
             scripts,
+            outpoints,
+            outpoint_db: Box::new(outpoint_db),
+            broadcast_scripts: HashMap::new(),
             block_queue: BlockQueue::new(),
+            block_filters: HashMap::new(),
+            filter_dispute: None,
+            time_offset: 0,
             dialog,
         })
     }
 
+    // Load the headers persisted in a previous session, keeping only the most recent headers in memory
+    async fn load_headers(
+        db: &mut (impl HeaderStore + Send + Sync),
+        dialog: &mut Dialog,
+        anchor: HeaderCheckpoint,
+        checkpoints: &mut HeaderCheckpoints,
+    ) -> Result<HeaderChain, HeaderPersistenceError> {
+        let mut header_chain = HeaderChain::new(anchor, BTreeMap::new());
+        loop {
+            let start_height = header_chain.height() + 1;
+            let loaded = db
+                .headers_in_range(start_height, start_height + HEADER_LOAD_BATCH_SIZE - 1)
+                .await
+                .map_err(|_| HeaderPersistenceError::SQLite)?;
+            let num_loaded = loaded.len() as u32;
+            let batch: Vec<Header> = loaded.into_values().collect();
+            if let Some(first) = batch.first() {
+                if first.prev_blockhash.ne(&header_chain.tip()) {
+                    // The header chain did not align, so just start from the anchor
+                    if header_chain.height().eq(&anchor.height) {
+                        dialog
+                            .send_warning("Checkpoint anchor mismatch".into())
+                            .await;
+                        break;
+                    }
+                    dialog
+                        .send_warning("Blockhash pointer mismatch".into())
+                        .await;
+                    return Err(HeaderPersistenceError::HeadersDoNotLink);
+                }
+            }
+            if batch
+                .iter()
+                .zip(batch.iter().skip(1))
+                .any(|(first, second)| first.block_hash().ne(&second.prev_blockhash))
+            {
+                dialog
+                    .send_warning("Blockhash pointer mismatch".into())
+                    .await;
+                return Err(HeaderPersistenceError::HeadersDoNotLink);
+            }
+            header_chain.extend(&batch);
+            for height in start_height..start_height + num_loaded {
+                if let Some(checkpoint) = checkpoints.next() {
+                    if header_chain
+                        .block_hash_at_height(height)
+                        .map_or(false, |hash| hash.eq(&checkpoint.hash))
+                    {
+                        checkpoints.advance()
+                    }
+                }
+            }
+            // The headers were read from the database, so they may be pruned right away
+            if let Some(height) = header_chain.prune_height() {
+                header_chain.prune(height);
+            }
+            if num_loaded.lt(&HEADER_LOAD_BATCH_SIZE) {
+                break;
+            }
+        }
+        Ok(header_chain)
+    }
+
+    // Load the filter headers verified in a previous session. The filter headers must link together
+    // and cannot extend past the header chain, otherwise the remaining filter headers are removed.
+    async fn load_cf_headers(
+        cf_header_db: &mut (dyn FilterHeaderStore + Send + Sync),
+        dialog: &mut Dialog,
+        anchor: HeaderCheckpoint,
+        height: u32,
+    ) -> BTreeMap<u32, (FilterHeader, FilterHash)> {
+        let loaded = match cf_header_db.load(anchor.height).await {
+            Ok(loaded) => loaded,
+            Err(_) => {
+                dialog
+                    .send_warning("Could not load filter headers from the database".into())
+                    .await;
+                return BTreeMap::new();
+            }
+        };
+        let num_loaded = loaded.len();
+        let mut linked = BTreeMap::new();
+        let mut prev_header: Option<FilterHeader> = None;
+        for (index, (filter_height, (filter_header, filter_hash))) in loaded.into_iter().enumerate()
+        {
+            if filter_height.ne(&(anchor.height + index as u32 + 1)) || filter_height.gt(&height) {
+                break;
+            }
+            if let Some(prev_header) = prev_header {
+                if filter_hash.filter_header(&prev_header).ne(&filter_header) {
+                    dialog
+                        .send_warning("Filter header pointer mismatch".into())
+                        .await;
+                    break;
+                }
+            }
+            prev_header = Some(filter_header);
+            linked.insert(filter_height, (filter_header, filter_hash));
+        }
+        if linked.len().lt(&num_loaded)
+            && cf_header_db
+                .truncate(anchor.height + linked.len() as u32)
+                .await
+                .is_err()
+        {
+            dialog
+                .send_warning("Could not remove invalid filter headers from the database".into())
+                .await;
+        }
+        linked
+    }
+
     // Top of the chain
     pub(crate) fn tip(&self) -> BlockHash {
         self.header_chain.tip()
@@ -126,21 +250,45 @@ impl Chain {
         self.header_chain.height()
     }
 
-    // This header chain contains a block hash
+    // The headers in memory contain a block hash
     pub(crate) fn contains_hash(&self, blockhash: BlockHash) -> bool {
         self.header_chain.contains_hash(blockhash)
     }
 
-    // This header chain contains a block hash
+    // The height of a block hash in our chain. Headers pruned from memory are read from the database.
     pub(crate) async fn height_of_hash(&self, blockhash: BlockHash) -> Option<u32> {
-        self.header_chain.height_of_hash(blockhash).await
+        if let Some(height) = self.header_chain.height_of_hash(blockhash) {
+            return Some(height);
+        }
+        let height = self
+            .db
+            .lock()
+            .await
+            .height_of(&blockhash)
+            .await
+            .ok()
+            .flatten()?;
+        // The database may have headers of a fork above the headers in memory
+        if self.header_chain.is_pruned(height) {
+            Some(height)
+        } else {
+            None
+        }
     }
 
-    // This header chain contains a block hash
+    // The header at a height, if it is in memory
     pub(crate) fn header_at_height(&self, height: u32) -> Option<&Header> {
         self.header_chain.header_at_height(height)
     }
 
+    // The block hash at a height in our chain. Headers pruned from memory are read from the database.
+    pub(crate) async fn block_hash_at_height(&self, height: u32) -> Option<BlockHash> {
+        if !self.header_chain.is_pruned(height) {
+            return self.header_chain.block_hash_at_height(height);
+        }
+        self.db.lock().await.hash_at(height).await.ok().flatten()
+    }
+
     // This header chain contains a block hash
     pub(crate) fn contains_header(&self, header: &Header) -> bool {
         self.header_chain.contains_header(header)
@@ -222,6 +370,50 @@ impl Chain {
         }
     }
 
+    // Write the filter headers strictly after a height to disk
+    async fn flush_cf_headers(&mut self, height: u32) {
+        if let Err(e) = self
+            .cf_header_db
+            .write(&self.cf_header_chain.headers_after(height))
+            .await
+        {
+            self.dialog
+                .send_warning(format!("Error persisting filter headers to storage: {}", e))
+                .await;
+        }
+    }
+
+    // The filter headers strictly after a height commit to blocks that are no longer in the chain
+    async fn truncate_cf_headers(&mut self, height: u32) {
+        if self.cf_header_chain.height().le(&height) {
+            return;
+        }
+        self.cf_header_chain.truncate(height);
+        if let Err(e) = self.cf_header_db.truncate(height).await {
+            self.dialog
+                .send_warning(format!("Error removing filter headers from storage: {}", e))
+                .await;
+        }
+        if let Err(e) = self.filter_db.truncate(height).await {
+            self.dialog
+                .send_warning(format!("Error removing filters from storage: {}", e))
+                .await;
+        }
+    }
+
+    // Our clock adjusted by the median offset of our peers
+    fn adjusted_time(&self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_secs();
+        now as i64 + self.time_offset
+    }
+
+    pub(crate) fn set_time_offset(&mut self, offset: i64) {
+        self.time_offset = offset;
+    }
+
     // Sync the chain with headers from a peer, adjusting to reorgs if needed
     pub(crate) async fn sync_chain(&mut self, message: Vec<Header>) -> Result<(), HeaderSyncError> {
         let header_batch = HeadersBatch::new(message).map_err(|_| HeaderSyncError::EmptyMessage)?;
@@ -235,24 +427,42 @@ impl Chain {
         // How we handle forks depends on if we are caught up through all checkpoints or not
         if initially_syncing {
             self.catch_up_sync(header_batch).await?;
-        } else {
+        } else if self.tip().eq(&header_batch.first().prev_blockhash) {
             // Nothing left to do but add the headers to the chain
-            if self.tip().eq(&header_batch.first().prev_blockhash) {
-                self.header_chain.extend(header_batch.inner());
-                return Ok(());
-            }
+            self.header_chain.extend(header_batch.inner());
+        } else {
             // We see if we have this previous hash in the database, and reload our
             // chain from that hash if so.
             let fork_start_hash = header_batch.first().prev_blockhash;
             if !self.contains_hash(fork_start_hash) {
                 self.load_fork(&header_batch).await?;
+                // Now that the stem of the fork is loaded, we can check the difficulty
+                self.audit_difficulty(&header_batch).await?;
             }
             //
             self.evaluate_fork(&header_batch).await?;
         }
+        self.prune_headers().await;
         Ok(())
     }
 
+    // Remove the older headers from memory once they can be read from the database. A database that does not
+    // return the headers written to it, like the empty database, keeps every header in memory.
+    async fn prune_headers(&mut self) {
+        let height = match self.header_chain.prune_height() {
+            Some(height) => height,
+            None => return,
+        };
+        self.flush_to_disk().await;
+        let persisted = self.db.lock().await.header_at(height).await;
+        match persisted {
+            Ok(Some(header)) if self.header_at_height(height).eq(&Some(&header)) => {
+                self.header_chain.prune(height)
+            }
+            _ => (),
+        }
+    }
+
     // These are invariants in all batches of headers we receive
     async fn sanity_check(&mut self, header_batch: &HeadersBatch) -> Result<(), HeaderSyncError> {
         let initially_syncing = !self.checkpoints.is_exhausted();
@@ -273,6 +483,19 @@ impl Chain {
             return Err(HeaderSyncError::InvalidHeaderWork);
         }
 
+        // No header is too far ahead of the time of our peers
+        if !header_batch.valid_future_times(self.adjusted_time()).await {
+            return Err(HeaderSyncError::InvalidHeaderTimes);
+        }
+
+        // All headers have a target at or below the maximum target of the network
+        if !header_batch.meet_network_minimum(&self.params).await {
+            return Err(HeaderSyncError::InvalidHeaderWork);
+        }
+
+        // The headers only change difficulty when allowed
+        self.audit_difficulty(header_batch).await?;
+
         // The headers have times that are greater than the median of the previous 11 blocks
         let mut last_relevant_mtp = self.header_chain.last_median_time_past_window();
         if !header_batch
@@ -301,10 +524,9 @@ impl Chain {
         // We need to check a hard-coded checkpoint
         if self.height().ge(&checkpoint.height) {
             if self
-                .header_chain
-                .header_at_height(checkpoint.height)
+                .block_hash_at_height(checkpoint.height)
+                .await
                 .expect("height is greater than the base checkpoint")
-                .block_hash()
                 .eq(&checkpoint.hash)
             {
                 self.dialog
@@ -334,7 +556,22 @@ impl Chain {
         Ok(())
     }
 
-    // audit the difficulty adjustment of the blocks we received
+    // Audit the difficulty of the blocks we received. Every block must have the bits required by the blocks before it,
+    // following the adjustment rules of the network.
+    async fn audit_difficulty(&self, header_batch: &HeadersBatch) -> Result<(), HeaderSyncError> {
+        let prev_hash = header_batch.first().prev_blockhash;
+        // A fork we have not loaded yet is audited after it is loaded
+        let prev_height = match self.height_of_hash(prev_hash).await {
+            Some(height) => height,
+            None => return Ok(()),
+        };
+        // The anchor is not a full header, so headers that depend on it are only partially checked
+        header_batch
+            .valid_difficulty_transitions(&self.params, prev_height + 1, |height| {
+                self.header_at_height(height).copied()
+            })
+            .await
+    }
 
     // This function draws from the neutrino implemention, where even if a fork is valid
     // we only accept it if there is more work provided. otherwise, we disconnect the peer sending
@@ -374,6 +611,7 @@ impl Chain {
                     .send_data(NodeMessage::BlocksDisconnected(reorged))
                     .await;
                 self.flush_over_height(stem).await;
+                self.truncate_cf_headers(stem).await;
                 Ok(())
             } else {
                 self.dialog
@@ -389,6 +627,8 @@ impl Chain {
     }
 
     async fn load_fork(&mut self, header_batch: &HeadersBatch) -> Result<(), HeaderSyncError> {
+        // The chain is reloaded from the database, so headers only in memory must be written first
+        self.flush_to_disk().await;
         let mut db_lock = self.db.lock().await;
         let prev_hash = header_batch.first().prev_blockhash;
         let maybe_height = db_lock
@@ -401,18 +641,34 @@ impl Chain {
                 // absurd amount of headers into RAM. Because headers come in batches of 2,000,
                 // we wouldn't accept a fork of a depth more than around 2,000 anyway.
                 // The only reorgs that have ever been recorded are of depth 1.
-                if self.height() - height > MAX_REORG_DEPTH {
+                if self.height().saturating_sub(height) > MAX_REORG_DEPTH {
+                    Err(HeaderSyncError::FloatingHeaders)
+                } else if self.header_chain.block_hash_at_height(height).is_some()
+                    || height.gt(&self.height())
+                {
+                    // The database has a header of a stale fork where our chain is in memory
                     Err(HeaderSyncError::FloatingHeaders)
                 } else {
                     let older_anchor = HeaderCheckpoint::new(height, prev_hash);
                     let loaded_headers = db_lock
-                        .load(older_anchor.height)
+                        .headers_in_range(older_anchor.height + 1, self.height())
                         .await
                         .map_err(|_| HeaderSyncError::DbError)?;
                     self.header_chain = HeaderChain::new(older_anchor, loaded_headers);
-                    self.cf_header_chain =
-                        CFHeaderChain::new(older_anchor, self.cf_header_chain.quorum_required());
+                    let loaded_cf_headers = Self::load_cf_headers(
+                        self.cf_header_db.as_mut(),
+                        &mut self.dialog,
+                        older_anchor,
+                        self.header_chain.height(),
+                    )
+                    .await;
+                    self.cf_header_chain = CFHeaderChain::new(
+                        older_anchor,
+                        loaded_cf_headers,
+                        self.cf_header_chain.quorum_required(),
+                    );
                     self.filter_chain = FilterChain::new(older_anchor);
+                    self.scanned_scripts = self.scripts.clone();
                     Ok(())
                 }
             }
@@ -451,20 +707,117 @@ impl Chain {
             None => return Err(CFHeaderSyncError::EmptyMessage),
         }
         self.audit_cf_headers(&batch).await?;
-        match self.cf_header_chain.append(peer_id, batch).await? {
+        let prev_height = self.cf_header_chain.height();
+        let attempt = self.cf_header_chain.append(peer_id, batch).await?;
+        self.handle_append_attempt(attempt, prev_height).await
+    }
+
+    async fn handle_append_attempt(
+        &mut self,
+        attempt: AppendAttempt,
+        prev_height: u32,
+    ) -> Result<CFHeaderSyncResult, CFHeaderSyncError> {
+        match attempt {
             AppendAttempt::AddedToQueue => Ok(CFHeaderSyncResult::AddedToQueue),
-            AppendAttempt::Extended => Ok(CFHeaderSyncResult::ReadyForNext),
-            AppendAttempt::Conflict(height) => match self.header_at_height(height) {
-                Some(header) => Ok(CFHeaderSyncResult::Dispute(header.block_hash())),
-                None => Err(CFHeaderSyncError::HeaderChainIndexOverflow),
-            },
+            AppendAttempt::Extended => {
+                self.flush_cf_headers(prev_height).await;
+                Ok(CFHeaderSyncResult::ReadyForNext)
+            }
+            AppendAttempt::Conflict(height) => {
+                // Wait for the dispute in progress to resolve
+                if self.filter_dispute.is_some() {
+                    return Ok(CFHeaderSyncResult::AddedToQueue);
+                }
+                match self.block_hash_at_height(height).await {
+                    Some(block_hash) => {
+                        let claims = self.cf_header_chain.claims_at(height);
+                        let dispute =
+                            FilterDispute::new(height, block_hash, claims, Instant::now());
+                        let message = dispute.filter_message();
+                        self.filter_dispute = Some(dispute);
+                        Ok(CFHeaderSyncResult::Dispute(message))
+                    }
+                    None => Err(CFHeaderSyncError::HeaderChainIndexOverflow),
+                }
+            }
         }
     }
 
+    // Is there an ongoing dispute over the filter for this block
+    pub(crate) fn is_disputed(&self, block_hash: &BlockHash) -> bool {
+        self.filter_dispute
+            .as_ref()
+            .map_or(false, |dispute| dispute.block_hash().eq(block_hash))
+    }
+
+    // Add a filter sent by a peer in the dispute, returning the block hash to request when all filters are in
+    pub(crate) fn add_disputed_filter(
+        &mut self,
+        peer_id: u32,
+        filter_message: CFilter,
+    ) -> Option<BlockHash> {
+        let dispute = self.filter_dispute.as_mut()?;
+        let filter = Filter::new(filter_message.filter, filter_message.block_hash);
+        if dispute.add_filter(peer_id, filter) {
+            Some(dispute.block_hash())
+        } else {
+            None
+        }
+    }
+
+    // Compare the disputed filters to the block, returning the peers that lied about the filter.
+    // The staged filter headers of the honest peers are merged again.
+    pub(crate) async fn resolve_dispute(
+        &mut self,
+        block: &Block,
+    ) -> Result<(Vec<u32>, CFHeaderSyncResult), CFHeaderSyncError> {
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            return Err(CFHeaderSyncError::InvalidDisputedBlock);
+        }
+        let mut dispute = match self.filter_dispute.take() {
+            Some(dispute) => dispute,
+            None => return Ok((Vec::new(), CFHeaderSyncResult::AddedToQueue)),
+        };
+        let liars = dispute.liars(block).await;
+        if liars.is_empty() || liars.len().eq(&dispute.num_peers()) {
+            // We cannot tell who is honest, so we start this batch over
+            self.dialog
+                .send_warning("Unable to resolve a filter dispute with the block".into())
+                .await;
+            self.cf_header_chain.clear_queue();
+            return Ok((Vec::new(), CFHeaderSyncResult::ReadyForNext));
+        }
+        self.cf_header_chain.remove_peers(&liars);
+        let prev_height = self.cf_header_chain.height();
+        let attempt = self.cf_header_chain.try_merge().await?;
+        Ok((
+            liars,
+            self.handle_append_attempt(attempt, prev_height).await?,
+        ))
+    }
+
+    // Give up on a dispute that ran past its deadline, or where a peer that has not sent its filter
+    // disconnected. The staged filter headers of every peer in the dispute are dropped, so the batch
+    // is requested again. Returns the peers that did not send their filter.
+    pub(crate) fn stalled_dispute(&mut self, peers: &[u32]) -> Option<Vec<u32>> {
+        self.abandon_dispute(peers, Instant::now())
+    }
+
+    fn abandon_dispute(&mut self, peers: &[u32], now: Instant) -> Option<Vec<u32>> {
+        let dispute = self.filter_dispute.as_ref()?;
+        let silent = dispute.silent_peers();
+        if !dispute.timed_out(now) && silent.iter().all(|peer| peers.contains(peer)) {
+            return None;
+        }
+        self.filter_dispute = None;
+        self.cf_header_chain.clear_queue();
+        Some(silent)
+    }
+
     /// Audit the validity of a batch of compact filter headers
     async fn audit_cf_headers(&mut self, batch: &CFHeaderBatch) -> Result<(), CFHeaderSyncError> {
         // Does this stop hash even exist in our chain
-        if !self.contains_hash(*batch.stop_hash()) {
+        if self.height_of_hash(*batch.stop_hash()).await.is_none() {
             return Err(CFHeaderSyncError::UnknownStophash);
         }
         // Does the filter header line up with our current chain of filter headers
@@ -474,10 +827,11 @@ impl Chain {
             }
         }
         // Did they send us the right amount of headers
-        let expected_stop_header =
-            self.header_at_height(self.cf_header_chain.height() + batch.len() as u32);
-        if let Some(stop_header) = expected_stop_header {
-            if stop_header.block_hash().ne(batch.stop_hash()) {
+        let expected_stop_hash = self
+            .block_hash_at_height(self.cf_header_chain.height() + batch.len() as u32)
+            .await;
+        if let Some(stop_hash) = expected_stop_hash {
+            if stop_hash.ne(batch.stop_hash()) {
                 return Err(CFHeaderSyncError::StopHashMismatch);
             }
         } else {
@@ -503,15 +857,11 @@ impl Chain {
     // We need to make this public for new peers that connect to us throughout syncing the filter headers
     pub(crate) async fn next_cf_header_message(&mut self) -> GetCFHeaders {
         let stop_hash_index = self.cf_header_chain.height() + CF_HEADER_BATCH_SIZE + 1;
-        let stop_hash = if let Some(hash) = self.header_at_height(stop_hash_index) {
-            hash.block_hash()
-        } else {
-            self.tip()
-        };
+        let stop_hash = self
+            .block_hash_at_height(stop_hash_index)
+            .await
+            .unwrap_or_else(|| self.tip());
         self.cf_header_chain.set_last_stop_hash(stop_hash);
-        if self.is_cf_headers_synced() {
-            self.cf_header_chain.join(&self.header_chain.values()).await;
-        }
         GetCFHeaders {
             filter_type: 0x00,
             start_height: self.cf_header_chain.height() + 1,
@@ -524,60 +874,134 @@ impl Chain {
         self.height().le(&self.cf_header_chain.height())
     }
 
-    // Handle a new filter
+    // Handle a filter from a peer sending a range of filters. Every filter must match our filter headers.
     pub(crate) async fn sync_filter(
         &mut self,
+        peer_id: u32,
         filter_message: CFilter,
-    ) -> Result<Option<GetCFilters>, CFilterSyncError> {
+    ) -> Result<(), CFilterSyncError> {
         if self.is_filters_synced() {
-            return Ok(None);
-        }
-        let mut filter = Filter::new(filter_message.filter, filter_message.block_hash);
-        let expected_filter_hash = self.cf_header_chain.hash_at(&filter_message.block_hash);
-        if let Some(ref_hash) = expected_filter_hash {
-            if filter.filter_hash().await.ne(ref_hash) {
-                return Err(CFilterSyncError::MisalignedFilterHash);
-            }
+            return Ok(());
         }
-        if !self.block_queue.contains(&filter_message.block_hash)
-            && filter
-                .contains_any(&self.scripts)
-                .await
-                .map_err(CFilterSyncError::Filter)?
+        // The filter is not the next in a range this peer is sending, such as a response to a request that
+        // was reassigned. A peer that never sends the expected filter has its range reassigned.
+        let height = match self.filter_chain.expected_height(peer_id) {
+            Some(height) => height,
+            None => return Ok(()),
+        };
+        if self
+            .block_hash_at_height(height)
+            .await
+            .map_or(true, |block_hash| block_hash.ne(&filter_message.block_hash))
         {
+            return Ok(());
+        }
+        let filter = Filter::new(filter_message.filter, filter_message.block_hash);
+        let expected_filter_hash = self
+            .cf_header_chain
+            .filter_hash_at_height(height)
+            .ok_or(CFilterSyncError::UnknownFilterHash)?;
+        if filter.filter_hash().await.ne(&expected_filter_hash) {
+            return Err(CFilterSyncError::MisalignedFilterHash);
+        }
+        self.cache_filter(height, &filter_message.block_hash, &filter)
+            .await;
+        self.queue_matching_block(filter)
+            .await
+            .map_err(CFilterSyncError::Filter)?;
+        self.filter_chain.received(peer_id, Instant::now());
+        Ok(())
+    }
+
+    // Add the block to the queue if the filter matches any of our scripts or a transaction we broadcast
+    async fn queue_matching_block(&mut self, mut filter: Filter) -> Result<(), FilterError> {
+        let block_hash = *filter.block_hash();
+        let mut scripts: HashSet<ScriptBuf> =
+            self.broadcast_scripts.values().flatten().cloned().collect();
+        scripts.extend(self.scripts.iter().cloned());
+        // A filter matches any empty query
+        if self.block_queue.contains(&block_hash) || scripts.is_empty() {
+            return Ok(());
+        }
+        if filter.contains_any(&scripts).await? {
             // Add to the block queue
-            self.block_queue.add(filter_message.block_hash);
+            self.block_queue.add(block_hash);
             self.dialog
-                .send_dialog(format!(
-                    "Found script at block: {}",
-                    filter_message.block_hash
-                ))
+                .send_dialog(format!("Found script at block: {}", block_hash))
                 .await;
+            // Keep the filter so we may check the block against it
+            self.block_filters.insert(block_hash, filter);
         }
-        self.filter_chain.put_hash(filter_message.block_hash).await;
-        if let Some(stop_hash) = self.filter_chain.last_stop_hash_request() {
-            if filter_message.block_hash.eq(stop_hash) {
-                if !self.is_filters_synced() {
-                    Ok(Some(self.next_filter_message().await))
-                } else {
-                    Ok(None)
+        Ok(())
+    }
+
+    async fn cache_filter(&mut self, height: u32, block_hash: &BlockHash, filter: &Filter) {
+        if let Err(e) = self
+            .filter_db
+            .put(height, *block_hash, filter.contents().to_vec())
+            .await
+        {
+            self.dialog
+                .send_warning(format!("Error persisting a filter to storage: {}", e))
+                .await;
+        }
+    }
+
+    // Scan the cached filters for our scripts, stopping at the first filter that is missing or does not
+    // match our chain. Returns if every filter was scanned.
+    pub(crate) async fn scan_cached_filters(&mut self) -> bool {
+        self.filter_chain.clear_cache().await;
+        self.scanned_scripts = self.scripts.clone();
+        let mut start_height = self.filter_chain.height() + 1;
+        while start_height.le(&self.height()) {
+            let end_height = (start_height + CACHED_FILTER_BATCH_SIZE).min(self.height());
+            let cached = match self.filter_db.load_range(start_height, end_height).await {
+                Ok(cached) => cached,
+                Err(e) => {
+                    self.dialog
+                        .send_warning(format!("Error loading filters from storage: {}", e))
+                        .await;
+                    break;
                 }
-            } else {
-                Ok(None)
+            };
+            for height in start_height..=end_height {
+                let (block_hash, contents) = match cached.get(&height) {
+                    Some(cached_filter) => cached_filter,
+                    None => return self.is_filters_synced(),
+                };
+                // The filter must commit to the block in our chain and match our filter headers
+                if self
+                    .block_hash_at_height(height)
+                    .await
+                    .map_or(true, |hash| hash.ne(block_hash))
+                {
+                    return self.is_filters_synced();
+                }
+                let filter = Filter::new(contents.clone(), *block_hash);
+                if self
+                    .cf_header_chain
+                    .filter_hash_at_height(height)
+                    .ne(&Some(filter.filter_hash().await))
+                {
+                    return self.is_filters_synced();
+                }
+                if self.queue_matching_block(filter).await.is_err() {
+                    return self.is_filters_synced();
+                }
+                self.filter_chain.put_height(height);
             }
-        } else {
-            Err(CFilterSyncError::UnrequestedStophash)
+            start_height = end_height + 1;
         }
+        self.is_filters_synced()
     }
 
-    // Next filter message, if there is one
-    pub(crate) async fn next_filter_message(&mut self) -> GetCFilters {
-        let stop_hash_index = self.filter_chain.height() + FILTER_BATCH_SIZE + 1;
-        let stop_hash = if let Some(hash) = self.header_at_height(stop_hash_index) {
-            hash.block_hash()
-        } else {
-            self.tip()
-        };
+    // Assign the next ranges of filters to the peers that are not sending any
+    pub(crate) async fn next_filter_requests(&mut self, peers: &[u32]) -> Vec<(u32, GetCFilters)> {
+        let tip_height = self.height().min(self.cf_header_chain.height());
+        let assigned = self.filter_chain.assign(peers, tip_height, Instant::now());
+        if assigned.is_empty() {
+            return Vec::new();
+        }
         self.dialog
             .chain_update(
                 self.height(),
@@ -589,12 +1013,41 @@ impl Chain {
                     .unwrap(),
             )
             .await;
-        self.filter_chain.set_last_stop_hash(stop_hash);
-        GetCFilters {
-            filter_type: 0x00,
-            start_height: self.filter_chain.height() + 1,
-            stop_hash,
+        let mut requests = Vec::new();
+        for (peer, start_height, stop_height) in assigned {
+            if let Some(stop_hash) = self.block_hash_at_height(stop_height).await {
+                requests.push((
+                    peer,
+                    GetCFilters {
+                        filter_type: 0x00,
+                        start_height,
+                        stop_hash,
+                    },
+                ));
+            }
         }
+        requests
+    }
+
+    // The peers that stopped sending the filters they were asked for. Their ranges are given to other peers.
+    pub(crate) fn stalled_filter_requests(&mut self) -> Vec<u32> {
+        self.filter_chain.stalled(Instant::now())
+    }
+
+    // Skip the filters that were scanned in a previous session. The checkpoint must be in our chain,
+    // otherwise the filters are scanned from the anchor.
+    pub(crate) async fn resume_filters(&mut self, checkpoint: HeaderCheckpoint) -> bool {
+        if self
+            .block_hash_at_height(checkpoint.height)
+            .await
+            .map_or(true, |hash| hash.ne(&checkpoint.hash))
+        {
+            return false;
+        }
+        for height in self.filter_chain.height() + 1..=checkpoint.height {
+            self.filter_chain.put_height(height);
+        }
+        true
     }
 
     // Are we synced with filters
@@ -602,9 +1055,19 @@ impl Chain {
         self.height().le(&self.filter_chain.height())
     }
 
-    // Pop a block from the queue of interesting blocks
-    pub(crate) fn next_block(&mut self) -> Option<BlockHash> {
-        self.block_queue.pop()
+    // Spread the blocks in the queue across our peers, returning the peer each block should be requested from
+    pub(crate) fn next_block_requests(&mut self, peers: &[u32]) -> Vec<(u32, BlockHash)> {
+        self.block_queue.assign(peers, Instant::now())
+    }
+
+    // The peers that did not send a requested block in time. The blocks are requested from other peers.
+    pub(crate) fn stalled_block_requests(&mut self) -> Vec<u32> {
+        self.block_queue.timed_out(Instant::now())
+    }
+
+    // A peer responded that it does not have these blocks. Returns true if we requested any of them from the peer.
+    pub(crate) fn blocks_not_found(&mut self, peer_id: u32, blocks: &[BlockHash]) -> bool {
+        self.block_queue.not_found(peer_id, blocks, Instant::now())
     }
 
     // Are there any blocks left in the queue
@@ -613,15 +1076,30 @@ impl Chain {
     }
 
     // Scan an incoming block for transactions with our scripts
-    pub(crate) async fn scan_block(&mut self, block: &Block) -> Result<(), BlockScanError> {
-        self.block_queue.receive_one();
-        match self.height_of_hash(block.block_hash()).await {
+    pub(crate) async fn scan_block(
+        &mut self,
+        peer_id: u32,
+        block: &Block,
+    ) -> Result<(), BlockScanError> {
+        let block_hash = block.block_hash();
+        // A block that was already received from another peer is not scanned again
+        if !self.block_queue.contains(&block_hash) {
+            return Ok(());
+        }
+        match self.height_of_hash(block_hash).await {
             Some(height) => {
+                if let Err(e) = self.verify_block(block).await {
+                    // The block should be requested from another peer
+                    self.block_queue.failed(peer_id, block_hash, Instant::now());
+                    return Err(e);
+                }
+                self.block_queue.received(&block_hash);
+                self.block_filters.remove(&block_hash);
                 self.dialog
                     .send_data(NodeMessage::Block(IndexedBlock::new(height, block.clone())))
                     .await;
                 for tx in &block.txdata {
-                    if self.scan_inputs(&tx.input) || self.scan_outputs(&tx.output) {
+                    if self.scan_transaction(tx).await {
                         self.dialog
                             .send_data(NodeMessage::Transaction(IndexedTransaction::new(
                                 tx.clone(),
@@ -640,16 +1118,44 @@ impl Chain {
         }
     }
 
-    fn scan_inputs(&mut self, inputs: &[TxIn]) -> bool {
-        inputs
-            .iter()
-            .any(|input| self.scripts.contains(&input.script_sig))
+    // A peer may attach arbitrary transactions to a valid header, so the transactions must commit to the
+    // header, and the block must match the filter we used to request it.
+    async fn verify_block(&mut self, block: &Block) -> Result<(), BlockScanError> {
+        if !block.check_merkle_root() {
+            return Err(BlockScanError::InvalidMerkleRoot);
+        }
+        if !block.check_witness_commitment() {
+            return Err(BlockScanError::InvalidWitnessCommitment);
+        }
+        if let Some(filter) = self.block_filters.get_mut(&block.block_hash()) {
+            if !filter.is_filter_for_block(block).await.unwrap_or(false) {
+                return Err(BlockScanError::InvalidFilter);
+            }
+        }
+        Ok(())
     }
 
-    fn scan_outputs(&mut self, inputs: &[TxOut]) -> bool {
-        inputs
+    // A transaction is relevant if it spends an output we are watching or pays to one of our scripts.
+    // Outputs paying to our scripts are remembered so we may detect when they are spent.
+    async fn scan_transaction(&mut self, tx: &Transaction) -> bool {
+        let spends_watched = tx
+            .input
             .iter()
-            .any(|out| self.scripts.contains(&out.script_pubkey))
+            .any(|input| self.outpoints.contains(&input.previous_output));
+        let mut pays_watched = false;
+        for (vout, output) in tx.output.iter().enumerate() {
+            if self.scripts.contains(&output.script_pubkey) {
+                pays_watched = true;
+                let outpoint = OutPoint::new(tx.compute_txid(), vout as u32);
+                if self.outpoints.insert(outpoint) && self.outpoint_db.put(outpoint).await.is_err()
+                {
+                    self.dialog
+                        .send_warning(format!("Could not persist the outpoint: {}", outpoint))
+                        .await;
+                }
+            }
+        }
+        spends_watched || pays_watched
     }
 
     // Add more scripts to our list
@@ -659,30 +1165,71 @@ impl Chain {
         }
     }
 
-    // Clear the filter header cache to rescan the filters for new scripts.
-    pub(crate) async fn clear_filters(&mut self) {
-        self.filter_chain.clear_cache().await;
+    // Check the filters for the outputs of a transaction we broadcast until it is confirmed. Filters do not
+    // commit to `OP_RETURN` outputs, so a transaction with only those outputs is never matched.
+    pub(crate) fn watch_broadcast(&mut self, tx: &Transaction) {
+        let scripts = tx
+            .output
+            .iter()
+            .filter(|output| !output.script_pubkey.is_op_return())
+            .map(|output| output.script_pubkey.clone())
+            .collect();
+        self.broadcast_scripts.insert(tx.compute_txid(), scripts);
+    }
+
+    // The transaction was confirmed or abandoned
+    pub(crate) fn unwatch_broadcast(&mut self, txid: &Txid) {
+        self.broadcast_scripts.remove(txid);
+    }
+
+    // The scripts the filters were checked for since the anchor, excluding the scripts added midway
+    pub(crate) fn scanned_scripts(&self) -> &HashSet<ScriptBuf> {
+        &self.scanned_scripts
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashSet, str::FromStr};
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        str::FromStr,
+        time::{Duration, Instant},
+    };
 
-    use bitcoin::{block::Header, consensus::deserialize, BlockHash};
+    use bitcoin::{
+        absolute,
+        bip158::BlockFilter,
+        block::{self, Header},
+        consensus::deserialize,
+        hashes::Hash,
+        key::TweakedPublicKey,
+        p2p::message_filter::CFilter,
+        secp256k1::XOnlyPublicKey,
+        transaction, Amount, Block, BlockHash, CompactTarget, FilterHash, FilterHeader, OutPoint,
+        ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Txid, WPubkeyHash, Witness,
+    };
 
     use crate::{
         chain::{
             checkpoints::{HeaderCheckpoint, HeaderCheckpoints},
-            error::HeaderSyncError,
+            error::{BlockScanError, HeaderSyncError},
         },
-        node::{dialog::Dialog, messages::NodeMessage},
+        db::{
+            error::DatabaseError,
+            memory::headers::MemoryHeaderStore,
+            traits::{FilterHeaderStore, FilterStore, HeaderStore, OutPointStore},
+        },
+        filters::{
+            cfheader_chain::CFHeaderChain, dispute::FilterDispute, error::CFilterSyncError,
+            filter::Filter,
+        },
+        node::dialog::Dialog,
     };
 
     use super::Chain;
 
     async fn new_regtest(anchor: HeaderCheckpoint) -> Chain {
-        let (sender, _) = tokio::sync::broadcast::channel::<NodeMessage>(1);
+        let (_, subscribers) = tokio::sync::mpsc::unbounded_channel();
         let mut checkpoints = HeaderCheckpoints::new(&bitcoin::Network::Regtest);
         checkpoints.prune_up_to(anchor);
         Chain::new(
@@ -690,7 +1237,10 @@ mod tests {
             HashSet::new(),
             anchor,
             checkpoints,
-            Dialog::new(sender),
+            Dialog::new(subscribers),
+            (),
+            Box::new(()),
+            Box::new(()),
             (),
             1,
         )
@@ -698,6 +1248,38 @@ mod tests {
         .unwrap()
     }
 
+    #[tokio::test]
+    async fn test_mainnet_from_genesis() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap(),
+        );
+        let (_, subscribers) = tokio::sync::mpsc::unbounded_channel();
+        let mut checkpoints = HeaderCheckpoints::new(&bitcoin::Network::Bitcoin);
+        checkpoints.prune_up_to(gen);
+        let mut chain = Chain::new(
+            &bitcoin::Network::Bitcoin,
+            HashSet::new(),
+            gen,
+            checkpoints,
+            Dialog::new(subscribers),
+            (),
+            Box::new(()),
+            Box::new(()),
+            (),
+            1,
+        )
+        .await
+        .unwrap();
+        let block_1: Header = deserialize(&hex::decode("010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61").unwrap()).unwrap();
+        let chain_sync = chain.sync_chain(vec![block_1, block_2]).await;
+        assert!(chain_sync.is_ok());
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.tip(), block_2.block_hash());
+    }
+
     #[tokio::test]
     async fn test_depth_one_fork() {
         let gen = HeaderCheckpoint::new(
@@ -811,4 +1393,527 @@ mod tests {
             vec![new_block_1, new_block_2, block_3]
         );
     }
+
+    fn spend(previous_output: OutPoint, script_pubkey: ScriptBuf) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![1_u8; 64]]),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detects_spends_of_watched_outputs() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen).await;
+        let watched = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let other = ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(
+            XOnlyPublicKey::from_str(
+                "18845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+            )
+            .unwrap(),
+        ));
+        chain.put_scripts(HashSet::from([watched.clone()]));
+        let receive = spend(OutPoint::new(Txid::all_zeros(), 0), watched);
+        assert!(chain.scan_transaction(&receive).await);
+        // The witness spend does not reveal the script, but it consumes our output
+        let send = spend(OutPoint::new(receive.compute_txid(), 0), other.clone());
+        assert!(chain.scan_transaction(&send).await);
+        let unrelated = spend(OutPoint::new(send.compute_txid(), 0), other);
+        assert!(!chain.scan_transaction(&unrelated).await);
+    }
+
+    struct PersistedOutPoints(HashSet<OutPoint>);
+
+    #[async_trait::async_trait]
+    impl OutPointStore for PersistedOutPoints {
+        async fn load(&mut self) -> Result<HashSet<OutPoint>, DatabaseError> {
+            Ok(self.0.clone())
+        }
+
+        async fn put(&mut self, outpoint: OutPoint) -> Result<(), DatabaseError> {
+            self.0.insert(outpoint);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loads_watched_outputs() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let outpoint = OutPoint::new(Txid::all_zeros(), 3);
+        let (_, subscribers) = tokio::sync::mpsc::unbounded_channel();
+        let mut checkpoints = HeaderCheckpoints::new(&bitcoin::Network::Regtest);
+        checkpoints.prune_up_to(gen);
+        let mut chain = Chain::new(
+            &bitcoin::Network::Regtest,
+            HashSet::new(),
+            gen,
+            checkpoints,
+            Dialog::new(subscribers),
+            (),
+            Box::new(()),
+            Box::new(()),
+            PersistedOutPoints(HashSet::from([outpoint])),
+            1,
+        )
+        .await
+        .unwrap();
+        let send = spend(outpoint, ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()));
+        assert!(chain.scan_transaction(&send).await);
+    }
+
+    fn block_with(txdata: Vec<Transaction>) -> Block {
+        let header = Header {
+            version: block::Version::TWO,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        };
+        let mut block = Block { header, txdata };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        block
+    }
+
+    #[tokio::test]
+    async fn test_verifies_blocks() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen).await;
+        let watched = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let mut coinbase = spend(OutPoint::null(), watched.clone());
+        coinbase.input[0].witness = Witness::new();
+        let payment = spend(OutPoint::new(Txid::all_zeros(), 0), watched.clone());
+        let mut block = block_with(vec![coinbase.clone()]);
+        assert!(chain.verify_block(&block).await.is_ok());
+        // Transactions that do not commit to the header
+        block.txdata.push(payment.clone());
+        assert!(matches!(
+            chain.verify_block(&block).await,
+            Err(BlockScanError::InvalidMerkleRoot)
+        ));
+        // Witness data without a commitment in the coinbase
+        let block = block_with(vec![coinbase.clone(), payment.clone()]);
+        assert!(matches!(
+            chain.verify_block(&block).await,
+            Err(BlockScanError::InvalidWitnessCommitment)
+        ));
+        // The block must match the filter it was requested with
+        let mut legacy_payment = payment;
+        legacy_payment.input[0].witness = Witness::new();
+        let block = block_with(vec![coinbase.clone(), legacy_payment.clone()]);
+        let filter = BlockFilter::new_script_filter(&block, |_| Ok(watched.clone())).unwrap();
+        chain.block_filters.insert(
+            block.block_hash(),
+            Filter::new(filter.content, block.block_hash()),
+        );
+        assert!(chain.verify_block(&block).await.is_ok());
+        let other = block_with(vec![coinbase]);
+        let filter = BlockFilter::new_script_filter(&other, |_| Ok(watched.clone())).unwrap();
+        chain.block_filters.insert(
+            block.block_hash(),
+            Filter::new(filter.content, block.block_hash()),
+        );
+        assert!(matches!(
+            chain.verify_block(&block).await,
+            Err(BlockScanError::InvalidFilter)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_matches_filters_for_broadcast_transactions() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let mut chain = new_regtest(gen).await;
+        let recipient = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let mut coinbase = spend(OutPoint::null(), ScriptBuf::new());
+        coinbase.input[0].witness = Witness::new();
+        let payment = spend(OutPoint::new(Txid::all_zeros(), 0), recipient.clone());
+        let block = block_with(vec![coinbase, payment.clone()]);
+        let filter = || {
+            let filter = BlockFilter::new_script_filter(&block, |_| Ok(ScriptBuf::new())).unwrap();
+            Filter::new(filter.content, block.block_hash())
+        };
+        // The payment is to a script we do not watch
+        chain.queue_matching_block(filter()).await.unwrap();
+        assert!(chain.block_queue.complete());
+        chain.watch_broadcast(&payment);
+        chain.queue_matching_block(filter()).await.unwrap();
+        assert!(chain.block_queue.contains(&block.block_hash()));
+        assert!(chain.block_queue.received(&block.block_hash()));
+        chain.unwatch_broadcast(&payment.compute_txid());
+        chain.queue_matching_block(filter()).await.unwrap();
+        assert!(chain.block_queue.complete());
+    }
+
+    struct PersistedFilterHeaders(BTreeMap<u32, (FilterHeader, FilterHash)>);
+
+    #[async_trait::async_trait]
+    impl FilterHeaderStore for PersistedFilterHeaders {
+        async fn load(
+            &mut self,
+            anchor_height: u32,
+        ) -> Result<BTreeMap<u32, (FilterHeader, FilterHash)>, DatabaseError> {
+            Ok(self.0.split_off(&(anchor_height + 1)))
+        }
+
+        async fn write<'a>(
+            &mut self,
+            filter_headers: &'a BTreeMap<u32, (FilterHeader, FilterHash)>,
+        ) -> Result<(), DatabaseError> {
+            self.0.extend(filter_headers);
+            Ok(())
+        }
+
+        async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError> {
+            self.0.split_off(&(height + 1));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loads_linked_filter_headers() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let mut filter_headers = BTreeMap::new();
+        let mut prev_header = FilterHeader::all_zeros();
+        for height in 1..=4 {
+            let filter_hash = FilterHash::hash(&[height as u8]);
+            let filter_header = filter_hash.filter_header(&prev_header);
+            filter_headers.insert(height, (filter_header, filter_hash));
+            prev_header = filter_header;
+        }
+        // A filter header that does not commit to the previous
+        filter_headers.insert(5, (FilterHeader::all_zeros(), FilterHash::hash(&[5_u8])));
+        let (_, subscribers) = tokio::sync::mpsc::unbounded_channel();
+        let mut dialog = Dialog::new(subscribers);
+        let mut store = PersistedFilterHeaders(filter_headers.clone());
+        let loaded = Chain::load_cf_headers(&mut store, &mut dialog, gen, 10).await;
+        assert_eq!(loaded.len(), 4);
+        let mut store = PersistedFilterHeaders(filter_headers);
+        // The filter headers cannot extend past the header chain
+        let loaded = Chain::load_cf_headers(&mut store, &mut dialog, gen, 2).await;
+        assert_eq!(loaded.len(), 2);
+        let mut cf_header_chain = CFHeaderChain::new(gen, loaded, 1);
+        assert_eq!(cf_header_chain.height(), 2);
+        assert_eq!(cf_header_chain.headers_after(1).len(), 1);
+        cf_header_chain.truncate(1);
+        assert_eq!(cf_header_chain.height(), 1);
+    }
+
+    struct CachedFilters(BTreeMap<u32, (BlockHash, Vec<u8>)>);
+
+    #[async_trait::async_trait]
+    impl FilterStore for CachedFilters {
+        async fn put(
+            &mut self,
+            height: u32,
+            block_hash: BlockHash,
+            filter: Vec<u8>,
+        ) -> Result<(), DatabaseError> {
+            self.0.insert(height, (block_hash, filter));
+            Ok(())
+        }
+
+        async fn load_range(
+            &mut self,
+            start_height: u32,
+            end_height: u32,
+        ) -> Result<BTreeMap<u32, (BlockHash, Vec<u8>)>, DatabaseError> {
+            Ok(self
+                .0
+                .range(start_height..=end_height)
+                .map(|(height, filter)| (*height, filter.clone()))
+                .collect())
+        }
+
+        async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError> {
+            self.0.split_off(&(height + 1));
+            Ok(())
+        }
+    }
+
+    // Filters for blocks after genesis, where only the second block pays to the watched script
+    async fn filters_paying_to(
+        headers: &[Header],
+        watched: &ScriptBuf,
+    ) -> (
+        BTreeMap<u32, (FilterHeader, FilterHash)>,
+        BTreeMap<u32, (BlockHash, Vec<u8>)>,
+    ) {
+        let mut cf_headers = BTreeMap::new();
+        let mut filters = BTreeMap::new();
+        let mut prev_header = FilterHeader::all_zeros();
+        for (index, header) in headers.iter().enumerate() {
+            let height = index as u32 + 1;
+            let script_pubkey = if height == 2 {
+                watched.clone()
+            } else {
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(&[height as u8]))
+            };
+            let block = Block {
+                header: *header,
+                txdata: vec![spend(OutPoint::null(), script_pubkey)],
+            };
+            let filter = BlockFilter::new_script_filter(&block, |_| Ok(ScriptBuf::new())).unwrap();
+            let filter = Filter::new(filter.content, header.block_hash());
+            let filter_hash = filter.filter_hash().await;
+            let filter_header = filter_hash.filter_header(&prev_header);
+            cf_headers.insert(height, (filter_header, filter_hash));
+            filters.insert(height, (header.block_hash(), filter.contents().to_vec()));
+            prev_header = filter_header;
+        }
+        (cf_headers, filters)
+    }
+
+    #[tokio::test]
+    async fn test_rescans_cached_filters() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020299e41732deb76d869fcdb5f72518d3784e99482f572afb73068d52134f1f75e1f20f5da8d18661d0f13aa3db8fff0f53598f7d61f56988a6d66573394b2c6ffc5805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("00000020b96feaa82716f11befeb608724acee4743e0920639a70f35f1637a88b8b6ea3471f1dbedc283ce6a43a87ed3c8e6326dae8d3dbacce1b2daba08e508054ffdb697815e66ffff7f2001000000").unwrap()).unwrap();
+        let watched = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let (cf_headers, filters) = filters_paying_to(&[block_1, block_2, block_3], &watched).await;
+        // The last filter is missing from the cache
+        let mut partial = filters.clone();
+        partial.remove(&3);
+        for (cached, synced) in [(filters, true), (partial, false)] {
+            let (_, subscribers) = tokio::sync::mpsc::unbounded_channel();
+            let mut checkpoints = HeaderCheckpoints::new(&bitcoin::Network::Regtest);
+            checkpoints.prune_up_to(gen);
+            let mut chain = Chain::new(
+                &bitcoin::Network::Regtest,
+                HashSet::from([watched.clone()]),
+                gen,
+                checkpoints,
+                Dialog::new(subscribers),
+                (),
+                Box::new(()),
+                Box::new(CachedFilters(cached)),
+                (),
+                1,
+            )
+            .await
+            .unwrap();
+            chain
+                .sync_chain(vec![block_1, block_2, block_3])
+                .await
+                .unwrap();
+            chain.cf_header_chain = CFHeaderChain::new(gen, cf_headers.clone(), 1);
+            assert_eq!(chain.scan_cached_filters().await, synced);
+            assert_eq!(
+                chain.next_block_requests(&[1]),
+                vec![(1, block_2.block_hash())]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resumes_scanned_filters() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020299e41732deb76d869fcdb5f72518d3784e99482f572afb73068d52134f1f75e1f20f5da8d18661d0f13aa3db8fff0f53598f7d61f56988a6d66573394b2c6ffc5805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("00000020b96feaa82716f11befeb608724acee4743e0920639a70f35f1637a88b8b6ea3471f1dbedc283ce6a43a87ed3c8e6326dae8d3dbacce1b2daba08e508054ffdb697815e66ffff7f2001000000").unwrap()).unwrap();
+        let mut chain = new_regtest(gen).await;
+        chain
+            .sync_chain(vec![block_1, block_2, block_3])
+            .await
+            .unwrap();
+        // A checkpoint that is not in our chain is ignored
+        assert!(
+            !chain
+                .resume_filters(HeaderCheckpoint::new(2, block_3.block_hash()))
+                .await
+        );
+        assert_eq!(chain.filter_chain.height(), 0);
+        assert!(
+            chain
+                .resume_filters(HeaderCheckpoint::new(2, block_2.block_hash()))
+                .await
+        );
+        assert_eq!(chain.filter_chain.height(), 2);
+        let watched = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let (cf_headers, _) = filters_paying_to(&[block_1, block_2, block_3], &watched).await;
+        chain.cf_header_chain = CFHeaderChain::new(gen, cf_headers, 1);
+        let requests = chain.next_filter_requests(&[1]).await;
+        assert_eq!(requests[0].1.start_height, 3);
+        assert_eq!(requests[0].1.stop_hash, block_3.block_hash());
+        assert!(!chain.is_filters_synced());
+        // A rescan still starts from the anchor
+        chain.filter_chain.clear_cache().await;
+        assert_eq!(chain.filter_chain.height(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_abandons_stalled_dispute() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let mut chain = new_regtest(gen).await;
+        chain.sync_chain(vec![block_1]).await.unwrap();
+        let block_hash = block_1.block_hash();
+        let claims = HashMap::from([(1, FilterHash::hash(&[1])), (2, FilterHash::hash(&[2]))]);
+        let now = Instant::now();
+        chain.filter_dispute = Some(FilterDispute::new(1, block_hash, claims.clone(), now));
+        let filter = CFilter {
+            filter_type: 0x00,
+            block_hash,
+            filter: Vec::new(),
+        };
+        assert!(chain.add_disputed_filter(1, filter).is_none());
+        assert!(chain.abandon_dispute(&[1, 2], now).is_none());
+        assert!(chain.is_disputed(&block_hash));
+        // The second peer never sends its filter
+        let later = now + Duration::from_secs(60);
+        assert_eq!(chain.abandon_dispute(&[1, 2], later), Some(vec![2]));
+        assert!(!chain.is_disputed(&block_hash));
+        assert!(chain.abandon_dispute(&[1, 2], later).is_none());
+        // A peer that has not sent its filter disconnects before the deadline
+        chain.filter_dispute = Some(FilterDispute::new(1, block_hash, claims, now));
+        let mut silent = chain.abandon_dispute(&[1], now).unwrap();
+        silent.sort();
+        assert_eq!(silent, vec![1, 2]);
+        assert!(!chain.is_disputed(&block_hash));
+    }
+
+    #[tokio::test]
+    async fn test_checks_filters_from_assigned_peers() {
+        let gen = HeaderCheckpoint::new(
+            0,
+            BlockHash::from_str("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206")
+                .unwrap(),
+        );
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020299e41732deb76d869fcdb5f72518d3784e99482f572afb73068d52134f1f75e1f20f5da8d18661d0f13aa3db8fff0f53598f7d61f56988a6d66573394b2c6ffc5805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("00000020b96feaa82716f11befeb608724acee4743e0920639a70f35f1637a88b8b6ea3471f1dbedc283ce6a43a87ed3c8e6326dae8d3dbacce1b2daba08e508054ffdb697815e66ffff7f2001000000").unwrap()).unwrap();
+        let watched = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let (cf_headers, filters) = filters_paying_to(&[block_1, block_2, block_3], &watched).await;
+        let mut chain = new_regtest(gen).await;
+        chain.put_scripts(HashSet::from([watched]));
+        chain
+            .sync_chain(vec![block_1, block_2, block_3])
+            .await
+            .unwrap();
+        chain.cf_header_chain = CFHeaderChain::new(gen, cf_headers, 1);
+        let cfilter = |height: u32| {
+            let (block_hash, filter) = filters.get(&height).unwrap().clone();
+            CFilter {
+                filter_type: 0x00,
+                block_hash,
+                filter,
+            }
+        };
+        // The whole range fits in one request, so the second peer has nothing to do
+        let requests = chain.next_filter_requests(&[1, 2]).await;
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, 1);
+        // Filters from a peer without a request, or out of order, are ignored
+        chain.sync_filter(2, cfilter(1)).await.unwrap();
+        chain.sync_filter(1, cfilter(2)).await.unwrap();
+        assert_eq!(chain.filter_chain.height(), 0);
+        // A filter that does not match the filter header is rejected
+        let mut false_filter = cfilter(1);
+        false_filter.filter = cfilter(2).filter;
+        assert!(matches!(
+            chain.sync_filter(1, false_filter).await,
+            Err(CFilterSyncError::MisalignedFilterHash)
+        ));
+        for height in 1..=3 {
+            chain.sync_filter(1, cfilter(height)).await.unwrap();
+        }
+        assert!(chain.is_filters_synced());
+        assert_eq!(
+            chain.next_block_requests(&[1]),
+            vec![(1, block_2.block_hash())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reads_pruned_headers_from_the_database() {
+        let anchor = HeaderCheckpoint::new(0, BlockHash::all_zeros());
+        let mut prev_blockhash = anchor.hash;
+        let mut persisted = BTreeMap::new();
+        for height in 1..=10_000 {
+            let header = Header {
+                version: block::Version::TWO,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: height,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            };
+            prev_blockhash = header.block_hash();
+            persisted.insert(height, header);
+        }
+        let mut db = MemoryHeaderStore::new();
+        db.write(&persisted).await.unwrap();
+        let (_, subscribers) = tokio::sync::mpsc::unbounded_channel();
+        let chain = Chain::new(
+            &bitcoin::Network::Regtest,
+            HashSet::new(),
+            anchor,
+            HeaderCheckpoints::new(&bitcoin::Network::Regtest),
+            Dialog::new(subscribers),
+            db,
+            Box::new(()),
+            Box::new(()),
+            (),
+            1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(chain.height(), 10_000);
+        assert_eq!(chain.tip(), prev_blockhash);
+        assert!(chain.header_chain.inner_len() < 10_000);
+        let work = persisted
+            .values()
+            .map(|header| header.work())
+            .reduce(|acc, next| acc + next)
+            .unwrap();
+        assert_eq!(chain.chainwork(), work);
+        // Headers that are no longer in memory are read from the database
+        let first = persisted[&1].block_hash();
+        assert!(!chain.contains_hash(first));
+        assert_eq!(chain.height_of_hash(first).await, Some(1));
+        assert_eq!(chain.block_hash_at_height(1).await, Some(first));
+        assert_eq!(chain.block_hash_at_height(10_001).await, None);
+    }
 }
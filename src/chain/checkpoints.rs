@@ -2,6 +2,66 @@ use std::{collections::VecDeque, str::FromStr};
 
 use bitcoin::{BlockHash, Network};
 
+/// Known block hashes for Bitcoin mainnet.
+pub const MAINNET_HEADER_CP: &[(u32, &str)] = &[
+    (
+        0,
+        "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    ),
+    (
+        11111,
+        "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+    ),
+    (
+        33333,
+        "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6",
+    ),
+    (
+        74000,
+        "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20",
+    ),
+    (
+        105000,
+        "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+    ),
+    (
+        134444,
+        "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe",
+    ),
+    (
+        168000,
+        "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763",
+    ),
+    (
+        193000,
+        "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317",
+    ),
+    (
+        210000,
+        "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+    ),
+    (
+        216116,
+        "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e",
+    ),
+    (
+        225430,
+        "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932",
+    ),
+    (
+        250000,
+        "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+    ),
+    (
+        279000,
+        "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40",
+    ),
+    (
+        295000,
+        "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983",
+    ),
+];
+
 /// Known Testnet3 block hashes.
 pub const TESTNET_HEADER_CP: &[(u32, &str)] = &[(
     546,
@@ -103,7 +163,7 @@ pub const SIGNET_HEADER_CP: &[(u32, &str)] = &[
 ];
 
 /// A known block hash in the chain of most work.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct HeaderCheckpoint {
     /// The index of the block hash.
     pub height: u32,
@@ -127,7 +187,7 @@ impl HeaderCheckpoints {
     pub fn new(network: &Network) -> Self {
         let mut checkpoints: VecDeque<HeaderCheckpoint> = VecDeque::new();
         let cp_list = match network {
-            Network::Bitcoin => panic!("unimplemented network"),
+            Network::Bitcoin => MAINNET_HEADER_CP.to_vec(),
             Network::Testnet => TESTNET_HEADER_CP.to_vec(),
             Network::Signet => SIGNET_HEADER_CP.to_vec(),
             Network::Regtest => REGTEST_HEADER_CP.to_vec(),
@@ -16,6 +16,8 @@ pub enum HeaderSyncError {
     InvalidCheckpoint,
     #[error("a computed difficulty adjustment did not match")]
     MiscalculatedDifficulty,
+    #[error("the difficulty changed outside of an adjustment interval")]
+    UnexpectedDifficultyChange,
     #[error("the peer sent us a chain that does not connect to any header of ours")]
     FloatingHeaders,
     #[error("less work fork")]
@@ -40,4 +42,10 @@ pub enum HeaderPersistenceError {
 pub enum BlockScanError {
     #[error("unknown block hash")]
     NoBlockHash,
+    #[error("the transactions do not match the merkle root of the block header")]
+    InvalidMerkleRoot,
+    #[error("the witness data does not match the commitment in the coinbase transaction")]
+    InvalidWitnessCommitment,
+    #[error("the block does not match the compact filter for this block")]
+    InvalidFilter,
 }
@@ -1,7 +1,9 @@
-use bitcoin::block::Header;
+use bitcoin::{block::Header, consensus::Params, CompactTarget, Target};
 use thiserror::Error;
 
-use crate::prelude::{Median, MEDIAN_TIME_PAST};
+use crate::prelude::{Median, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_PAST};
+
+use super::error::HeaderSyncError;
 
 pub(crate) struct HeadersBatch {
     batch: Vec<Header>,
@@ -34,6 +36,70 @@ impl HeadersBatch {
         })
     }
 
+    // Are all the targets at or below the maximum target of the network
+    pub(crate) async fn meet_network_minimum(&self, params: &Params) -> bool {
+        self.batch
+            .iter()
+            .all(|header| header.target().le(&params.max_attainable_target))
+    }
+
+    // Every header must have the bits required by the headers before it. The batch begins at the start height,
+    // and headers before the batch are found with `previous`. If the bits of a header depend on headers we do not
+    // have, like those before our anchor, the target is only checked to change by a bounded amount on an adjustment.
+    pub(crate) async fn valid_difficulty_transitions(
+        &self,
+        params: &Params,
+        start_height: u32,
+        previous: impl Fn(u32) -> Option<Header>,
+    ) -> Result<(), HeaderSyncError> {
+        let header_at = |height: u32| {
+            if height.ge(&start_height) {
+                self.batch.get((height - start_height) as usize).copied()
+            } else {
+                previous(height)
+            }
+        };
+        let interval = params.difficulty_adjustment_interval() as u32;
+        for (index, header) in self.batch.iter().enumerate() {
+            let height = start_height + index as u32;
+            let prev = match header_at(height - 1) {
+                Some(prev) => prev,
+                None => continue,
+            };
+            if height % interval == 0 {
+                if params.no_pow_retargeting {
+                    if header.bits.ne(&prev.bits) {
+                        return Err(HeaderSyncError::MiscalculatedDifficulty);
+                    }
+                    continue;
+                }
+                let valid = match header_at(height - interval) {
+                    Some(first) => header.bits.eq(&next_work_required(&prev, &first, params)),
+                    None => within_adjustment_bounds(header, &prev, params),
+                };
+                if !valid {
+                    return Err(HeaderSyncError::MiscalculatedDifficulty);
+                }
+            } else if params.allow_min_difficulty_blocks {
+                if let Some(bits) = min_difficulty_bits(params, height, header, header_at) {
+                    if header.bits.ne(&bits) {
+                        return Err(HeaderSyncError::UnexpectedDifficultyChange);
+                    }
+                }
+            } else if header.bits.ne(&prev.bits) {
+                return Err(HeaderSyncError::UnexpectedDifficultyChange);
+            }
+        }
+        Ok(())
+    }
+
+    // No header may be more than two hours past the network adjusted time
+    pub(crate) async fn valid_future_times(&self, adjusted_time: i64) -> bool {
+        self.batch
+            .iter()
+            .all(|header| (header.time as i64).le(&(adjusted_time + MAX_FUTURE_BLOCK_TIME)))
+    }
+
     // Do the blocks pass the time requirements
     pub(crate) async fn valid_median_time_past(&self, previous_buffer: &mut Vec<Header>) -> bool {
         previous_buffer.extend_from_slice(&self.batch);
@@ -71,8 +137,283 @@ impl HeadersBatch {
     }
 }
 
+// The bits of the first block in an adjustment period, given the first and last block of the previous period.
+// The target is scaled by the time the previous period took, which is bounded by a factor of four.
+fn next_work_required(last: &Header, first: &Header, params: &Params) -> CompactTarget {
+    let target_timespan = params.pow_target_timespan as i64;
+    let timespan = (last.time as i64 - first.time as i64)
+        .clamp(target_timespan / 4, target_timespan * 4) as u64;
+    match scale_target(last.target(), timespan, params.pow_target_timespan) {
+        Some(target) if target.le(&params.max_attainable_target) => target.to_compact_lossy(),
+        _ => params.max_attainable_target.to_compact_lossy(),
+    }
+}
+
+// Compute target * numerator / denominator, returning `None` if the result does not fit in 256 bits.
+fn scale_target(target: Target, numerator: u64, denominator: u64) -> Option<Target> {
+    let bytes = target.to_le_bytes();
+    // An extra limb holds any overflow of the multiplication
+    let mut limbs = [0_u64; 5];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+        let mut word = [0_u8; 8];
+        word.copy_from_slice(chunk);
+        *limb = u64::from_le_bytes(word);
+    }
+    let mut carry = 0_u128;
+    for limb in limbs.iter_mut() {
+        let product = *limb as u128 * numerator as u128 + carry;
+        *limb = product as u64;
+        carry = product >> 64;
+    }
+    let mut remainder = 0_u128;
+    for limb in limbs.iter_mut().rev() {
+        let dividend = (remainder << 64) | *limb as u128;
+        *limb = (dividend / denominator as u128) as u64;
+        remainder = dividend % denominator as u128;
+    }
+    if limbs[4].ne(&0) {
+        return None;
+    }
+    let mut bytes = [0_u8; 32];
+    for (chunk, limb) in bytes.chunks_exact_mut(8).zip(limbs.iter()) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    Some(Target::from_le_bytes(bytes))
+}
+
+// Without the first block of the previous period, we only know the target changes by no more than a factor of four.
+fn within_adjustment_bounds(header: &Header, prev: &Header, params: &Params) -> bool {
+    // Compare in the compact encoding, as the adjusted target is truncated when it is encoded
+    let prev_target = prev.target();
+    let largest = Target::from_compact(
+        prev_target
+            .max_transition_threshold(params)
+            .to_compact_lossy(),
+    );
+    let smallest = Target::from_compact(prev_target.min_transition_threshold().to_compact_lossy());
+    let target = header.target();
+    target.le(&largest) && target.ge(&smallest)
+}
+
+// On networks like testnet, a block more than twice the target spacing after the previous block may have the
+// minimum difficulty. Otherwise, a block has the bits of the last block in the period without the minimum difficulty.
+// Returns `None` if that block is before the headers we have.
+fn min_difficulty_bits(
+    params: &Params,
+    height: u32,
+    header: &Header,
+    header_at: impl Fn(u32) -> Option<Header>,
+) -> Option<CompactTarget> {
+    let interval = params.difficulty_adjustment_interval() as u32;
+    let pow_limit = params.max_attainable_target.to_compact_lossy();
+    let mut prev_height = height - 1;
+    let mut prev = header_at(prev_height)?;
+    if header.time as u64 > prev.time as u64 + params.pow_target_spacing * 2 {
+        return Some(pow_limit);
+    }
+    while prev_height % interval != 0 && prev.bits.eq(&pow_limit) {
+        prev_height -= 1;
+        prev = header_at(prev_height)?;
+    }
+    Some(prev.bits)
+}
+
 #[derive(Error, Debug)]
 pub(crate) enum HeadersBatchError {
     #[error("no headers were found in the initialization vector")]
     EmptyVec,
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        block::{Header, Version},
+        hashes::Hash,
+        BlockHash, CompactTarget, Network, TxMerkleNode,
+    };
+
+    use crate::{chain::error::HeaderSyncError, prelude::params_from_network};
+
+    use super::{next_work_required, HeadersBatch};
+
+    fn header_with_bits(bits: u32) -> Header {
+        header_with_time(bits, 0)
+    }
+
+    fn header_with_time(bits: u32, time: u32) -> Header {
+        Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time,
+            bits: CompactTarget::from_consensus(bits),
+            nonce: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_difficulty_transitions() {
+        let params = params_from_network(&Network::Bitcoin);
+        let batch = HeadersBatch::new(vec![
+            header_with_bits(0x1d00ffff),
+            header_with_bits(0x1d00ffff),
+        ])
+        .unwrap();
+        assert!(batch.meet_network_minimum(&params).await);
+        // The target stays the same within an adjustment period
+        assert!(batch
+            .valid_difficulty_transitions(&params, 2014, |_| Some(header_with_bits(0x1d00ffff)))
+            .await
+            .is_ok());
+        let batch = HeadersBatch::new(vec![
+            header_with_bits(0x1d00ffff),
+            header_with_bits(0x1c3fffc0),
+        ])
+        .unwrap();
+        // The target may only change on the adjustment interval
+        assert_eq!(
+            batch
+                .valid_difficulty_transitions(&params, 2014, |_| None)
+                .await,
+            Err(HeaderSyncError::UnexpectedDifficultyChange)
+        );
+        // The difficulty increased by a factor of four, and the start of the period is unknown
+        assert!(batch
+            .valid_difficulty_transitions(&params, 2015, |_| None)
+            .await
+            .is_ok());
+        let batch = HeadersBatch::new(vec![
+            header_with_bits(0x1d00ffff),
+            header_with_bits(0x1c3fff00),
+        ])
+        .unwrap();
+        // The difficulty increased by more than a factor of four
+        assert_eq!(
+            batch
+                .valid_difficulty_transitions(&params, 2015, |_| None)
+                .await,
+            Err(HeaderSyncError::MiscalculatedDifficulty)
+        );
+        // Easier than the network allows
+        let batch = HeadersBatch::new(vec![header_with_bits(0x1d01ffff)]).unwrap();
+        assert!(!batch.meet_network_minimum(&params).await);
+    }
+
+    #[tokio::test]
+    async fn test_future_times() {
+        let now = 1_700_000_000;
+        let batch = HeadersBatch::new(vec![
+            header_with_time(0x1d00ffff, now as u32),
+            header_with_time(0x1d00ffff, now as u32 + 2 * 60 * 60),
+        ])
+        .unwrap();
+        assert!(batch.valid_future_times(now).await);
+        // Our peers believe it is a minute earlier than we do
+        assert!(!batch.valid_future_times(now - 60).await);
+    }
+
+    // Fixtures from the `pow_tests` of Bitcoin Core
+    #[test]
+    fn test_next_work_required() {
+        let params = params_from_network(&Network::Bitcoin);
+        for (first_time, last_time, last_bits, expected) in [
+            // Blocks 30240 and 32255
+            (1261130161, 1262152739, 0x1d00ffff, 0x1d00d86a),
+            // Blocks 0 and 2015, limited by the maximum target
+            (1231006505, 1233061996, 0x1d00ffff, 0x1d00ffff),
+            // Blocks 66528 and 68543, limited by a factor of four
+            (1279008237, 1279297671, 0x1c05a3f4, 0x1c0168fd),
+            // Blocks 46368 and 48383, limited by a factor of four
+            (1263163443, 1269211443, 0x1c387f6f, 0x1d00e1fd),
+        ] {
+            let first = header_with_time(0x1d00ffff, first_time);
+            let last = header_with_time(last_bits, last_time);
+            assert_eq!(
+                next_work_required(&last, &first, &params),
+                CompactTarget::from_consensus(expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retargets_with_known_period() {
+        let params = params_from_network(&Network::Bitcoin);
+        let first = header_with_time(0x1d00ffff, 1261130161);
+        let last = header_with_time(0x1d00ffff, 1262152739);
+        let previous = |height: u32| match height {
+            30240 => Some(first),
+            _ => None,
+        };
+        let batch =
+            HeadersBatch::new(vec![last, header_with_time(0x1d00d86a, 1262153000)]).unwrap();
+        assert!(batch
+            .valid_difficulty_transitions(&params, 32255, previous)
+            .await
+            .is_ok());
+        // Within the bounds of an adjustment, but not the computed adjustment
+        let batch =
+            HeadersBatch::new(vec![last, header_with_time(0x1d00d800, 1262153000)]).unwrap();
+        assert_eq!(
+            batch
+                .valid_difficulty_transitions(&params, 32255, previous)
+                .await,
+            Err(HeaderSyncError::MiscalculatedDifficulty)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_min_difficulty_blocks() {
+        let params = params_from_network(&Network::Testnet);
+        let start = header_with_time(0x1c00ffff, 1_000_000);
+        let previous = |height: u32| match height {
+            4032 => Some(start),
+            _ => None,
+        };
+        let batch = HeadersBatch::new(vec![
+            // Twenty minutes after the previous block, so the minimum difficulty is not allowed
+            header_with_time(0x1c00ffff, 1_001_200),
+            // More than twenty minutes after the previous block
+            header_with_time(0x1d00ffff, 1_002_401),
+            // The difficulty returns to the last block without the minimum difficulty
+            header_with_time(0x1c00ffff, 1_002_402),
+        ])
+        .unwrap();
+        assert!(batch
+            .valid_difficulty_transitions(&params, 4033, previous)
+            .await
+            .is_ok());
+        let batch = HeadersBatch::new(vec![
+            header_with_time(0x1d00ffff, 1_002_401),
+            header_with_time(0x1d00ffff, 1_002_402),
+        ])
+        .unwrap();
+        assert_eq!(
+            batch
+                .valid_difficulty_transitions(&params, 4033, previous)
+                .await,
+            Err(HeaderSyncError::UnexpectedDifficultyChange)
+        );
+        // Regtest blocks always have the minimum difficulty and never adjust
+        let params = params_from_network(&Network::Regtest);
+        let batch = HeadersBatch::new(vec![
+            header_with_time(0x207fffff, 1_000_000),
+            header_with_time(0x207fffff, 1_000_001),
+        ])
+        .unwrap();
+        assert!(batch
+            .valid_difficulty_transitions(&params, 2015, |_| Some(header_with_time(
+                0x207fffff, 999_999
+            )))
+            .await
+            .is_ok());
+        let batch = HeadersBatch::new(vec![header_with_time(0x1d00ffff, 1_000_000)]).unwrap();
+        assert_eq!(
+            batch
+                .valid_difficulty_transitions(&params, 2016, |_| Some(header_with_time(
+                    0x207fffff, 999_999
+                )))
+                .await,
+            Err(HeaderSyncError::MiscalculatedDifficulty)
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use bitcoin::{block::Header, BlockHash, Work};
 
@@ -9,69 +9,96 @@ use super::checkpoints::HeaderCheckpoint;
 pub(crate) type Headers = BTreeMap<u32, Header>;
 
 const LOCATOR_LOOKBACKS: &[usize] = &[1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024];
-const MAX_LOOKBACK: usize = 1025;
+// The number of headers below the tip that are kept in memory. The difficulty of a header depends on at most one
+// adjustment period of headers before it, so the difficulty of forks within the window can be audited.
+const HEADER_WINDOW: u32 = 4_032;
 
+// The most recent headers of the chain, indexed by block hash. Hashing a header is expensive, so the hash of each
+// header is computed once when it is added to the chain. Older headers may be pruned from memory once they are
+// persisted, after which they are read from the header store.
 #[derive(Debug)]
 pub(crate) struct HeaderChain {
     anchor_checkpoint: HeaderCheckpoint,
     headers: Headers,
+    // The block hashes of the headers in order, starting with the header after the pruned headers
+    hashes: Vec<BlockHash>,
+    // The height of every block hash in memory, including the anchor until headers are pruned
+    heights: HashMap<BlockHash, u32>,
+    // The number of headers after the anchor that were pruned from memory
+    pruned: u32,
+    // The work of the pruned headers
+    pruned_work: Work,
+    pruned_log2_work: f64,
 }
 
 impl HeaderChain {
     pub(crate) fn new(checkpoint: HeaderCheckpoint, headers: Headers) -> Self {
-        Self {
+        let mut chain = Self {
             anchor_checkpoint: checkpoint,
-            headers,
+            headers: BTreeMap::new(),
+            hashes: Vec::new(),
+            heights: HashMap::from([(checkpoint.hash, checkpoint.height)]),
+            pruned: 0,
+            pruned_work: Work::from_be_bytes([0; 32]),
+            pruned_log2_work: 0.0,
+        };
+        for header in headers.into_values() {
+            chain.push(header);
         }
+        chain
     }
 
     // Top of the chain
     pub(crate) fn tip(&self) -> BlockHash {
-        match self.headers.values().last() {
-            Some(header) => header.block_hash(),
+        match self.hashes.last() {
+            Some(hash) => *hash,
             None => self.anchor_checkpoint.hash,
         }
     }
 
     // The canoncial height of the chain, one less than the length
     pub(crate) fn height(&self) -> u32 {
-        self.headers.len() as u32 + self.anchor_checkpoint.height
+        self.headers.len() as u32 + self.pruned + self.anchor_checkpoint.height
     }
 
-    // The length of the chain we have interally
+    // The number of headers we have in memory
     pub(crate) fn inner_len(&self) -> usize {
         self.headers().len()
     }
 
-    // All the headers of the chain
+    // All the headers in memory
     pub(crate) fn headers(&self) -> &Headers {
         &self.headers
     }
 
-    // All the headers of the chain
+    // All the headers in memory
     pub(crate) fn values(&self) -> Vec<Header> {
         self.headers.values().copied().collect()
     }
 
     // This header chain contains a block hash
     pub(crate) fn contains_hash(&self, blockhash: BlockHash) -> bool {
-        self.headers
-            .values()
-            .any(|header| header.block_hash().eq(&blockhash))
-            || blockhash.eq(&self.anchor_checkpoint.hash)
+        self.heights.contains_key(&blockhash)
     }
 
     // The height of the blockhash in the chain
-    pub(crate) async fn height_of_hash(&self, blockhash: BlockHash) -> Option<u32> {
-        if blockhash.eq(&self.anchor_checkpoint.hash) {
-            return Some(self.anchor_checkpoint.height);
-        }
-        for (height, header) in self.headers.iter().rev() {
-            if header.block_hash().eq(&blockhash) {
-                return Some(*height);
-            }
+    pub(crate) fn height_of_hash(&self, blockhash: BlockHash) -> Option<u32> {
+        self.heights.get(&blockhash).copied()
+    }
+
+    // The block hash at a height in memory, including the anchor
+    pub(crate) fn block_hash_at_height(&self, height: u32) -> Option<BlockHash> {
+        if height.eq(&self.anchor_checkpoint.height) {
+            return Some(self.anchor_checkpoint.hash);
         }
-        None
+        let index = height.checked_sub(self.anchor_checkpoint.height + self.pruned + 1)?;
+        self.hashes.get(index as usize).copied()
+    }
+
+    // The header at this height was pruned from memory
+    pub(crate) fn is_pruned(&self, height: u32) -> bool {
+        height.gt(&self.anchor_checkpoint.height)
+            && height.le(&(self.anchor_checkpoint.height + self.pruned))
     }
 
     // This header chain contains a block hash
@@ -81,7 +108,10 @@ impl HeaderChain {
 
     // This header chain contains a block hash
     pub(crate) fn contains_header(&self, other: &Header) -> bool {
-        self.headers.values().any(|header| header.eq(other))
+        self.heights
+            .get(&other.block_hash())
+            .and_then(|height| self.headers.get(height))
+            .map_or(false, |header| header.eq(other))
     }
 
     // Compute the total work for the chain
@@ -98,7 +128,7 @@ impl HeaderChain {
 
     // Canoncial chainwork from the anchor checkpoint
     pub(crate) fn chainwork(&self) -> Work {
-        self.get_chainwork(&self.headers)
+        self.pruned_work + self.get_chainwork(&self.headers)
     }
 
     // Calculate the chainwork after a fork height to evalutate the fork
@@ -123,7 +153,7 @@ impl HeaderChain {
             .values()
             .map(|header| header.work().log2())
             .reduce(|acc, next| acc + next);
-        work.unwrap_or(0.0)
+        self.pruned_log2_work + work.unwrap_or(0.0)
     }
 
     // The last 11 headers, if we have that many
@@ -138,18 +168,11 @@ impl HeaderChain {
     }
 
     // The block locators are a way to inform our peer of blocks we know about
-    pub(crate) fn locators(&mut self) -> Vec<BlockHash> {
+    pub(crate) fn locators(&self) -> Vec<BlockHash> {
         let mut locators = Vec::new();
-        let rev: Vec<BlockHash> = self
-            .headers
-            .values()
-            .rev()
-            .take(MAX_LOOKBACK)
-            .map(|header| header.block_hash())
-            .collect();
         locators.push(self.tip());
         for locator in LOCATOR_LOOKBACKS {
-            match rev.get(*locator) {
+            match self.hashes.iter().rev().nth(*locator) {
                 Some(hash) => locators.push(*hash),
                 None => break,
             }
@@ -161,71 +184,104 @@ impl HeaderChain {
     pub(crate) fn extend(&mut self, batch: &[Header]) -> Vec<DisconnectedHeader> {
         let mut reorged = Vec::new();
         // We cannot extend from nothing
-        if batch.is_empty() {
-            return reorged;
-        }
-        // This is the usual case where the headers link
-        if self.tip().eq(&batch
-            .first()
-            .expect("cannot extend from empty batch")
-            .prev_blockhash)
-        {
-            let current_anchor = self.height();
-            for (index, header) in batch.iter().enumerate() {
-                self.headers
-                    .insert(current_anchor + 1 + index as u32, *header);
-            }
-        } else {
+        let prev_blockhash = match batch.first() {
+            Some(header) => header.prev_blockhash,
+            None => return reorged,
+        };
+        // Unless the headers link to our tip, the headers after the one they link to are disconnected
+        if self.tip().ne(&prev_blockhash) {
             // Panic if we don't contain the hash. Something went wrong further up the call stack.
-            assert!(self.contains_hash(
-                batch
-                    .first()
-                    .expect("cannot extend from empty batch")
-                    .prev_blockhash
-            ));
-            // Supporting MSRV of 1.63 requires this round-about way of removing the headers instead of `pop_last`
-            // Find the header that matches the new batch prev block hash, collecting the disconnected headers
-            for (height, header) in self.headers.iter().rev() {
-                if header.block_hash().ne(&batch
-                    .first()
-                    .expect("cannot extend from empty batch")
-                    .prev_blockhash)
-                {
-                    reorged.push(DisconnectedHeader::new(*height, *header));
-                } else {
-                    break;
-                }
-            }
-            // Actually remove anything that should no longer be connected
-            for removal in reorged.iter() {
-                self.remove(&removal.height);
+            let fork_height = self
+                .height_of_hash(prev_blockhash)
+                .expect("the batch must connect to the chain");
+            let disconnected = self.headers.split_off(&(fork_height + 1));
+            for (height, header) in disconnected.into_iter().rev() {
+                reorged.push(DisconnectedHeader::new(height, header));
             }
-            // Add back the new headers, starting at the proper link
-            let current_anchor = self.height();
-            for (index, header) in batch.iter().enumerate() {
-                self.headers
-                    .insert(current_anchor + 1 + index as u32, *header);
+            let retained = (fork_height - self.anchor_checkpoint.height - self.pruned) as usize;
+            for hash in self.hashes.drain(retained..) {
+                self.heights.remove(&hash);
             }
         }
+        for header in batch {
+            self.push(*header);
+        }
         reorged
     }
 
-    fn remove(&mut self, height: &u32) {
-        self.headers.remove(height);
+    fn push(&mut self, header: Header) {
+        let height = self.height() + 1;
+        let hash = header.block_hash();
+        self.headers.insert(height, header);
+        self.hashes.push(hash);
+        self.heights.insert(hash, height);
+    }
+
+    // The headers up to and including this height may be pruned, once the headers in memory have grown to twice
+    // the window kept below the tip
+    pub(crate) fn prune_height(&self) -> Option<u32> {
+        if (self.headers.len() as u32).lt(&(2 * HEADER_WINDOW)) {
+            return None;
+        }
+        Some(self.height() - HEADER_WINDOW)
+    }
+
+    // Remove the headers up to and including this height from memory. The headers must be persisted first.
+    pub(crate) fn prune(&mut self, height: u32) {
+        let retained = self.headers.split_off(&(height + 1));
+        let pruned = std::mem::replace(&mut self.headers, retained);
+        for header in pruned.values() {
+            self.pruned_work = self.pruned_work + header.work();
+            self.pruned_log2_work += header.work().log2();
+        }
+        for hash in self.hashes.drain(..pruned.len()) {
+            self.heights.remove(&hash);
+        }
+        // A fork from the anchor cannot be evaluated without the pruned headers
+        self.heights.remove(&self.anchor_checkpoint.hash);
+        self.pruned += pruned.len() as u32;
     }
 
     // Clear all the headers from our chain. Only to be used when a peer has feed us faulty checkpoints
     pub(crate) fn clear_all(&mut self) {
-        self.headers.clear()
+        self.headers.clear();
+        self.hashes.clear();
+        self.heights.clear();
+        self.heights
+            .insert(self.anchor_checkpoint.hash, self.anchor_checkpoint.height);
+        self.pruned = 0;
+        self.pruned_work = Work::from_be_bytes([0; 32]);
+        self.pruned_log2_work = 0.0;
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use bitcoin::consensus::deserialize;
+    use bitcoin::{
+        block::Version, consensus::deserialize, hashes::Hash, CompactTarget, TxMerkleNode,
+    };
 
     use super::*;
-    use std::str::FromStr;
+    use std::{str::FromStr, time::Instant};
+
+    // Headers that link together, without a valid proof of work
+    fn linked_headers(prev_blockhash: BlockHash, count: u32, nonce: u32) -> Vec<Header> {
+        let mut prev_blockhash = prev_blockhash;
+        (0..count)
+            .map(|time| {
+                let header = Header {
+                    version: Version::TWO,
+                    prev_blockhash,
+                    merkle_root: TxMerkleNode::all_zeros(),
+                    time,
+                    bits: CompactTarget::from_consensus(0x207fffff),
+                    nonce,
+                };
+                prev_blockhash = header.block_hash();
+                header
+            })
+            .collect()
+    }
 
     #[test]
     fn test_empty_chain() {
@@ -376,6 +432,38 @@ mod tests {
         assert_eq!(chain.header_at_height(3), Some(&new_block_3));
         assert_eq!(chain.header_at_height(2), Some(&block_2));
         assert_eq!(chain.header_at_height(1), Some(&block_1));
+        // The disconnected headers are no longer indexed
+        assert!(!chain.contains_hash(block_3.block_hash()));
+        assert!(!chain.contains_header(&block_4));
+        assert!(chain.contains_header(&new_block_4));
+        assert_eq!(chain.height_of_hash(new_block_3.block_hash()), Some(3));
+        assert_eq!(chain.height_of_hash(block_2.block_hash()), Some(2));
+        assert_eq!(
+            chain.block_hash_at_height(0),
+            Some(
+                BlockHash::from_str(
+                    "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"
+                )
+                .unwrap()
+            )
+        );
+        assert_eq!(
+            chain.block_hash_at_height(4),
+            Some(new_block_4.block_hash())
+        );
+        assert_eq!(chain.block_hash_at_height(5), None);
+        assert_eq!(
+            chain.locators(),
+            vec![
+                new_block_4.block_hash(),
+                new_block_3.block_hash(),
+                block_2.block_hash()
+            ]
+        );
+        chain.clear_all();
+        assert_eq!(chain.height(), 0);
+        assert!(!chain.contains_hash(block_1.block_hash()));
+        assert_eq!(chain.height_of_hash(chain.tip()), Some(0));
     }
 
     #[tokio::test]
@@ -414,7 +502,98 @@ mod tests {
             chain.values()
         );
         assert_eq!(chain.header_at_height(3), Some(&block_3));
-        let want = chain.height_of_hash(new_block_2.block_hash()).await;
+        let want = chain.height_of_hash(new_block_2.block_hash());
         assert_eq!(Some(2), want);
     }
+
+    #[test]
+    fn test_prunes_old_headers() {
+        let anchor = HeaderCheckpoint::new(10, BlockHash::all_zeros());
+        let mut chain = HeaderChain::new(anchor, BTreeMap::new());
+        let batch = linked_headers(anchor.hash, 2 * HEADER_WINDOW - 1, 0);
+        chain.extend(&batch);
+        assert_eq!(chain.prune_height(), None);
+        let next = linked_headers(chain.tip(), 1, 0);
+        chain.extend(&next);
+        let height = chain.prune_height().unwrap();
+        assert_eq!(height, 10 + HEADER_WINDOW);
+        let work = chain.chainwork();
+        let log2_work = chain.log2_work();
+        chain.prune(height);
+        assert_eq!(chain.inner_len(), HEADER_WINDOW as usize);
+        assert_eq!(chain.height(), 10 + 2 * HEADER_WINDOW);
+        assert_eq!(chain.tip(), next[0].block_hash());
+        assert_eq!(chain.chainwork(), work);
+        assert!((chain.log2_work() - log2_work).abs() < 1e-6);
+        // The pruned headers are no longer in memory
+        assert!(chain.is_pruned(11));
+        assert!(chain.is_pruned(height));
+        assert!(!chain.is_pruned(height + 1));
+        assert!(!chain.contains_hash(anchor.hash));
+        assert!(!chain.contains_header(&batch[0]));
+        assert_eq!(chain.block_hash_at_height(height), None);
+        let first = batch[HEADER_WINDOW as usize];
+        assert_eq!(
+            chain.block_hash_at_height(height + 1),
+            Some(first.block_hash())
+        );
+        assert_eq!(chain.height_of_hash(first.block_hash()), Some(height + 1));
+        // Forks within the headers in memory are still followed
+        let fork = linked_headers(first.block_hash(), HEADER_WINDOW, 1);
+        let reorged = chain.extend(&fork);
+        assert_eq!(reorged.len(), HEADER_WINDOW as usize - 1);
+        assert_eq!(chain.height(), height + 1 + HEADER_WINDOW);
+        assert_eq!(chain.tip(), fork.last().unwrap().block_hash());
+        assert_eq!(chain.prune_height(), None);
+    }
+
+    // Times lookups into a chain the length of a sync from a mainnet checkpoint, against hashing every header
+    // until a match is found. Run with `cargo test --release bench_header_chain -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_header_chain() {
+        const CHAIN_LENGTH: u32 = 100_000;
+        const REORG_DEPTH: u32 = 6;
+        const ITERATIONS: u32 = 100;
+        let anchor = HeaderCheckpoint::new(0, BlockHash::all_zeros());
+        let batch = linked_headers(anchor.hash, CHAIN_LENGTH, 0);
+        let mut chain = HeaderChain::new(anchor, BTreeMap::new());
+        chain.extend(&batch);
+        // The header is taken from the middle, so a scan from either end hashes half of the chain
+        let header = batch[(CHAIN_LENGTH / 2) as usize];
+        let hash = header.block_hash();
+        let unknown = BlockHash::from_byte_array([1; 32]);
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            assert!(chain.contains_hash(hash));
+            assert!(!chain.contains_hash(unknown));
+            assert_eq!(chain.height_of_hash(hash), Some(CHAIN_LENGTH / 2 + 1));
+            assert!(chain.contains_header(&header));
+        }
+        let indexed = start.elapsed() / ITERATIONS;
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let height = chain
+                .headers()
+                .iter()
+                .find(|(_, header)| header.block_hash().eq(&hash))
+                .map(|(height, _)| *height);
+            assert_eq!(height, Some(CHAIN_LENGTH / 2 + 1));
+        }
+        let scanned = start.elapsed() / ITERATIONS;
+        let fork_point = batch[(CHAIN_LENGTH - REORG_DEPTH - 1) as usize].block_hash();
+        let fork = linked_headers(fork_point, REORG_DEPTH + 1, 1);
+        let tail = &batch[(CHAIN_LENGTH - REORG_DEPTH) as usize..];
+        let start = Instant::now();
+        // The chain is reorganized to the fork and back, so every iteration starts from the same chain
+        for _ in 0..ITERATIONS {
+            assert_eq!(chain.extend(&fork).len(), REORG_DEPTH as usize);
+            assert_eq!(chain.extend(tail).len(), REORG_DEPTH as usize + 1);
+        }
+        let reorg = start.elapsed() / ITERATIONS;
+        println!("Indexed lookups: {:?}", indexed);
+        println!("Scanning for a hash: {:?}", scanned);
+        println!("Reorganizing {} blocks and back: {:?}", REORG_DEPTH, reorg);
+        assert!(indexed < scanned);
+    }
 }
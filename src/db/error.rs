@@ -6,6 +6,10 @@ pub enum DatabaseError {
     LoadError,
     #[error("writing a query or data from the database failed")]
     WriteError,
+    #[error("the data in the database is corrupted")]
+    Corruption,
+    #[error("the database schema version {0} is not supported")]
+    UnsupportedSchema(u32),
 }
 
 #[derive(Error, Debug)]
@@ -14,4 +18,6 @@ pub enum PeerManagerError {
     Dns,
     #[error("reading or writing from the database failed")]
     Database(DatabaseError),
+    #[error("no peer in the database is on a network that can be reached")]
+    Unreachable,
 }
@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use bitcoin::{block::Header, BlockHash};
+
+use crate::{
+    chain::checkpoints::HeaderCheckpoint,
+    db::{error::DatabaseError, traits::HeaderStore},
+};
+
+/// Block headers indexed by height. Headers are lost when the program exits, so the chain is synced
+/// from the anchor checkpoint every time the node is ran.
+#[derive(Debug, Default)]
+pub struct MemoryHeaderStore {
+    headers: BTreeMap<u32, Header>,
+    // The height of every header, so headers are found by hash without hashing the chain
+    heights: HashMap<BlockHash, u32>,
+}
+
+impl MemoryHeaderStore {
+    /// Create a new, empty header store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, height: u32, header: Header) {
+        self.heights.insert(header.block_hash(), height);
+        self.headers.insert(height, header);
+    }
+
+    // Remove the headers at and above this height
+    fn remove_from(&mut self, height: u32) {
+        for header in self.headers.split_off(&height).values() {
+            self.heights.remove(&header.block_hash());
+        }
+    }
+}
+
+#[async_trait]
+impl HeaderStore for MemoryHeaderStore {
+    async fn load(&mut self, anchor_height: u32) -> Result<BTreeMap<u32, Header>, DatabaseError> {
+        Ok(self
+            .headers
+            .range(anchor_height + 1..)
+            .map(|(height, header)| (*height, *header))
+            .collect())
+    }
+
+    async fn write<'a>(
+        &mut self,
+        header_chain: &'a BTreeMap<u32, Header>,
+    ) -> Result<(), DatabaseError> {
+        for (height, header) in header_chain {
+            if !self.headers.contains_key(height) {
+                self.insert(*height, *header);
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_over<'a>(
+        &mut self,
+        header_chain: &'a BTreeMap<u32, Header>,
+        height: u32,
+    ) -> Result<(), DatabaseError> {
+        // Headers of the old chain past the fork no longer link to the new chain
+        self.remove_from(height);
+        for (height, header) in header_chain.range(height..) {
+            self.insert(*height, *header);
+        }
+        Ok(())
+    }
+
+    async fn height_of<'a>(&mut self, hash: &'a BlockHash) -> Result<Option<u32>, DatabaseError> {
+        Ok(self.heights.get(hash).copied())
+    }
+
+    async fn header_at(&mut self, height: u32) -> Result<Option<Header>, DatabaseError> {
+        Ok(self.headers.get(&height).copied())
+    }
+
+    async fn headers_in_range(
+        &mut self,
+        start_height: u32,
+        end_height: u32,
+    ) -> Result<BTreeMap<u32, Header>, DatabaseError> {
+        if start_height.gt(&end_height) {
+            return Ok(BTreeMap::new());
+        }
+        Ok(self
+            .headers
+            .range(start_height..=end_height)
+            .map(|(height, header)| (*height, *header))
+            .collect())
+    }
+
+    async fn hash_at(&mut self, height: u32) -> Result<Option<BlockHash>, DatabaseError> {
+        Ok(self.headers.get(&height).map(|header| header.block_hash()))
+    }
+
+    async fn tip(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError> {
+        Ok(self
+            .headers
+            .iter()
+            .next_back()
+            .map(|(height, header)| HeaderCheckpoint::new(*height, header.block_hash())))
+    }
+
+    async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError> {
+        self.remove_from(height + 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::consensus::deserialize;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_writes_over_forks() {
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020299e41732deb76d869fcdb5f72518d3784e99482f572afb73068d52134f1f75e1f20f5da8d18661d0f13aa3db8fff0f53598f7d61f56988a6d66573394b2c6ffc5805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("00000020b96feaa82716f11befeb608724acee4743e0920639a70f35f1637a88b8b6ea3471f1dbedc283ce6a43a87ed3c8e6326dae8d3dbacce1b2daba08e508054ffdb697815e66ffff7f2001000000").unwrap()).unwrap();
+        let mut store = MemoryHeaderStore::new();
+        let chain = BTreeMap::from([(1, block_1), (2, block_2), (3, block_3)]);
+        store.write(&chain).await.unwrap();
+        assert_eq!(store.load(1).await.unwrap().len(), 2);
+        assert_eq!(
+            store.height_of(&block_3.block_hash()).await.unwrap(),
+            Some(3)
+        );
+        // Existing headers are not replaced by a plain write
+        store.write(&BTreeMap::from([(2, block_3)])).await.unwrap();
+        assert_eq!(
+            store.height_of(&block_2.block_hash()).await.unwrap(),
+            Some(2)
+        );
+        // A fork at height two replaces the headers from that height on
+        store
+            .write_over(&BTreeMap::from([(1, block_1), (2, block_3)]), 2)
+            .await
+            .unwrap();
+        assert_eq!(store.height_of(&block_2.block_hash()).await.unwrap(), None);
+        assert_eq!(
+            store.height_of(&block_3.block_hash()).await.unwrap(),
+            Some(2)
+        );
+        assert_eq!(store.load(0).await.unwrap().len(), 2);
+        assert_eq!(store.header_at(2).await.unwrap(), Some(block_3));
+        assert_eq!(store.header_at(3).await.unwrap(), None);
+        assert_eq!(
+            store.headers_in_range(2, 10).await.unwrap(),
+            BTreeMap::from([(2, block_3)])
+        );
+        assert!(store.headers_in_range(2, 1).await.unwrap().is_empty());
+        assert_eq!(store.hash_at(2).await.unwrap(), Some(block_3.block_hash()));
+        assert_eq!(
+            store.tip().await.unwrap(),
+            Some(HeaderCheckpoint::new(2, block_3.block_hash()))
+        );
+        store.truncate(1).await.unwrap();
+        assert_eq!(store.hash_at(2).await.unwrap(), None);
+        assert_eq!(store.height_of(&block_3.block_hash()).await.unwrap(), None);
+        assert_eq!(
+            store.height_of(&block_1.block_hash()).await.unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            store.tip().await.unwrap(),
+            Some(HeaderCheckpoint::new(1, block_1.block_hash()))
+        );
+    }
+}
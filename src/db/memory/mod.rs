@@ -0,0 +1,4 @@
+/// A [`crate::db::traits::HeaderStore`] that keeps block headers in memory.
+pub mod headers;
+/// A [`crate::db::traits::PeerStore`] that keeps peers in memory.
+pub mod peers;
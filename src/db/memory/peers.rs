@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use bitcoin::p2p::address::AddrV2;
+use rand::{seq::IteratorRandom, thread_rng, Rng};
+
+use crate::{
+    db::{error::DatabaseError, now, traits::PeerStore, PersistedPeer},
+    prelude::SlashSixteen,
+    PeerNetwork,
+};
+
+/// Peers are kept in a table of peers we have connected to and a table of peers we have only heard about,
+/// similar to the address manager of Bitcoin Core. Banned peers are remembered so they are not added again
+/// when they are gossiped to us, and return to the tried table once their ban expires.
+#[derive(Debug, Default)]
+pub struct MemoryPeerStore {
+    tried: HashMap<AddrV2, PersistedPeer>,
+    new: HashMap<AddrV2, PersistedPeer>,
+    banned: HashMap<AddrV2, PersistedPeer>,
+}
+
+impl MemoryPeerStore {
+    /// Create a new, empty peer store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn contains(&self, addr: &AddrV2) -> bool {
+        self.tried.contains_key(addr)
+            || self.new.contains_key(addr)
+            || self.banned.contains_key(addr)
+    }
+
+    // Move the peers with an expired ban back to the tried table
+    fn unban_expired(&mut self) {
+        let now = now();
+        let expired: Vec<AddrV2> = self
+            .banned
+            .iter()
+            .filter(|(_, peer)| !peer.is_banned(now))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in expired {
+            if let Some(mut peer) = self.banned.remove(&addr) {
+                peer.banned_until = None;
+                self.tried.insert(addr, peer);
+            }
+        }
+    }
+
+    // Select a net group at random and then a peer from that net group, so a large number of
+    // peers in one net group are no more likely to be selected than a single peer in another.
+    fn random_from(
+        table: &HashMap<AddrV2, PersistedPeer>,
+        networks: &[PeerNetwork],
+    ) -> Option<PersistedPeer> {
+        let mut rng = thread_rng();
+        let netgroups: HashSet<String> = table
+            .keys()
+            .filter(|addr| on_networks(addr, networks))
+            .map(netgroup)
+            .collect();
+        let selected = netgroups.into_iter().choose(&mut rng)?;
+        table
+            .iter()
+            .filter(|(addr, _)| netgroup(addr).eq(&selected))
+            .map(|(_, peer)| peer)
+            .choose(&mut rng)
+            .cloned()
+    }
+}
+
+fn netgroup(addr: &AddrV2) -> String {
+    addr.clone().slash_sixteen()
+}
+
+fn on_networks(addr: &AddrV2, networks: &[PeerNetwork]) -> bool {
+    PeerNetwork::from_addr(addr).map_or(false, |network| networks.contains(&network))
+}
+
+#[async_trait]
+impl PeerStore for MemoryPeerStore {
+    async fn update(&mut self, peer: PersistedPeer, replace: bool) -> Result<(), DatabaseError> {
+        if !replace && self.contains(&peer.addr) {
+            // Hearing about a peer again only tells us it was online more recently
+            if let Some(known) = self.new.get_mut(&peer.addr) {
+                known.last_seen = known.last_seen.max(peer.last_seen);
+            }
+            if let Some(known) = self.tried.get_mut(&peer.addr) {
+                known.last_seen = known.last_seen.max(peer.last_seen);
+            }
+            return Ok(());
+        }
+        self.tried.remove(&peer.addr);
+        self.new.remove(&peer.addr);
+        self.banned.remove(&peer.addr);
+        if peer.banned_until.is_some() {
+            self.banned.insert(peer.addr.clone(), peer);
+        } else if peer.tried {
+            self.tried.insert(peer.addr.clone(), peer);
+        } else {
+            self.new.insert(peer.addr.clone(), peer);
+        }
+        Ok(())
+    }
+
+    async fn random(&mut self, networks: &[PeerNetwork]) -> Result<PersistedPeer, DatabaseError> {
+        self.unban_expired();
+        // Prefer neither table when both have peers
+        let tried = Self::random_from(&self.tried, networks);
+        let new = Self::random_from(&self.new, networks);
+        match (tried, new) {
+            (Some(tried), Some(new)) => {
+                if thread_rng().gen_bool(0.5) {
+                    Ok(tried)
+                } else {
+                    Ok(new)
+                }
+            }
+            (Some(peer), None) | (None, Some(peer)) => Ok(peer),
+            (None, None) => Err(DatabaseError::LoadError),
+        }
+    }
+
+    async fn num_unbanned(&mut self) -> Result<u32, DatabaseError> {
+        self.unban_expired();
+        Ok((self.tried.len() + self.new.len()) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcoin::p2p::ServiceFlags;
+
+    use super::*;
+
+    fn peer(addr: [u8; 4], tried: bool, banned_until: Option<u64>) -> PersistedPeer {
+        PersistedPeer::new(
+            AddrV2::Ipv4(Ipv4Addr::from(addr)),
+            8333,
+            ServiceFlags::NONE,
+            0,
+            tried,
+            banned_until,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tracks_tried_and_banned_peers() {
+        let mut store = MemoryPeerStore::new();
+        assert!(store.random(&PeerNetwork::ALL).await.is_err());
+        store
+            .update(peer([1, 1, 1, 1], false, None), false)
+            .await
+            .unwrap();
+        store
+            .update(peer([2, 2, 2, 2], false, None), false)
+            .await
+            .unwrap();
+        assert_eq!(store.num_unbanned().await.unwrap(), 2);
+        // A gossiped peer does not replace one we have tried
+        store
+            .update(peer([1, 1, 1, 1], true, None), true)
+            .await
+            .unwrap();
+        store
+            .update(peer([1, 1, 1, 1], false, None), false)
+            .await
+            .unwrap();
+        assert!(store
+            .tried
+            .contains_key(&AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1))));
+        assert_eq!(store.num_unbanned().await.unwrap(), 2);
+        // A banned peer is never selected or added back
+        store
+            .update(peer([2, 2, 2, 2], true, Some(u64::MAX)), true)
+            .await
+            .unwrap();
+        store
+            .update(peer([2, 2, 2, 2], false, None), false)
+            .await
+            .unwrap();
+        assert_eq!(store.num_unbanned().await.unwrap(), 1);
+        for _ in 0..20 {
+            assert_eq!(
+                store.random(&PeerNetwork::ALL).await.unwrap().addr,
+                AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unbans_peers_after_the_ban_expires() {
+        let mut store = MemoryPeerStore::new();
+        store
+            .update(peer([1, 1, 1, 1], true, Some(u64::MAX)), true)
+            .await
+            .unwrap();
+        assert!(store.random(&PeerNetwork::ALL).await.is_err());
+        // A ban that expired in the past no longer applies
+        store
+            .update(peer([1, 1, 1, 1], true, Some(1)), true)
+            .await
+            .unwrap();
+        assert_eq!(store.num_unbanned().await.unwrap(), 1);
+        let peer = store.random(&PeerNetwork::ALL).await.unwrap();
+        assert_eq!(peer.addr, AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1)));
+        assert_eq!(peer.banned_until, None);
+    }
+
+    #[tokio::test]
+    async fn test_selects_across_netgroups() {
+        let mut store = MemoryPeerStore::new();
+        for host in 0..100 {
+            store
+                .update(peer([1, 1, 1, host], false, None), false)
+                .await
+                .unwrap();
+        }
+        store
+            .update(peer([2, 2, 2, 2], false, None), false)
+            .await
+            .unwrap();
+        let mut lone_selected = 0;
+        for _ in 0..200 {
+            if store
+                .random(&PeerNetwork::ALL)
+                .await
+                .unwrap()
+                .addr
+                .eq(&AddrV2::Ipv4(Ipv4Addr::new(2, 2, 2, 2)))
+            {
+                lone_selected += 1;
+            }
+        }
+        // The lone peer is in its own net group, so it is selected about half of the time
+        assert!(lone_selected > 50);
+    }
+
+    #[tokio::test]
+    async fn test_selects_from_networks() {
+        let mut store = MemoryPeerStore::new();
+        store
+            .update(peer([1, 1, 1, 1], false, None), false)
+            .await
+            .unwrap();
+        let onion = AddrV2::TorV3([7; 32]);
+        store
+            .update(
+                PersistedPeer::new(onion.clone(), 8333, ServiceFlags::NONE, 10, false, None),
+                false,
+            )
+            .await
+            .unwrap();
+        for _ in 0..20 {
+            assert_eq!(
+                store.random(&[PeerNetwork::TorV3]).await.unwrap().addr,
+                onion
+            );
+        }
+        assert!(store.random(&[PeerNetwork::I2p]).await.is_err());
+        // Gossip about a known peer only updates when it was last seen
+        store
+            .update(
+                PersistedPeer::new(onion.clone(), 8333, ServiceFlags::NONE, 20, false, None),
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(store.new.get(&onion).unwrap().last_seen, 20);
+    }
+}
@@ -1,9 +1,11 @@
-use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use bitcoin::p2p::ServiceFlags;
+use bitcoin::p2p::{address::AddrV2, ServiceFlags};
 
 /// Errors a database backend may produce.
 pub mod error;
+/// Databases that keep all data in memory, for devices without persistent storage.
+pub mod memory;
 pub(crate) mod peer_man;
 #[cfg(feature = "database")]
 pub(crate) mod sqlite;
@@ -13,26 +15,50 @@ pub mod traits;
 /// A peer that will be saved to the [`traits::PeerStore`].
 #[derive(Debug, Clone)]
 pub struct PersistedPeer {
-    /// Canonical IP address of this peer.
-    pub addr: IpAddr,
+    /// The address of this peer, which may be an IP address or an address on another [BIP-155](https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki)
+    /// network, like Tor.
+    pub addr: AddrV2,
     /// The port believed to be listening for connections.
     pub port: u16,
     /// The services this peer may offer.
     pub services: ServiceFlags,
+    /// The last time this peer was known to be online, as a UNIX timestamp in seconds.
+    pub last_seen: u64,
     /// Have we tried this peer before.
     pub tried: bool,
-    /// Did we ban this peer for faulty behavior.
-    pub banned: bool,
+    /// If we banned this peer for faulty behavior, the UNIX timestamp in seconds the ban expires at.
+    pub banned_until: Option<u64>,
 }
 
 impl PersistedPeer {
-    pub fn new(addr: IpAddr, port: u16, services: ServiceFlags, tried: bool, banned: bool) -> Self {
+    pub fn new(
+        addr: AddrV2,
+        port: u16,
+        services: ServiceFlags,
+        last_seen: u64,
+        tried: bool,
+        banned_until: Option<u64>,
+    ) -> Self {
         Self {
             addr,
             port,
             services,
+            last_seen,
             tried,
-            banned,
+            banned_until,
         }
     }
+
+    /// Is this peer banned at the given UNIX timestamp in seconds.
+    pub fn is_banned(&self, now: u64) -> bool {
+        self.banned_until.map_or(false, |until| now.lt(&until))
+    }
+}
+
+// The current UNIX timestamp in seconds, which peers are last seen and banned until
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs()
 }
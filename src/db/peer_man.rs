@@ -1,11 +1,21 @@
 use std::{collections::HashSet, net::IpAddr, sync::Arc};
 
-use bitcoin::{p2p::ServiceFlags, Network};
+use bitcoin::{
+    p2p::{address::AddrV2, ServiceFlags},
+    Network,
+};
 use tokio::sync::Mutex;
 
-use crate::prelude::{default_port_from_network, SlashSixteen};
+use crate::{
+    peers::addrv2::from_ip,
+    prelude::{default_port_from_network, SlashSixteen},
+    ConnectionMode, PeerNetwork,
+};
 
-use super::{error::PeerManagerError, traits::PeerStore, PersistedPeer};
+use super::{error::PeerManagerError, now, traits::PeerStore, PersistedPeer};
+
+// A banned peer is not connected to again for a day
+const BAN_DURATION: u64 = 60 * 60 * 24;
 
 #[derive(Debug, Clone)]
 pub(crate) struct PeerManager {
@@ -13,51 +23,97 @@ pub(crate) struct PeerManager {
     netgroups: HashSet<String>,
     network: Network,
     default_port: u16,
+    connection: ConnectionMode,
+    // The networks peers are selected from
+    networks: Vec<PeerNetwork>,
 }
 
 impl PeerManager {
-    pub(crate) fn new(db: impl PeerStore + Send + Sync + 'static, network: &Network) -> Self {
+    pub(crate) fn new(
+        db: impl PeerStore + Send + Sync + 'static,
+        network: &Network,
+        connection: ConnectionMode,
+        target_networks: Option<Vec<PeerNetwork>>,
+    ) -> Self {
         let default_port = default_port_from_network(network);
+        let networks = match target_networks {
+            Some(targets) => connection
+                .networks()
+                .into_iter()
+                .filter(|network| targets.contains(network))
+                .collect(),
+            None => connection.networks(),
+        };
         Self {
             db: Arc::new(Mutex::new(db)),
             netgroups: HashSet::new(),
             network: *network,
             default_port,
+            connection,
+            networks,
         }
     }
 
-    pub(crate) async fn next_peer(&mut self) -> Result<(IpAddr, u16), PeerManagerError> {
+    pub(crate) async fn next_peer(
+        &mut self,
+    ) -> Result<(AddrV2, u16, ServiceFlags), PeerManagerError> {
+        if self.networks.is_empty() {
+            return Err(PeerManagerError::Unreachable);
+        }
         let mut db_lock = self.db.lock().await;
+        // A peer in a net group we are already connected to
+        let mut fallback = None;
         let mut tries = 0;
         while tries < 10 {
-            let mut next = db_lock.random().await.map_err(PeerManagerError::Database)?;
+            tries += 1;
+            let mut next = db_lock
+                .random(&self.networks)
+                .await
+                .map_err(PeerManagerError::Database)?;
             if !self.netgroups.contains(&next.addr.slash_sixteen()) {
                 self.netgroups.insert(next.addr.slash_sixteen());
-                return Ok((next.addr, next.port));
+                return Ok((next.addr, next.port, next.services));
             }
-            tries += 1;
+            fallback = Some(next);
         }
-        let mut next = db_lock.random().await.map_err(PeerManagerError::Database)?;
+        let mut next = fallback.ok_or(PeerManagerError::Unreachable)?;
         self.netgroups.insert(next.addr.slash_sixteen());
-        Ok((next.addr, next.port))
+        Ok((next.addr, next.port, next.services))
+    }
+
+    // DNS seeds only return IPv4 and IPv6 addresses, and they are queried outside of any proxy
+    pub(crate) fn can_bootstrap(&self) -> bool {
+        !self.connection.is_proxied() && self.networks.iter().any(|network| network.is_clearnet())
     }
 
     #[cfg(feature = "dns")]
     pub(crate) async fn bootstrap(&mut self) -> Result<(), PeerManagerError> {
         use crate::peers::dns::Dns;
         use rand::{prelude::SliceRandom, rngs::StdRng, SeedableRng};
-        let mut db_lock = self.db.lock().await;
         let mut new_peers = Dns::bootstrap(self.network)
             .await
             .map_err(|_| PeerManagerError::Dns)?;
         let mut rng = StdRng::from_entropy();
         new_peers.shuffle(&mut rng);
         // DNS fails if there is an insufficient number of peers
-        for peer in new_peers {
+        self.add_seed_peers(new_peers).await
+    }
+
+    // Seeds may return peers we already know of, and a peer we banned stays banned
+    async fn add_seed_peers(&mut self, peers: Vec<IpAddr>) -> Result<(), PeerManagerError> {
+        let mut db_lock = self.db.lock().await;
+        for peer in peers {
             db_lock
                 .update(
-                    PersistedPeer::new(peer, self.default_port, ServiceFlags::NONE, false, false),
-                    true,
+                    PersistedPeer::new(
+                        from_ip(peer),
+                        self.default_port,
+                        ServiceFlags::NONE,
+                        now(),
+                        false,
+                        None,
+                    ),
+                    false,
                 )
                 .await
                 .map_err(PeerManagerError::Database)?;
@@ -75,41 +131,45 @@ impl PeerManager {
 
     pub(crate) async fn add_new_peer(
         &mut self,
-        addr: IpAddr,
+        addr: AddrV2,
         port: Option<u16>,
         services: Option<ServiceFlags>,
+        last_seen: Option<u64>,
     ) -> Result<(), PeerManagerError> {
-        self.internal_db_update(addr, port, services, false, false)
+        let last_seen = last_seen.unwrap_or_else(now);
+        self.internal_db_update(addr, port, services, last_seen, false, None)
             .await
     }
 
     pub(crate) async fn tried_peer(
         &mut self,
-        addr: IpAddr,
+        addr: AddrV2,
         port: Option<u16>,
         services: Option<ServiceFlags>,
     ) -> Result<(), PeerManagerError> {
-        self.internal_db_update(addr, port, services, true, false)
+        self.internal_db_update(addr, port, services, now(), true, None)
             .await
     }
 
     pub(crate) async fn ban_peer(
         &mut self,
-        addr: IpAddr,
+        addr: AddrV2,
         port: Option<u16>,
         services: Option<ServiceFlags>,
     ) -> Result<(), PeerManagerError> {
-        self.internal_db_update(addr, port, services, true, true)
+        let now = now();
+        self.internal_db_update(addr, port, services, now, true, Some(now + BAN_DURATION))
             .await
     }
 
     async fn internal_db_update(
         &mut self,
-        addr: IpAddr,
+        addr: AddrV2,
         port: Option<u16>,
         services: Option<ServiceFlags>,
+        last_seen: u64,
         tried: bool,
-        ban: bool,
+        banned_until: Option<u64>,
     ) -> Result<(), PeerManagerError> {
         let mut db_lock = self.db.lock().await;
         db_lock
@@ -118,8 +178,9 @@ impl PeerManager {
                     addr,
                     port.unwrap_or(self.default_port),
                     services.unwrap_or(ServiceFlags::NONE),
+                    last_seen,
                     tried,
-                    ban,
+                    banned_until,
                 ),
                 tried,
             )
@@ -128,3 +189,37 @@ impl PeerManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::db::memory::peers::MemoryPeerStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_seeds_do_not_unban_peers() {
+        let mut peer_man = PeerManager::new(
+            MemoryPeerStore::new(),
+            &Network::Signet,
+            ConnectionMode::Direct,
+            None,
+        );
+        let banned = Ipv4Addr::new(1, 2, 3, 4);
+        let seed = Ipv4Addr::new(5, 6, 7, 8);
+        peer_man
+            .ban_peer(AddrV2::Ipv4(banned), None, None)
+            .await
+            .unwrap();
+        peer_man
+            .add_seed_peers(vec![IpAddr::V4(banned), IpAddr::V4(seed)])
+            .await
+            .unwrap();
+        assert_eq!(peer_man.peer_count().await.unwrap(), 1);
+        for _ in 0..10 {
+            let (addr, _, _) = peer_man.next_peer().await.unwrap();
+            assert_eq!(addr, AddrV2::Ipv4(seed));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bitcoin::{BlockHash, Network};
+use rusqlite::{params, Connection, Result};
+use tokio::sync::Mutex;
+
+use crate::db::error::DatabaseError;
+use crate::db::traits::FilterStore;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS filters (
+    height INTEGER PRIMARY KEY,
+    block_hash TEXT NOT NULL,
+    filter BLOB NOT NULL
+) STRICT";
+
+#[derive(Debug)]
+pub(crate) struct SqliteFilterDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteFilterDb {
+    pub fn new(network: Network, path: Option<PathBuf>) -> Result<Self, DatabaseError> {
+        let mut path = path.unwrap_or_else(|| PathBuf::from("."));
+        path.push("data");
+        path.push(network.to_string());
+        if !path.exists() {
+            fs::create_dir_all(&path).unwrap();
+        }
+        let conn =
+            Connection::open(path.join("filters.db")).map_err(|_| DatabaseError::LoadError)?;
+        conn.execute(SCHEMA, [])
+            .map_err(|_| DatabaseError::LoadError)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+}
+
+#[async_trait]
+impl FilterStore for SqliteFilterDb {
+    async fn put(
+        &mut self,
+        height: u32,
+        block_hash: BlockHash,
+        filter: Vec<u8>,
+    ) -> Result<(), DatabaseError> {
+        let lock = self.conn.lock().await;
+        let stmt =
+            "INSERT OR REPLACE INTO filters (height, block_hash, filter) VALUES (?1, ?2, ?3)";
+        lock.execute(stmt, params![height, block_hash.to_string(), filter])
+            .map_err(|_| DatabaseError::WriteError)?;
+        Ok(())
+    }
+
+    async fn load_range(
+        &mut self,
+        start_height: u32,
+        end_height: u32,
+    ) -> Result<BTreeMap<u32, (BlockHash, Vec<u8>)>, DatabaseError> {
+        let mut filters = BTreeMap::new();
+        let stmt = "SELECT * FROM filters WHERE height >= ?1 AND height <= ?2 ORDER BY height";
+        let lock = self.conn.lock().await;
+        let mut query = lock.prepare(stmt).map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = query
+            .query(params![start_height, end_height])
+            .map_err(|_| DatabaseError::LoadError)?;
+        while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
+            let height: u32 = row.get(0).map_err(|_| DatabaseError::LoadError)?;
+            let block_hash: String = row.get(1).map_err(|_| DatabaseError::LoadError)?;
+            let filter: Vec<u8> = row.get(2).map_err(|_| DatabaseError::LoadError)?;
+            let block_hash =
+                BlockHash::from_str(&block_hash).map_err(|_| DatabaseError::LoadError)?;
+            filters.insert(height, (block_hash, filter));
+        }
+        Ok(filters)
+    }
+
+    async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError> {
+        let lock = self.conn.lock().await;
+        lock.execute("DELETE FROM filters WHERE height > ?1", params![height])
+            .map_err(|_| DatabaseError::WriteError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_puts_and_truncates_filters() {
+        let dir = std::env::temp_dir().join(format!("kyoto-filter-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = SqliteFilterDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        let filters: BTreeMap<u32, (BlockHash, Vec<u8>)> = (1..=4)
+            .map(|height: u32| {
+                let block_hash = BlockHash::hash(&height.to_le_bytes());
+                (height, (block_hash, vec![height as u8; height as usize]))
+            })
+            .collect();
+        for (height, (block_hash, filter)) in &filters {
+            db.put(*height, *block_hash, filter.clone()).await.unwrap();
+        }
+        assert_eq!(db.load_range(1, 4).await.unwrap(), filters);
+        assert_eq!(db.load_range(5, 10).await.unwrap(), BTreeMap::new());
+        // A filter at an existing height is replaced
+        let replaced = (BlockHash::all_zeros(), vec![0xff]);
+        db.put(2, replaced.0, replaced.1.clone()).await.unwrap();
+        assert_eq!(
+            db.load_range(2, 2).await.unwrap(),
+            BTreeMap::from([(2, replaced.clone())])
+        );
+        db.truncate(2).await.unwrap();
+        drop(db);
+        let mut db = SqliteFilterDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        assert_eq!(
+            db.load_range(0, 10).await.unwrap(),
+            BTreeMap::from([(1, filters[&1].clone()), (2, replaced)])
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bitcoin::hashes::Hash;
+use bitcoin::{FilterHash, FilterHeader, Network};
+use rusqlite::{params, Connection, Result, Row, Transaction};
+use tokio::sync::Mutex;
+
+use crate::db::error::DatabaseError;
+use crate::db::traits::FilterHeaderStore;
+
+// Filter headers and filter hashes are stored as their 32 bytes
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS filter_headers (
+    height INTEGER PRIMARY KEY,
+    filter_header BLOB NOT NULL,
+    filter_hash BLOB NOT NULL
+) STRICT";
+
+// The filter headers of the original schema, which stored them as hex strings, are moved to this table while
+// they are migrated
+const LEGACY_TABLE: &str = "legacy_filter_headers";
+
+#[derive(Debug)]
+pub(crate) struct SqliteFilterHeaderDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteFilterHeaderDb {
+    pub fn new(network: Network, path: Option<PathBuf>) -> Result<Self, DatabaseError> {
+        let mut path = path.unwrap_or_else(|| PathBuf::from("."));
+        path.push("data");
+        path.push(network.to_string());
+        if !path.exists() {
+            fs::create_dir_all(&path).unwrap();
+        }
+        let mut conn = Connection::open(path.join("filter_headers.db"))
+            .map_err(|_| DatabaseError::LoadError)?;
+        Self::migrate(&mut conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Create the table, rewriting the hex strings of the original schema as bytes
+    fn migrate(conn: &mut Connection) -> Result<(), DatabaseError> {
+        let tx = conn.transaction().map_err(|_| DatabaseError::WriteError)?;
+        let legacy: bool = tx
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info('filter_headers') WHERE name = 'filter_header' AND type = 'TEXT')",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|_| DatabaseError::LoadError)?;
+        if legacy {
+            tx.execute(
+                &format!("ALTER TABLE filter_headers RENAME TO {LEGACY_TABLE}"),
+                [],
+            )
+            .map_err(|_| DatabaseError::WriteError)?;
+        }
+        tx.execute(SCHEMA, [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        if legacy {
+            Self::migrate_legacy(&tx)?;
+            tx.execute(&format!("DROP TABLE {LEGACY_TABLE}"), [])
+                .map_err(|_| DatabaseError::WriteError)?;
+        }
+        tx.commit().map_err(|_| DatabaseError::WriteError)
+    }
+
+    // Copy the filter headers of the original schema up to the first one that cannot be read. The rest are
+    // downloaded again.
+    fn migrate_legacy(tx: &Transaction) -> Result<(), DatabaseError> {
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT height, filter_header, filter_hash FROM {LEGACY_TABLE} ORDER BY height"
+            ))
+            .map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = stmt.query([]).map_err(|_| DatabaseError::LoadError)?;
+        while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
+            let (height, filter_header, filter_hash) = match legacy_filter_header_from_row(row) {
+                Some(filter_header) => filter_header,
+                None => break,
+            };
+            insert(tx, height, &filter_header, &filter_hash)?;
+        }
+        Ok(())
+    }
+}
+
+fn insert(
+    conn: &Connection,
+    height: u32,
+    filter_header: &FilterHeader,
+    filter_hash: &FilterHash,
+) -> Result<(), DatabaseError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO filter_headers (height, filter_header, filter_hash) VALUES (?1, ?2, ?3)",
+        params![
+            height,
+            filter_header.as_byte_array(),
+            filter_hash.as_byte_array()
+        ],
+    )
+    .map_err(|_| DatabaseError::WriteError)?;
+    Ok(())
+}
+
+fn filter_header_from_row(row: &Row) -> Result<(u32, FilterHeader, FilterHash), DatabaseError> {
+    let height: u32 = row.get(0).map_err(|_| DatabaseError::LoadError)?;
+    let filter_header: [u8; 32] = row.get(1).map_err(|_| DatabaseError::Corruption)?;
+    let filter_hash: [u8; 32] = row.get(2).map_err(|_| DatabaseError::Corruption)?;
+    Ok((
+        height,
+        FilterHeader::from_byte_array(filter_header),
+        FilterHash::from_byte_array(filter_hash),
+    ))
+}
+
+fn legacy_filter_header_from_row(row: &Row) -> Option<(u32, FilterHeader, FilterHash)> {
+    let height: u32 = row.get(0).ok()?;
+    let filter_header: String = row.get(1).ok()?;
+    let filter_hash: String = row.get(2).ok()?;
+    Some((
+        height,
+        FilterHeader::from_str(&filter_header).ok()?,
+        FilterHash::from_str(&filter_hash).ok()?,
+    ))
+}
+
+#[async_trait]
+impl FilterHeaderStore for SqliteFilterHeaderDb {
+    async fn load(
+        &mut self,
+        anchor_height: u32,
+    ) -> Result<BTreeMap<u32, (FilterHeader, FilterHash)>, DatabaseError> {
+        let mut filter_headers = BTreeMap::new();
+        let stmt = "SELECT height, filter_header, filter_hash FROM filter_headers WHERE height > ?1 ORDER BY height";
+        let lock = self.conn.lock().await;
+        let mut query = lock.prepare(stmt).map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = query
+            .query(params![anchor_height])
+            .map_err(|_| DatabaseError::LoadError)?;
+        while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
+            let (height, filter_header, filter_hash) = filter_header_from_row(row)?;
+            filter_headers.insert(height, (filter_header, filter_hash));
+        }
+        Ok(filter_headers)
+    }
+
+    async fn write<'a>(
+        &mut self,
+        filter_headers: &'a BTreeMap<u32, (FilterHeader, FilterHash)>,
+    ) -> Result<(), DatabaseError> {
+        let mut lock = self.conn.lock().await;
+        let tx = lock.transaction().map_err(|_| DatabaseError::WriteError)?;
+        for (height, (filter_header, filter_hash)) in filter_headers {
+            insert(&tx, *height, filter_header, filter_hash)?;
+        }
+        tx.commit().map_err(|_| DatabaseError::WriteError)?;
+        Ok(())
+    }
+
+    async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError> {
+        let lock = self.conn.lock().await;
+        lock.execute(
+            "DELETE FROM filter_headers WHERE height > ?1",
+            params![height],
+        )
+        .map_err(|_| DatabaseError::WriteError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_headers(
+        heights: std::ops::RangeInclusive<u32>,
+    ) -> BTreeMap<u32, (FilterHeader, FilterHash)> {
+        let mut prev_header = FilterHeader::all_zeros();
+        let mut filter_headers = BTreeMap::new();
+        for height in heights {
+            let filter_hash = FilterHash::hash(&height.to_le_bytes());
+            let filter_header = filter_hash.filter_header(&prev_header);
+            filter_headers.insert(height, (filter_header, filter_hash));
+            prev_header = filter_header;
+        }
+        filter_headers
+    }
+
+    #[tokio::test]
+    async fn test_writes_and_truncates_filter_headers() {
+        let dir =
+            std::env::temp_dir().join(format!("kyoto-filter-header-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = SqliteFilterHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        let written = filter_headers(1..=4);
+        db.write(&written).await.unwrap();
+        let mut expected = written.clone();
+        expected.remove(&1);
+        assert_eq!(db.load(1).await.unwrap(), expected);
+        // Filter headers at existing heights are replaced
+        let replaced = BTreeMap::from([(4, (FilterHeader::all_zeros(), FilterHash::all_zeros()))]);
+        db.write(&replaced).await.unwrap();
+        assert_eq!(db.load(3).await.unwrap(), replaced);
+        db.truncate(2).await.unwrap();
+        drop(db);
+        let mut db = SqliteFilterHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        let mut expected = written;
+        expected.split_off(&3);
+        assert_eq!(db.load(0).await.unwrap(), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_migrates_hex_filter_headers() {
+        let dir = std::env::temp_dir().join(format!(
+            "kyoto-legacy-filter-header-db-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let db_dir = dir.join("data").join(Network::Regtest.to_string());
+        fs::create_dir_all(&db_dir).unwrap();
+        let legacy = Connection::open(db_dir.join("filter_headers.db")).unwrap();
+        legacy
+            .execute(
+                "CREATE TABLE filter_headers (height INTEGER PRIMARY KEY, filter_header TEXT NOT NULL, filter_hash TEXT NOT NULL) STRICT",
+                [],
+            )
+            .unwrap();
+        let written = filter_headers(1..=3);
+        for (height, (filter_header, filter_hash)) in &written {
+            // The last filter header is not valid hex
+            let filter_header = if height.eq(&3) {
+                "not hex".to_string()
+            } else {
+                filter_header.to_string()
+            };
+            legacy
+                .execute(
+                    "INSERT INTO filter_headers VALUES (?1, ?2, ?3)",
+                    params![height, filter_header, filter_hash.to_string()],
+                )
+                .unwrap();
+        }
+        drop(legacy);
+        let mut db = SqliteFilterHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        let mut expected = written;
+        expected.remove(&3);
+        assert_eq!(db.load(0).await.unwrap(), expected);
+        // The migrated table accepts new filter headers
+        db.write(&filter_headers(3..=3)).await.unwrap();
+        assert_eq!(db.load(2).await.unwrap().len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
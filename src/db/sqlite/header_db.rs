@@ -6,24 +6,36 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use bitcoin::block::{Header, Version};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::Hash;
 use bitcoin::{BlockHash, CompactTarget, Network, TxMerkleNode};
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result, Row, Transaction};
 use tokio::sync::Mutex;
 
+use crate::chain::checkpoints::HeaderCheckpoint;
 use crate::db::error::DatabaseError;
 use crate::db::traits::HeaderStore;
 
+// The version of the schema written by this version of the crate. The original schema, which stored the fields
+// of a header as text, had no version table and is version zero.
+const SCHEMA_VERSION: u32 = 1;
+
+const VERSION_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER NOT NULL
+) STRICT";
+
+// Headers are stored in their 80 byte consensus encoding, along with their block hash so they may be found by hash
 const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS headers (
     height INTEGER PRIMARY KEY,
-    block_hash TEXT NOT NULL,
-    version INTEGER NOT NULL,
-    prev_hash TEXT NOT NULL,
-    merkle_root TEXT NOT NULL,
-    time INTEGER NOT NULL,
-    bits INTEGER NOT NULL,
-    nonce INTEGER NOT NULL
+    block_hash BLOB NOT NULL,
+    header BLOB NOT NULL
 ) STRICT";
 
+const HASH_INDEX: &str = "CREATE INDEX IF NOT EXISTS headers_by_hash ON headers (block_hash)";
+
+// The headers of the original schema are moved to this table while they are migrated
+const LEGACY_TABLE: &str = "legacy_headers";
+
 #[derive(Debug)]
 pub(crate) struct SqliteHeaderDb {
     network: Network,
@@ -38,15 +50,112 @@ impl SqliteHeaderDb {
         if !path.exists() {
             fs::create_dir_all(&path).unwrap();
         }
-        let conn =
+        let mut conn =
             Connection::open(path.join("headers.db")).map_err(|_| DatabaseError::LoadError)?;
-        conn.execute(SCHEMA, [])
-            .map_err(|_| DatabaseError::LoadError)?;
+        Self::migrate(&mut conn)?;
+        Self::repair(&mut conn)?;
         Ok(Self {
             network,
             conn: Arc::new(Mutex::new(conn)),
         })
     }
+
+    // Create the tables, moving the headers of the original schema to the current schema
+    fn migrate(conn: &mut Connection) -> Result<(), DatabaseError> {
+        let tx = conn.transaction().map_err(|_| DatabaseError::WriteError)?;
+        tx.execute(VERSION_SCHEMA, [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        let version: Option<u32> = tx
+            .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+            .optional()
+            .map_err(|_| DatabaseError::LoadError)?;
+        match version {
+            Some(version) if version.eq(&SCHEMA_VERSION) => return Ok(()),
+            Some(version) => return Err(DatabaseError::UnsupportedSchema(version)),
+            None => (),
+        }
+        let legacy: bool = tx
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info('headers') WHERE name = 'prev_hash')",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|_| DatabaseError::LoadError)?;
+        if legacy {
+            tx.execute(&format!("ALTER TABLE headers RENAME TO {LEGACY_TABLE}"), [])
+                .map_err(|_| DatabaseError::WriteError)?;
+        }
+        tx.execute(SCHEMA, [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        tx.execute(HASH_INDEX, [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        if legacy {
+            Self::migrate_legacy(&tx)?;
+            tx.execute(&format!("DROP TABLE {LEGACY_TABLE}"), [])
+                .map_err(|_| DatabaseError::WriteError)?;
+        }
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            [SCHEMA_VERSION],
+        )
+        .map_err(|_| DatabaseError::WriteError)?;
+        tx.commit().map_err(|_| DatabaseError::WriteError)
+    }
+
+    // Copy the headers of the original schema, up to the first header that cannot be read or does not link to the
+    // header before it
+    fn migrate_legacy(tx: &Transaction) -> Result<(), DatabaseError> {
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT height, block_hash, version, prev_hash, merkle_root, time, bits, nonce FROM {LEGACY_TABLE} ORDER BY height"
+            ))
+            .map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = stmt.query([]).map_err(|_| DatabaseError::LoadError)?;
+        let mut prev: Option<(u32, BlockHash)> = None;
+        while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
+            let (height, header) = match legacy_header_from_row(row) {
+                Some(header) => header,
+                None => break,
+            };
+            if !links(prev, height, &header) {
+                break;
+            }
+            insert(tx, height, &header)?;
+            prev = Some((height, header.block_hash()));
+        }
+        Ok(())
+    }
+
+    // Remove every header from the first header that cannot be read or does not link to the header before it, so
+    // the database holds a valid chain of headers
+    fn repair(conn: &mut Connection) -> Result<(), DatabaseError> {
+        let invalid = {
+            let mut stmt = conn
+                .prepare("SELECT height, block_hash, header FROM headers ORDER BY height")
+                .map_err(|_| DatabaseError::LoadError)?;
+            let mut rows = stmt.query([]).map_err(|_| DatabaseError::LoadError)?;
+            let mut prev: Option<(u32, BlockHash)> = None;
+            let mut invalid = None;
+            while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
+                let height: u32 = row.get(0).map_err(|_| DatabaseError::LoadError)?;
+                match header_from_row(row) {
+                    Ok(header) if links(prev, height, &header) => {
+                        prev = Some((height, header.block_hash()));
+                    }
+                    _ => {
+                        invalid = Some(height);
+                        break;
+                    }
+                }
+            }
+            invalid
+        };
+        if let Some(height) = invalid {
+            conn.execute("DELETE FROM headers WHERE height >= ?1", [height])
+                .map_err(|_| DatabaseError::WriteError)?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -54,49 +163,25 @@ impl HeaderStore for SqliteHeaderDb {
     // load all the known headers from storage
     async fn load(&mut self, anchor_height: u32) -> Result<BTreeMap<u32, Header>, DatabaseError> {
         let mut headers = BTreeMap::<u32, Header>::new();
-        let stmt = "SELECT * FROM headers ORDER BY height";
+        // The anchor height should not be included in the chain, as the anchor is non-inclusive
+        let stmt =
+            "SELECT height, block_hash, header FROM headers WHERE height > ?1 ORDER BY height";
         let write_lock = self.conn.lock().await;
         let mut query = write_lock
             .prepare(stmt)
             .map_err(|_| DatabaseError::LoadError)?;
-        let mut rows = query.query([]).map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = query
+            .query(params![anchor_height])
+            .map_err(|_| DatabaseError::LoadError)?;
+        let mut prev: Option<(u32, BlockHash)> = None;
         while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
             let height: u32 = row.get(0).map_err(|_| DatabaseError::LoadError)?;
-            // The anchor height should not be included in the chain, as the anchor is non-inclusive
-            if height.le(&anchor_height) {
-                continue;
-            }
-            let hash: String = row.get(1).map_err(|_| DatabaseError::LoadError)?;
-            let version: i32 = row.get(2).map_err(|_| DatabaseError::LoadError)?;
-            let prev_hash: String = row.get(3).map_err(|_| DatabaseError::LoadError)?;
-            let merkle_root: String = row.get(4).map_err(|_| DatabaseError::LoadError)?;
-            let time: u32 = row.get(5).map_err(|_| DatabaseError::LoadError)?;
-            let bits: u32 = row.get(6).map_err(|_| DatabaseError::LoadError)?;
-            let nonce: u32 = row.get(7).map_err(|_| DatabaseError::LoadError)?;
-
-            let next_header = Header {
-                version: Version::from_consensus(version),
-                prev_blockhash: BlockHash::from_str(&prev_hash).unwrap(),
-                merkle_root: TxMerkleNode::from_str(&merkle_root).unwrap(),
-                time,
-                bits: CompactTarget::from_consensus(bits),
-                nonce,
-            };
-
-            assert_eq!(
-                BlockHash::from_str(&hash).unwrap(),
-                next_header.block_hash(),
-                "db corruption. incorrect header hash."
-            );
-
-            if let Some(header) = headers.values().last() {
-                assert_eq!(
-                    header.block_hash(),
-                    next_header.prev_blockhash,
-                    "db corruption. headers do not link."
-                );
+            let header = header_from_row(row)?;
+            if !links(prev, height, &header) {
+                return Err(DatabaseError::Corruption);
             }
-            headers.insert(height, next_header);
+            prev = Some((height, header.block_hash()));
+            headers.insert(height, header);
         }
         Ok(headers)
     }
@@ -114,28 +199,7 @@ impl HeaderStore for SqliteHeaderDb {
             .map_err(|_| DatabaseError::WriteError)?;
         for (height, header) in header_chain {
             if height.ge(&(best_height.unwrap_or(0))) {
-                let hash: String = header.block_hash().to_string();
-                let version: i32 = header.version.to_consensus();
-                let prev_hash: String = header.prev_blockhash.as_raw_hash().to_string();
-                let merkle_root: String = header.merkle_root.to_string();
-                let time: u32 = header.time;
-                let bits: u32 = header.bits.to_consensus();
-                let nonce: u32 = header.nonce;
-                let stmt = "INSERT OR REPLACE INTO headers (height, block_hash, version, prev_hash, merkle_root, time, bits, nonce) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)";
-                tx.execute(
-                    stmt,
-                    params![
-                        height,
-                        hash,
-                        version,
-                        prev_hash,
-                        merkle_root,
-                        time,
-                        bits,
-                        nonce
-                    ],
-                )
-                .map_err(|_| DatabaseError::WriteError)?;
+                insert(&tx, *height, header)?;
             }
         }
         tx.commit().map_err(|_| DatabaseError::WriteError)?;
@@ -151,31 +215,11 @@ impl HeaderStore for SqliteHeaderDb {
         let tx = write_lock
             .transaction()
             .map_err(|_| DatabaseError::WriteError)?;
-        for (h, header) in header_chain {
-            if h.ge(&height) {
-                let hash: String = header.block_hash().to_string();
-                let version: i32 = header.version.to_consensus();
-                let prev_hash: String = header.prev_blockhash.as_raw_hash().to_string();
-                let merkle_root: String = header.merkle_root.to_string();
-                let time: u32 = header.time;
-                let bits: u32 = header.bits.to_consensus();
-                let nonce: u32 = header.nonce;
-                let stmt = "INSERT OR REPLACE INTO headers (height, block_hash, version, prev_hash, merkle_root, time, bits, nonce) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)";
-                tx.execute(
-                    stmt,
-                    params![
-                        height,
-                        hash,
-                        version,
-                        prev_hash,
-                        merkle_root,
-                        time,
-                        bits,
-                        nonce
-                    ],
-                )
-                .map_err(|_| DatabaseError::WriteError)?;
-            }
+        // Headers of the old chain past the fork no longer link to the new chain
+        tx.execute("DELETE FROM headers WHERE height >= ?1", params![height])
+            .map_err(|_| DatabaseError::WriteError)?;
+        for (h, header) in header_chain.range(height..) {
+            insert(&tx, *h, header)?;
         }
         tx.commit().map_err(|_| DatabaseError::WriteError)?;
         Ok(())
@@ -188,8 +232,263 @@ impl HeaderStore for SqliteHeaderDb {
         let write_lock = self.conn.lock().await;
         let stmt = "SELECT height FROM headers WHERE block_hash = ?1";
         let row: Option<u32> = write_lock
-            .query_row(stmt, params![block_hash.to_string()], |row| row.get(0))
+            .query_row(stmt, params![&block_hash.as_byte_array()[..]], |row| {
+                row.get(0)
+            })
+            .optional()
             .map_err(|_| DatabaseError::LoadError)?;
         Ok(row)
     }
+
+    async fn header_at(&mut self, height: u32) -> Result<Option<Header>, DatabaseError> {
+        let write_lock = self.conn.lock().await;
+        let stmt = "SELECT height, block_hash, header FROM headers WHERE height = ?1";
+        let mut query = write_lock
+            .prepare(stmt)
+            .map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = query
+            .query(params![height])
+            .map_err(|_| DatabaseError::LoadError)?;
+        match rows.next().map_err(|_| DatabaseError::LoadError)? {
+            Some(row) => Ok(Some(header_from_row(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn headers_in_range(
+        &mut self,
+        start_height: u32,
+        end_height: u32,
+    ) -> Result<BTreeMap<u32, Header>, DatabaseError> {
+        let mut headers = BTreeMap::new();
+        let write_lock = self.conn.lock().await;
+        let stmt = "SELECT height, block_hash, header FROM headers WHERE height BETWEEN ?1 AND ?2 ORDER BY height";
+        let mut query = write_lock
+            .prepare(stmt)
+            .map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = query
+            .query(params![start_height, end_height])
+            .map_err(|_| DatabaseError::LoadError)?;
+        while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
+            let height: u32 = row.get(0).map_err(|_| DatabaseError::LoadError)?;
+            headers.insert(height, header_from_row(row)?);
+        }
+        Ok(headers)
+    }
+
+    async fn hash_at(&mut self, height: u32) -> Result<Option<BlockHash>, DatabaseError> {
+        let write_lock = self.conn.lock().await;
+        let stmt = "SELECT block_hash FROM headers WHERE height = ?1";
+        let hash: Option<Vec<u8>> = write_lock
+            .query_row(stmt, params![height], |row| row.get(0))
+            .optional()
+            .map_err(|_| DatabaseError::LoadError)?;
+        hash.map(|hash| block_hash_from_bytes(&hash)).transpose()
+    }
+
+    async fn tip(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError> {
+        let write_lock = self.conn.lock().await;
+        let stmt = "SELECT height, block_hash FROM headers ORDER BY height DESC LIMIT 1";
+        let tip: Option<(u32, Vec<u8>)> = write_lock
+            .query_row(stmt, [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .map_err(|_| DatabaseError::LoadError)?;
+        tip.map(|(height, hash)| {
+            block_hash_from_bytes(&hash).map(|hash| HeaderCheckpoint::new(height, hash))
+        })
+        .transpose()
+    }
+
+    async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError> {
+        let write_lock = self.conn.lock().await;
+        write_lock
+            .execute("DELETE FROM headers WHERE height > ?1", params![height])
+            .map_err(|_| DatabaseError::WriteError)?;
+        Ok(())
+    }
+}
+
+fn insert(tx: &Transaction, height: u32, header: &Header) -> Result<(), DatabaseError> {
+    let stmt = "INSERT OR REPLACE INTO headers (height, block_hash, header) VALUES (?1, ?2, ?3)";
+    tx.execute(
+        stmt,
+        params![
+            height,
+            &header.block_hash().as_byte_array()[..],
+            serialize(header)
+        ],
+    )
+    .map_err(|_| DatabaseError::WriteError)?;
+    Ok(())
+}
+
+fn block_hash_from_bytes(bytes: &[u8]) -> Result<BlockHash, DatabaseError> {
+    let bytes: [u8; 32] = bytes.try_into().map_err(|_| DatabaseError::Corruption)?;
+    Ok(BlockHash::from_byte_array(bytes))
+}
+
+// Read the header from a row of height, block hash and header. The header must hash to the block hash.
+fn header_from_row(row: &Row) -> Result<Header, DatabaseError> {
+    let hash: Vec<u8> = row.get(1).map_err(|_| DatabaseError::LoadError)?;
+    let header: Vec<u8> = row.get(2).map_err(|_| DatabaseError::LoadError)?;
+    let header: Header = deserialize(&header).map_err(|_| DatabaseError::Corruption)?;
+    if header.block_hash().ne(&block_hash_from_bytes(&hash)?) {
+        return Err(DatabaseError::Corruption);
+    }
+    Ok(header)
+}
+
+// Read the height and header from a row of the original schema, if the header hashes to its block hash
+fn legacy_header_from_row(row: &Row) -> Option<(u32, Header)> {
+    let height: u32 = row.get(0).ok()?;
+    let hash: String = row.get(1).ok()?;
+    let version: i32 = row.get(2).ok()?;
+    let prev_hash: String = row.get(3).ok()?;
+    let merkle_root: String = row.get(4).ok()?;
+    let time: u32 = row.get(5).ok()?;
+    let bits: u32 = row.get(6).ok()?;
+    let nonce: u32 = row.get(7).ok()?;
+    let header = Header {
+        version: Version::from_consensus(version),
+        prev_blockhash: BlockHash::from_str(&prev_hash).ok()?,
+        merkle_root: TxMerkleNode::from_str(&merkle_root).ok()?,
+        time,
+        bits: CompactTarget::from_consensus(bits),
+        nonce,
+    };
+    if header.block_hash().ne(&BlockHash::from_str(&hash).ok()?) {
+        return None;
+    }
+    Some((height, header))
+}
+
+// A header must be at the height after the header before it, and commit to the hash of that header
+fn links(prev: Option<(u32, BlockHash)>, height: u32, header: &Header) -> bool {
+    prev.map_or(true, |(prev_height, prev_hash)| {
+        height.eq(&(prev_height + 1)) && header.prev_blockhash.eq(&prev_hash)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::consensus::deserialize;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queries_headers() {
+        let dir = std::env::temp_dir().join(format!("kyoto-header-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020299e41732deb76d869fcdb5f72518d3784e99482f572afb73068d52134f1f75e1f20f5da8d18661d0f13aa3db8fff0f53598f7d61f56988a6d66573394b2c6ffc5805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("00000020b96feaa82716f11befeb608724acee4743e0920639a70f35f1637a88b8b6ea3471f1dbedc283ce6a43a87ed3c8e6326dae8d3dbacce1b2daba08e508054ffdb697815e66ffff7f2001000000").unwrap()).unwrap();
+        let mut db = SqliteHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        assert_eq!(db.tip().await.unwrap(), None);
+        let chain = BTreeMap::from([(1, block_1), (2, block_2), (3, block_3)]);
+        db.write(&chain).await.unwrap();
+        assert_eq!(db.header_at(2).await.unwrap(), Some(block_2));
+        assert_eq!(db.header_at(4).await.unwrap(), None);
+        assert_eq!(db.hash_at(3).await.unwrap(), Some(block_3.block_hash()));
+        assert_eq!(db.hash_at(4).await.unwrap(), None);
+        assert_eq!(
+            db.headers_in_range(2, 10).await.unwrap(),
+            BTreeMap::from([(2, block_2), (3, block_3)])
+        );
+        assert_eq!(
+            db.tip().await.unwrap(),
+            Some(HeaderCheckpoint::new(3, block_3.block_hash()))
+        );
+        db.truncate(1).await.unwrap();
+        assert_eq!(db.header_at(2).await.unwrap(), None);
+        assert_eq!(
+            db.tip().await.unwrap(),
+            Some(HeaderCheckpoint::new(1, block_1.block_hash()))
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_migrates_legacy_headers() {
+        let dir =
+            std::env::temp_dir().join(format!("kyoto-legacy-header-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let db_dir = dir.join("data").join(Network::Regtest.to_string());
+        fs::create_dir_all(&db_dir).unwrap();
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020299e41732deb76d869fcdb5f72518d3784e99482f572afb73068d52134f1f75e1f20f5da8d18661d0f13aa3db8fff0f53598f7d61f56988a6d66573394b2c6ffc5805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("00000020b96feaa82716f11befeb608724acee4743e0920639a70f35f1637a88b8b6ea3471f1dbedc283ce6a43a87ed3c8e6326dae8d3dbacce1b2daba08e508054ffdb697815e66ffff7f2001000000").unwrap()).unwrap();
+        let legacy = Connection::open(db_dir.join("headers.db")).unwrap();
+        legacy
+            .execute(
+                "CREATE TABLE headers (height INTEGER PRIMARY KEY, block_hash TEXT NOT NULL, version INTEGER NOT NULL, prev_hash TEXT NOT NULL, merkle_root TEXT NOT NULL, time INTEGER NOT NULL, bits INTEGER NOT NULL, nonce INTEGER NOT NULL) STRICT",
+                [],
+            )
+            .unwrap();
+        for (height, header) in [(1, block_1), (2, block_2), (3, block_3)] {
+            // The last header was corrupted, so it no longer hashes to its block hash
+            let nonce = if height == 3 { 0 } else { header.nonce };
+            legacy
+                .execute(
+                    "INSERT INTO headers VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        height,
+                        header.block_hash().to_string(),
+                        header.version.to_consensus(),
+                        header.prev_blockhash.to_string(),
+                        header.merkle_root.to_string(),
+                        header.time,
+                        header.bits.to_consensus(),
+                        nonce
+                    ],
+                )
+                .unwrap();
+        }
+        drop(legacy);
+        let mut db = SqliteHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        // The headers are migrated up to the corrupted header
+        assert_eq!(
+            db.load(0).await.unwrap(),
+            BTreeMap::from([(1, block_1), (2, block_2)])
+        );
+        assert_eq!(db.height_of(&block_2.block_hash()).await.unwrap(), Some(2));
+        assert_eq!(db.height_of(&block_3.block_hash()).await.unwrap(), None);
+        drop(db);
+        // Opening the database again does not migrate again
+        let mut db = SqliteHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        assert_eq!(db.load(0).await.unwrap().len(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_reports_and_repairs_corruption() {
+        let dir =
+            std::env::temp_dir().join(format!("kyoto-corrupt-header-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let block_1: Header = deserialize(&hex::decode("0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f047eb4d0fe76345e307d0e020a079cedfa37101ee7ac84575cf829a611b0f84bc4805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_2: Header = deserialize(&hex::decode("00000020299e41732deb76d869fcdb5f72518d3784e99482f572afb73068d52134f1f75e1f20f5da8d18661d0f13aa3db8fff0f53598f7d61f56988a6d66573394b2c6ffc5805e66ffff7f2001000000").unwrap()).unwrap();
+        let block_3: Header = deserialize(&hex::decode("00000020b96feaa82716f11befeb608724acee4743e0920639a70f35f1637a88b8b6ea3471f1dbedc283ce6a43a87ed3c8e6326dae8d3dbacce1b2daba08e508054ffdb697815e66ffff7f2001000000").unwrap()).unwrap();
+        let mut db = SqliteHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        db.write(&BTreeMap::from([(1, block_1), (2, block_2), (3, block_3)]))
+            .await
+            .unwrap();
+        db.conn
+            .lock()
+            .await
+            .execute("UPDATE headers SET header = x'00' WHERE height = 2", [])
+            .unwrap();
+        assert!(matches!(db.load(0).await, Err(DatabaseError::Corruption)));
+        assert!(matches!(
+            db.header_at(2).await,
+            Err(DatabaseError::Corruption)
+        ));
+        drop(db);
+        // The headers from the corrupted header on are removed when the database is opened
+        let mut db = SqliteHeaderDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        assert_eq!(db.load(0).await.unwrap(), BTreeMap::from([(1, block_1)]));
+        assert_eq!(
+            db.tip().await.unwrap(),
+            Some(HeaderCheckpoint::new(1, block_1.block_hash()))
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -1,2 +1,5 @@
+pub(crate) mod filter_db;
+pub(crate) mod filter_header_db;
 pub(crate) mod header_db;
 pub(crate) mod peer_db;
+pub(crate) mod progress_db;
@@ -4,23 +4,34 @@ use bitcoin::Network;
 use rusqlite::params;
 use rusqlite::{Connection, Result};
 use std::fs;
-use std::net::IpAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use crate::db::error::DatabaseError;
+use crate::db::now;
 use crate::db::traits::PeerStore;
 use crate::db::PersistedPeer;
+use crate::peers::addrv2::{from_bytes, from_host, to_bytes};
+use crate::PeerNetwork;
 
-const PEER_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS peers (
-    ip_addr TEXT PRIMARY KEY,
+// Addresses are stored as the BIP-155 network ID and the address bytes, so every network may be persisted
+const PEER_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS peer_addrs (
+    network INTEGER NOT NULL,
+    addr BLOB NOT NULL,
     port INTEGER NOT NULL,
     service_flags INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
     tried BOOLEAN NOT NULL,
-    banned BOOLEAN NOT NULL
+    banned_until INTEGER,
+    PRIMARY KEY (network, addr)
 )";
 
+// Peers were previously stored by their IP address as text
+const LEGACY_TABLE: &str = "peers";
+// Bans were previously permanent, so they are converted to a ban of this many seconds from the migration
+const LEGACY_BAN_DURATION: u64 = 60 * 60 * 24;
+
 #[derive(Debug)]
 pub(crate) struct SqlitePeerDb {
     conn: Arc<Mutex<Connection>>,
@@ -34,56 +45,151 @@ impl SqlitePeerDb {
         if !path.exists() {
             fs::create_dir_all(&path).unwrap();
         }
-        let conn =
+        let mut conn =
             Connection::open(path.join("peers.db")).map_err(|_| DatabaseError::WriteError)?;
         conn.execute(PEER_SCHEMA, [])
             .map_err(|_| DatabaseError::WriteError)?;
+        Self::migrate_bans(&mut conn)?;
+        Self::migrate_legacy(&mut conn)?;
         Ok(Self {
             conn: Arc::new(Mutex::new(conn)),
         })
     }
+
+    // Replace the permanent ban flag with the time the ban expires
+    fn migrate_bans(conn: &mut Connection) -> Result<(), DatabaseError> {
+        let has_flag: bool = conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info('peer_addrs') WHERE name = 'banned')",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|_| DatabaseError::LoadError)?;
+        if !has_flag {
+            return Ok(());
+        }
+        let tx = conn.transaction().map_err(|_| DatabaseError::WriteError)?;
+        tx.execute("ALTER TABLE peer_addrs ADD COLUMN banned_until INTEGER", [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        tx.execute(
+            "UPDATE peer_addrs SET banned_until = ?1 WHERE banned = true",
+            [now() + LEGACY_BAN_DURATION],
+        )
+        .map_err(|_| DatabaseError::WriteError)?;
+        tx.execute("ALTER TABLE peer_addrs DROP COLUMN banned", [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        tx.commit().map_err(|_| DatabaseError::WriteError)
+    }
+
+    // Move the peers from the legacy table, which has no record of when a peer was last seen
+    fn migrate_legacy(conn: &mut Connection) -> Result<(), DatabaseError> {
+        let exists: bool = conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+                [LEGACY_TABLE],
+                |row| row.get(0),
+            )
+            .map_err(|_| DatabaseError::LoadError)?;
+        if !exists {
+            return Ok(());
+        }
+        let tx = conn.transaction().map_err(|_| DatabaseError::WriteError)?;
+        {
+            let mut stmt = tx
+                .prepare("SELECT ip_addr, port, service_flags, tried, banned FROM peers")
+                .map_err(|_| DatabaseError::LoadError)?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, u16>(1)?,
+                        row.get::<_, u64>(2)?,
+                        row.get::<_, bool>(3)?,
+                        row.get::<_, bool>(4)?,
+                    ))
+                })
+                .map_err(|_| DatabaseError::LoadError)?;
+            for row in rows {
+                let (host, port, service_flags, tried, banned) =
+                    row.map_err(|_| DatabaseError::LoadError)?;
+                // Anything we cannot parse was never connected to
+                let addr = match from_host(&host) {
+                    Some(addr) => addr,
+                    None => continue,
+                };
+                let (network, bytes) = to_bytes(&addr);
+                let banned_until = if banned {
+                    Some(now() + LEGACY_BAN_DURATION)
+                } else {
+                    None
+                };
+                tx.execute(
+                    "INSERT OR IGNORE INTO peer_addrs (network, addr, port, service_flags, last_seen, tried, banned_until) VALUES (?1, ?2, ?3, ?4, 0, ?5, ?6)",
+                    params![network, bytes, port, service_flags, tried, banned_until],
+                )
+                .map_err(|_| DatabaseError::WriteError)?;
+            }
+        }
+        tx.execute("DROP TABLE peers", [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        tx.commit().map_err(|_| DatabaseError::WriteError)
+    }
 }
 
 #[async_trait]
 impl PeerStore for SqlitePeerDb {
     async fn update(&mut self, peer: PersistedPeer, replace: bool) -> Result<(), DatabaseError> {
+        let (network, bytes) = to_bytes(&peer.addr);
         let lock = self.conn.lock().await;
         let stmt = if !replace {
-            "INSERT OR IGNORE INTO peers (ip_addr, port, service_flags, tried, banned) VALUES (?1, ?2, ?3, ?4, ?5)"
+            // Hearing about a peer again only tells us it was online more recently
+            "INSERT INTO peer_addrs (network, addr, port, service_flags, last_seen, tried, banned_until) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (network, addr) DO UPDATE SET last_seen = MAX(last_seen, excluded.last_seen)"
         } else {
-            "INSERT OR REPLACE INTO peers (ip_addr, port, service_flags, tried, banned) VALUES (?1, ?2, ?3, ?4, ?5)"
+            "INSERT OR REPLACE INTO peer_addrs (network, addr, port, service_flags, last_seen, tried, banned_until) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
         };
         lock.execute(
             stmt,
             params![
-                peer.addr.to_string(),
+                network,
+                bytes,
                 peer.port,
                 peer.services.to_u64(),
+                peer.last_seen,
                 peer.tried,
-                peer.banned,
+                peer.banned_until,
             ],
         )
         .map_err(|_| DatabaseError::WriteError)?;
         Ok(())
     }
 
-    async fn random(&mut self) -> Result<PersistedPeer, DatabaseError> {
+    async fn random(&mut self, networks: &[PeerNetwork]) -> Result<PersistedPeer, DatabaseError> {
+        // The IDs are integers we define, so they are safe to write into the query
+        let ids: Vec<String> = networks
+            .iter()
+            .map(|network| network.bip155_id().to_string())
+            .collect();
+        let query = format!(
+            "SELECT network, addr, port, service_flags, last_seen, tried FROM peer_addrs WHERE (banned_until IS NULL OR banned_until <= ?1) AND network IN ({}) ORDER BY RANDOM() LIMIT 1",
+            ids.join(", ")
+        );
         let lock = self.conn.lock().await;
-        let mut stmt = lock
-            .prepare("SELECT * FROM peers WHERE banned = false ORDER BY RANDOM() LIMIT 1")
-            .map_err(|_| DatabaseError::LoadError)?;
-        let mut rows = stmt.query([]).map_err(|_| DatabaseError::LoadError)?;
+        let mut stmt = lock.prepare(&query).map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = stmt.query([now()]).map_err(|_| DatabaseError::LoadError)?;
         if let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
-            let ip_addr: String = row.get(0).map_err(|_| DatabaseError::LoadError)?;
-            let port: u16 = row.get(1).map_err(|_| DatabaseError::LoadError)?;
-            let service_flags: u64 = row.get(2).map_err(|_| DatabaseError::LoadError)?;
-            let tried: bool = row.get(3).map_err(|_| DatabaseError::LoadError)?;
-            let banned: bool = row.get(4).map_err(|_| DatabaseError::LoadError)?;
-            let ip = ip_addr
-                .parse::<IpAddr>()
-                .map_err(|_| DatabaseError::LoadError)?;
+            let network: u8 = row.get(0).map_err(|_| DatabaseError::LoadError)?;
+            let bytes: Vec<u8> = row.get(1).map_err(|_| DatabaseError::LoadError)?;
+            let port: u16 = row.get(2).map_err(|_| DatabaseError::LoadError)?;
+            let service_flags: u64 = row.get(3).map_err(|_| DatabaseError::LoadError)?;
+            let last_seen: u64 = row.get(4).map_err(|_| DatabaseError::LoadError)?;
+            let tried: bool = row.get(5).map_err(|_| DatabaseError::LoadError)?;
+            let addr = from_bytes(network, &bytes).ok_or(DatabaseError::LoadError)?;
             let services: ServiceFlags = ServiceFlags::from(service_flags);
-            return Ok(PersistedPeer::new(ip, port, services, tried, banned));
+            // Any ban of a selected peer has expired
+            return Ok(PersistedPeer::new(
+                addr, port, services, last_seen, tried, None,
+            ));
         } else {
             return Err(DatabaseError::LoadError);
         }
@@ -92,11 +198,116 @@ impl PeerStore for SqlitePeerDb {
     async fn num_unbanned(&mut self) -> Result<u32, DatabaseError> {
         let lock = self.conn.lock().await;
         let mut stmt = lock
-            .prepare("SELECT COUNT(*) FROM peers WHERE banned = false")
+            .prepare(
+                "SELECT COUNT(*) FROM peer_addrs WHERE banned_until IS NULL OR banned_until <= ?1",
+            )
             .map_err(|_| DatabaseError::LoadError)?;
         let count: u32 = stmt
-            .query_row([], |row| row.get(0))
+            .query_row([now()], |row| row.get(0))
             .map_err(|_| DatabaseError::LoadError)?;
         Ok(count)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcoin::p2p::address::AddrV2;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_migrates_and_selects_networks() {
+        let dir = std::env::temp_dir().join(format!("kyoto-peer-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let db_dir = dir.join("data").join(Network::Signet.to_string());
+        fs::create_dir_all(&db_dir).unwrap();
+        let legacy = Connection::open(db_dir.join("peers.db")).unwrap();
+        legacy
+            .execute(
+                "CREATE TABLE peers (ip_addr TEXT PRIMARY KEY, port INTEGER NOT NULL, service_flags INTEGER NOT NULL, tried BOOLEAN NOT NULL, banned BOOLEAN NOT NULL)",
+                [],
+            )
+            .unwrap();
+        legacy
+            .execute(
+                "INSERT INTO peers VALUES ('95.217.198.121', 38333, 0, true, false)",
+                [],
+            )
+            .unwrap();
+        drop(legacy);
+        let mut db = SqlitePeerDb::new(Network::Signet, Some(dir.clone())).unwrap();
+        let migrated = db.random(&[PeerNetwork::Ipv4]).await.unwrap();
+        assert_eq!(
+            migrated.addr,
+            AddrV2::Ipv4(Ipv4Addr::new(95, 217, 198, 121))
+        );
+        assert!(migrated.tried);
+        let i2p = AddrV2::I2p([5; 32]);
+        db.update(
+            PersistedPeer::new(i2p.clone(), 0, ServiceFlags::NONE, 100, false, None),
+            false,
+        )
+        .await
+        .unwrap();
+        db.update(
+            PersistedPeer::new(i2p.clone(), 0, ServiceFlags::NONE, 50, false, None),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.num_unbanned().await.unwrap(), 2);
+        let selected = db.random(&[PeerNetwork::I2p]).await.unwrap();
+        assert_eq!(selected.addr, i2p);
+        assert_eq!(selected.last_seen, 100);
+        assert!(db.random(&[PeerNetwork::TorV3]).await.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrates_and_expires_bans() {
+        let dir = std::env::temp_dir().join(format!("kyoto-peer-bans-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let db_dir = dir.join("data").join(Network::Signet.to_string());
+        fs::create_dir_all(&db_dir).unwrap();
+        let legacy = Connection::open(db_dir.join("peers.db")).unwrap();
+        legacy
+            .execute(
+                "CREATE TABLE peer_addrs (network INTEGER NOT NULL, addr BLOB NOT NULL, port INTEGER NOT NULL, service_flags INTEGER NOT NULL, last_seen INTEGER NOT NULL, tried BOOLEAN NOT NULL, banned BOOLEAN NOT NULL, PRIMARY KEY (network, addr))",
+                [],
+            )
+            .unwrap();
+        legacy
+            .execute(
+                "INSERT INTO peer_addrs VALUES (1, x'01010101', 8333, 0, 0, true, true)",
+                [],
+            )
+            .unwrap();
+        drop(legacy);
+        let mut db = SqlitePeerDb::new(Network::Signet, Some(dir.clone())).unwrap();
+        // The permanent ban became a ban that has not expired yet
+        assert_eq!(db.num_unbanned().await.unwrap(), 0);
+        assert!(db.random(&PeerNetwork::ALL).await.is_err());
+        let addr = AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
+        // Gossip does not lift a ban
+        db.update(
+            PersistedPeer::new(addr.clone(), 8333, ServiceFlags::NONE, 10, false, None),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.num_unbanned().await.unwrap(), 0);
+        db.update(
+            PersistedPeer::new(addr.clone(), 8333, ServiceFlags::NONE, 10, true, Some(1)),
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.num_unbanned().await.unwrap(), 1);
+        let unbanned = db.random(&PeerNetwork::ALL).await.unwrap();
+        assert_eq!(unbanned.addr, addr);
+        assert!(!unbanned.is_banned(now()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bitcoin::{BlockHash, Network, ScriptBuf};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use tokio::sync::Mutex;
+
+use crate::chain::checkpoints::HeaderCheckpoint;
+use crate::db::error::DatabaseError;
+use crate::db::traits::ProgressStore;
+
+// There is only ever one checkpoint, so it is always written to the same row
+const CHECKPOINT_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS checkpoint (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    height INTEGER NOT NULL,
+    block_hash TEXT NOT NULL
+) STRICT";
+
+const SCRIPT_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS scripts (
+    script BLOB PRIMARY KEY
+) STRICT";
+
+#[derive(Debug)]
+pub(crate) struct SqliteProgressDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteProgressDb {
+    pub fn new(network: Network, path: Option<PathBuf>) -> Result<Self, DatabaseError> {
+        let mut path = path.unwrap_or_else(|| PathBuf::from("."));
+        path.push("data");
+        path.push(network.to_string());
+        if !path.exists() {
+            fs::create_dir_all(&path).unwrap();
+        }
+        let conn =
+            Connection::open(path.join("progress.db")).map_err(|_| DatabaseError::LoadError)?;
+        conn.execute(CHECKPOINT_SCHEMA, [])
+            .map_err(|_| DatabaseError::LoadError)?;
+        conn.execute(SCRIPT_SCHEMA, [])
+            .map_err(|_| DatabaseError::LoadError)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+}
+
+#[async_trait]
+impl ProgressStore for SqliteProgressDb {
+    async fn load_checkpoint(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError> {
+        let lock = self.conn.lock().await;
+        let stmt = "SELECT height, block_hash FROM checkpoint WHERE id = 0";
+        let row: Option<(u32, String)> = lock
+            .query_row(stmt, [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .map_err(|_| DatabaseError::LoadError)?;
+        match row {
+            Some((height, block_hash)) => {
+                let hash =
+                    BlockHash::from_str(&block_hash).map_err(|_| DatabaseError::LoadError)?;
+                Ok(Some(HeaderCheckpoint::new(height, hash)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn load_scripts(&mut self) -> Result<HashSet<ScriptBuf>, DatabaseError> {
+        let mut scripts = HashSet::new();
+        let lock = self.conn.lock().await;
+        let mut query = lock
+            .prepare("SELECT script FROM scripts")
+            .map_err(|_| DatabaseError::LoadError)?;
+        let mut rows = query.query([]).map_err(|_| DatabaseError::LoadError)?;
+        while let Some(row) = rows.next().map_err(|_| DatabaseError::LoadError)? {
+            let script: Vec<u8> = row.get(0).map_err(|_| DatabaseError::LoadError)?;
+            scripts.insert(ScriptBuf::from_bytes(script));
+        }
+        Ok(scripts)
+    }
+
+    async fn write<'a>(
+        &mut self,
+        checkpoint: HeaderCheckpoint,
+        scripts: &'a HashSet<ScriptBuf>,
+    ) -> Result<(), DatabaseError> {
+        let mut lock = self.conn.lock().await;
+        // The checkpoint is only meaningful for the scripts it was scanned for, so both are replaced at once
+        let tx = lock.transaction().map_err(|_| DatabaseError::WriteError)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO checkpoint (id, height, block_hash) VALUES (0, ?1, ?2)",
+            params![checkpoint.height, checkpoint.hash.to_string()],
+        )
+        .map_err(|_| DatabaseError::WriteError)?;
+        tx.execute("DELETE FROM scripts", [])
+            .map_err(|_| DatabaseError::WriteError)?;
+        for script in scripts {
+            tx.execute(
+                "INSERT OR IGNORE INTO scripts (script) VALUES (?1)",
+                params![script.as_bytes()],
+            )
+            .map_err(|_| DatabaseError::WriteError)?;
+        }
+        tx.commit().map_err(|_| DatabaseError::WriteError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, WPubkeyHash};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replaces_progress() {
+        let dir = std::env::temp_dir().join(format!("kyoto-progress-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut db = SqliteProgressDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        assert_eq!(db.load_checkpoint().await.unwrap(), None);
+        assert!(db.load_scripts().await.unwrap().is_empty());
+        let script = |byte: u8| ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(&[byte]));
+        let first = HeaderCheckpoint::new(10, BlockHash::hash(&[10]));
+        db.write(first, &HashSet::from([script(1), script(2)]))
+            .await
+            .unwrap();
+        drop(db);
+        let mut db = SqliteProgressDb::new(Network::Regtest, Some(dir.clone())).unwrap();
+        assert_eq!(db.load_checkpoint().await.unwrap(), Some(first));
+        assert_eq!(
+            db.load_scripts().await.unwrap(),
+            HashSet::from([script(1), script(2)])
+        );
+        // Both the checkpoint and the scripts are replaced
+        let second = HeaderCheckpoint::new(20, BlockHash::hash(&[20]));
+        db.write(second, &HashSet::from([script(3)])).await.unwrap();
+        assert_eq!(db.load_checkpoint().await.unwrap(), Some(second));
+        assert_eq!(db.load_scripts().await.unwrap(), HashSet::from([script(3)]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
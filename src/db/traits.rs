@@ -1,7 +1,9 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 
 use async_trait::async_trait;
-use bitcoin::{block::Header, BlockHash};
+use bitcoin::{block::Header, BlockHash, FilterHash, FilterHeader, OutPoint, ScriptBuf};
+
+use crate::{chain::checkpoints::HeaderCheckpoint, PeerNetwork};
 
 use super::{error::DatabaseError, PersistedPeer};
 
@@ -26,6 +28,25 @@ pub trait HeaderStore {
 
     /// Return the height of a block hash in the database, if it exists.
     async fn height_of<'a>(&mut self, hash: &'a BlockHash) -> Result<Option<u32>, DatabaseError>;
+
+    /// Return the header at a height in the database, if it exists.
+    async fn header_at(&mut self, height: u32) -> Result<Option<Header>, DatabaseError>;
+
+    /// Load the headers with heights in the inclusive range, indexed by height.
+    async fn headers_in_range(
+        &mut self,
+        start_height: u32,
+        end_height: u32,
+    ) -> Result<BTreeMap<u32, Header>, DatabaseError>;
+
+    /// Return the block hash at a height in the database, if it exists.
+    async fn hash_at(&mut self, height: u32) -> Result<Option<BlockHash>, DatabaseError>;
+
+    /// Return the height and block hash of the header with the greatest height, if there are any headers.
+    async fn tip(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError>;
+
+    /// Remove all headers with heights *strictly after* the specified height.
+    async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError>;
 }
 
 // Do nothing
@@ -56,6 +77,118 @@ impl HeaderStore for () {
     ) -> Result<Option<u32>, DatabaseError> {
         Ok(None)
     }
+
+    async fn header_at(&mut self, _height: u32) -> Result<Option<Header>, DatabaseError> {
+        Ok(None)
+    }
+
+    async fn headers_in_range(
+        &mut self,
+        _start_height: u32,
+        _end_height: u32,
+    ) -> Result<BTreeMap<u32, Header>, DatabaseError> {
+        Ok(BTreeMap::new())
+    }
+
+    async fn hash_at(&mut self, _height: u32) -> Result<Option<BlockHash>, DatabaseError> {
+        Ok(None)
+    }
+
+    async fn tip(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError> {
+        Ok(None)
+    }
+
+    async fn truncate(&mut self, _height: u32) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+}
+
+/// Methods required to persist the chain of compact filter headers, along with the filter hashes that commit to them.
+#[async_trait]
+pub trait FilterHeaderStore {
+    /// Load all filter headers with heights *strictly after* the specified anchor height.
+    async fn load(
+        &mut self,
+        anchor_height: u32,
+    ) -> Result<BTreeMap<u32, (FilterHeader, FilterHash)>, DatabaseError>;
+
+    /// Write an indexed map of filter headers to the database, replacing any that exist at these heights.
+    async fn write<'a>(
+        &mut self,
+        filter_headers: &'a BTreeMap<u32, (FilterHeader, FilterHash)>,
+    ) -> Result<(), DatabaseError>;
+
+    /// Remove all filter headers with heights *strictly after* the specified height.
+    async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError>;
+}
+
+// Do nothing
+#[async_trait]
+impl FilterHeaderStore for () {
+    async fn load(
+        &mut self,
+        _anchor_height: u32,
+    ) -> Result<BTreeMap<u32, (FilterHeader, FilterHash)>, DatabaseError> {
+        Ok(BTreeMap::new())
+    }
+
+    async fn write<'a>(
+        &mut self,
+        _filter_headers: &'a BTreeMap<u32, (FilterHeader, FilterHash)>,
+    ) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+
+    async fn truncate(&mut self, _height: u32) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+}
+
+/// Methods required to persist compact block filters, so filters may be scanned locally for new scripts.
+#[async_trait]
+pub trait FilterStore {
+    /// Write the filter for the block with this hash and height, replacing any filter at this height.
+    async fn put(
+        &mut self,
+        height: u32,
+        block_hash: BlockHash,
+        filter: Vec<u8>,
+    ) -> Result<(), DatabaseError>;
+
+    /// Load the filters with heights in the inclusive range, indexed by height.
+    async fn load_range(
+        &mut self,
+        start_height: u32,
+        end_height: u32,
+    ) -> Result<BTreeMap<u32, (BlockHash, Vec<u8>)>, DatabaseError>;
+
+    /// Remove all filters with heights *strictly after* the specified height.
+    async fn truncate(&mut self, height: u32) -> Result<(), DatabaseError>;
+}
+
+// Do nothing
+#[async_trait]
+impl FilterStore for () {
+    async fn put(
+        &mut self,
+        _height: u32,
+        _block_hash: BlockHash,
+        _filter: Vec<u8>,
+    ) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+
+    async fn load_range(
+        &mut self,
+        _start_height: u32,
+        _end_height: u32,
+    ) -> Result<BTreeMap<u32, (BlockHash, Vec<u8>)>, DatabaseError> {
+        Ok(BTreeMap::new())
+    }
+
+    async fn truncate(&mut self, _height: u32) -> Result<(), DatabaseError> {
+        Ok(())
+    }
 }
 
 /// Methods that define a list of peers on the Bitcoin P2P network.
@@ -64,10 +197,11 @@ pub trait PeerStore {
     /// Add a peer to the database, defining if it should be replaced or not.
     async fn update(&mut self, peer: PersistedPeer, replace: bool) -> Result<(), DatabaseError>;
 
-    /// Get any peer from the database, selected at random.
-    async fn random(&mut self) -> Result<PersistedPeer, DatabaseError>;
+    /// Get any peer from the database on one of the given networks, selected at random. Peers are never
+    /// selected until their ban expires.
+    async fn random(&mut self, networks: &[PeerNetwork]) -> Result<PersistedPeer, DatabaseError>;
 
-    /// The number of peers in the database that are not marked as banned.
+    /// The number of peers in the database that are not banned, including peers whose ban expired.
     async fn num_unbanned(&mut self) -> Result<u32, DatabaseError>;
 }
 
@@ -77,7 +211,7 @@ impl PeerStore for () {
         Ok(())
     }
 
-    async fn random(&mut self) -> Result<PersistedPeer, DatabaseError> {
+    async fn random(&mut self, _networks: &[PeerNetwork]) -> Result<PersistedPeer, DatabaseError> {
         Err(DatabaseError::LoadError)
     }
 
@@ -86,6 +220,80 @@ impl PeerStore for () {
     }
 }
 
+/// Methods that persist the outputs paying to watched scripts, so later spends of these outputs may be detected.
+#[async_trait]
+pub trait OutPointStore {
+    /// Load all of the watched outpoints.
+    async fn load(&mut self) -> Result<HashSet<OutPoint>, DatabaseError>;
+
+    /// Add an outpoint to the database, ignoring it if it already exists.
+    async fn put(&mut self, outpoint: OutPoint) -> Result<(), DatabaseError>;
+}
+
+// Only keep outpoints in memory
+#[async_trait]
+impl OutPointStore for () {
+    async fn load(&mut self) -> Result<HashSet<OutPoint>, DatabaseError> {
+        Ok(HashSet::new())
+    }
+
+    async fn put(&mut self, _outpoint: OutPoint) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+}
+
+// Allow a store chosen at runtime to be used where a generic store is expected
+#[async_trait]
+impl<T: OutPointStore + Send + ?Sized> OutPointStore for Box<T> {
+    async fn load(&mut self) -> Result<HashSet<OutPoint>, DatabaseError> {
+        self.as_mut().load().await
+    }
+
+    async fn put(&mut self, outpoint: OutPoint) -> Result<(), DatabaseError> {
+        self.as_mut().put(outpoint).await
+    }
+}
+
+/// Methods that persist the progress of the node, so a restarted node may resume scanning for the
+/// watched scripts where it stopped.
+#[async_trait]
+pub trait ProgressStore {
+    /// Load the last checkpoint where every block up to and including the checkpoint was scanned for the
+    /// persisted scripts, if there is one.
+    async fn load_checkpoint(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError>;
+
+    /// Load the scripts that every block up to the checkpoint was scanned for.
+    async fn load_scripts(&mut self) -> Result<HashSet<ScriptBuf>, DatabaseError>;
+
+    /// Replace the checkpoint and the scripts every block up to it was scanned for. Scripts that were added
+    /// after the scan started are not included.
+    async fn write<'a>(
+        &mut self,
+        checkpoint: HeaderCheckpoint,
+        scripts: &'a HashSet<ScriptBuf>,
+    ) -> Result<(), DatabaseError>;
+}
+
+// Always scan from the anchor
+#[async_trait]
+impl ProgressStore for () {
+    async fn load_checkpoint(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError> {
+        Ok(None)
+    }
+
+    async fn load_scripts(&mut self) -> Result<HashSet<ScriptBuf>, DatabaseError> {
+        Ok(HashSet::new())
+    }
+
+    async fn write<'a>(
+        &mut self,
+        _checkpoint: HeaderCheckpoint,
+        _scripts: &'a HashSet<ScriptBuf>,
+    ) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+}
+
 impl std::fmt::Debug for dyn HeaderStore + Send + Sync + 'static {
     fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         std::fmt::Result::Ok(())
@@ -97,3 +305,27 @@ impl std::fmt::Debug for dyn PeerStore + Send + Sync + 'static {
         std::fmt::Result::Ok(())
     }
 }
+
+impl std::fmt::Debug for dyn OutPointStore + Send + Sync + 'static {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Result::Ok(())
+    }
+}
+
+impl std::fmt::Debug for dyn FilterHeaderStore + Send + Sync + 'static {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Result::Ok(())
+    }
+}
+
+impl std::fmt::Debug for dyn FilterStore + Send + Sync + 'static {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Result::Ok(())
+    }
+}
+
+impl std::fmt::Debug for dyn ProgressStore + Send + Sync + 'static {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Result::Ok(())
+    }
+}
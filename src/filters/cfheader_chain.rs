@@ -1,6 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use bitcoin::{block::Header, BlockHash, FilterHash, FilterHeader};
+use bitcoin::{p2p::message_filter::GetCFilters, BlockHash, FilterHash, FilterHeader};
 
 use crate::chain::checkpoints::HeaderCheckpoint;
 
@@ -8,8 +8,6 @@ use super::{cfheader_batch::CFHeaderBatch, error::CFHeaderSyncError};
 
 type InternalChain = Vec<(FilterHeader, FilterHash)>;
 
-const INITIAL_BUFFER_SIZE: usize = 20_000;
-
 pub(crate) enum AppendAttempt {
     // Nothing to do yet
     AddedToQueue,
@@ -23,25 +21,28 @@ pub(crate) enum AppendAttempt {
 pub(crate) enum CFHeaderSyncResult {
     AddedToQueue,
     ReadyForNext,
-    Dispute(BlockHash),
+    // Request the disputed filter from every peer
+    Dispute(GetCFilters),
 }
 #[derive(Debug)]
 pub(crate) struct CFHeaderChain {
     anchor_checkpoint: HeaderCheckpoint,
     header_chain: InternalChain,
     merged_queue: HashMap<u32, InternalChain>,
-    block_to_hash: HashMap<BlockHash, FilterHash>,
     prev_stophash_request: Option<BlockHash>,
     quorum_required: usize,
 }
 
 impl CFHeaderChain {
-    pub(crate) fn new(anchor_checkpoint: HeaderCheckpoint, quorum_required: usize) -> Self {
+    pub(crate) fn new(
+        anchor_checkpoint: HeaderCheckpoint,
+        loaded_headers: BTreeMap<u32, (FilterHeader, FilterHash)>,
+        quorum_required: usize,
+    ) -> Self {
         Self {
             anchor_checkpoint,
-            header_chain: Vec::new(),
+            header_chain: loaded_headers.into_values().collect(),
             merged_queue: HashMap::new(),
-            block_to_hash: HashMap::with_capacity(INITIAL_BUFFER_SIZE),
             prev_stophash_request: None,
             quorum_required,
         }
@@ -56,7 +57,7 @@ impl CFHeaderChain {
         self.try_merge().await
     }
 
-    async fn try_merge(&mut self) -> Result<AppendAttempt, CFHeaderSyncError> {
+    pub(crate) async fn try_merge(&mut self) -> Result<AppendAttempt, CFHeaderSyncError> {
         let staged_headers = self.merged_queue.values().count();
         if staged_headers.ge(&self.quorum_required) {
             self.append_or_conflict().await
@@ -105,6 +106,24 @@ impl CFHeaderChain {
         }
     }
 
+    // The filter headers strictly after a height, indexed by height
+    pub(crate) fn headers_after(&self, height: u32) -> BTreeMap<u32, (FilterHeader, FilterHash)> {
+        let start = height.saturating_sub(self.anchor_checkpoint.height);
+        self.header_chain
+            .iter()
+            .enumerate()
+            .skip(start as usize)
+            .map(|(index, header)| (self.anchor_checkpoint.height + index as u32 + 1, *header))
+            .collect()
+    }
+
+    // Remove the filter headers strictly after a height, as the blocks they commit to were reorganized
+    pub(crate) fn truncate(&mut self, height: u32) {
+        let keep = height.saturating_sub(self.anchor_checkpoint.height);
+        self.header_chain.truncate(keep as usize);
+        self.merged_queue.clear();
+    }
+
     pub(crate) fn set_last_stop_hash(&mut self, stop_hash: BlockHash) {
         self.prev_stophash_request = Some(stop_hash)
     }
@@ -131,25 +150,22 @@ impl CFHeaderChain {
         }
     }
 
-    pub(crate) fn map_len(&self) -> usize {
-        self.block_to_hash.len()
-    }
-
-    pub(crate) fn clear_queue(&mut self) {
-        self.merged_queue.clear()
+    // The filter hash each peer in the queue committed to at this height
+    pub(crate) fn claims_at(&self, height: u32) -> HashMap<u32, FilterHash> {
+        let index = height.saturating_sub(self.height() + 1) as usize;
+        self.merged_queue
+            .iter()
+            .filter_map(|(peer, headers)| headers.get(index).map(|(_, hash)| (*peer, *hash)))
+            .collect()
     }
 
-    pub(crate) async fn join(&mut self, headers: &[Header]) {
-        headers
-            .iter()
-            .zip(self.header_chain.iter().map(|(_, hash)| hash))
-            .for_each(|(header, hash)| {
-                self.block_to_hash.insert(header.block_hash(), *hash);
-            })
+    // Drop the staged headers from these peers
+    pub(crate) fn remove_peers(&mut self, peers: &[u32]) {
+        self.merged_queue.retain(|peer, _| !peers.contains(peer));
     }
 
-    pub(crate) fn hash_at(&self, block: &BlockHash) -> Option<&FilterHash> {
-        self.block_to_hash.get(block)
+    pub(crate) fn clear_queue(&mut self) {
+        self.merged_queue.clear()
     }
 
     pub(crate) fn quorum_required(&self) -> usize {
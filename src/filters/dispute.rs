@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bitcoin::{p2p::message_filter::GetCFilters, Block, BlockHash, FilterHash};
+
+use super::filter::Filter;
+
+// Every peer in a dispute must send its filter, and the block must arrive, within this time
+const DISPUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Peers disagreed on the compact filter header at this height. Each peer in the dispute must send the
+// filter it committed to, and the filters are compared against the block itself.
+#[derive(Debug)]
+pub(crate) struct FilterDispute {
+    height: u32,
+    block_hash: BlockHash,
+    claims: HashMap<u32, FilterHash>,
+    filters: HashMap<u32, Filter>,
+    started: Instant,
+}
+
+impl FilterDispute {
+    pub(crate) fn new(
+        height: u32,
+        block_hash: BlockHash,
+        claims: HashMap<u32, FilterHash>,
+        now: Instant,
+    ) -> Self {
+        Self {
+            height,
+            block_hash,
+            claims,
+            filters: HashMap::new(),
+            started: now,
+        }
+    }
+
+    pub(crate) fn block_hash(&self) -> BlockHash {
+        self.block_hash
+    }
+
+    pub(crate) fn filter_message(&self) -> GetCFilters {
+        GetCFilters {
+            filter_type: 0x00,
+            start_height: self.height,
+            stop_hash: self.block_hash,
+        }
+    }
+
+    // Add a filter from a disputing peer, returning if every peer has sent their filter
+    pub(crate) fn add_filter(&mut self, peer_id: u32, filter: Filter) -> bool {
+        if self.claims.contains_key(&peer_id) {
+            self.filters.insert(peer_id, filter);
+        }
+        self.claims.len().eq(&self.filters.len())
+    }
+
+    // The peers that sent a filter that does not match their commitment or the block
+    pub(crate) async fn liars(&mut self, block: &Block) -> Vec<u32> {
+        let mut liars = Vec::new();
+        for (peer_id, claim) in &self.claims {
+            if let Some(filter) = self.filters.get_mut(peer_id) {
+                if filter.filter_hash().await.ne(claim)
+                    || !filter.is_filter_for_block(block).await.unwrap_or(false)
+                {
+                    liars.push(*peer_id);
+                }
+            }
+        }
+        liars
+    }
+
+    pub(crate) fn num_peers(&self) -> usize {
+        self.claims.len()
+    }
+
+    // The peers in the dispute that have not sent their filter
+    pub(crate) fn silent_peers(&self) -> Vec<u32> {
+        self.claims
+            .keys()
+            .filter(|peer_id| !self.filters.contains_key(peer_id))
+            .copied()
+            .collect()
+    }
+
+    pub(crate) fn timed_out(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.started)
+            .gt(&DISPUTE_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use bitcoin::{
+        absolute,
+        bip158::BlockFilter,
+        block::{self, Header},
+        hashes::Hash,
+        transaction, Amount, Block, BlockHash, CompactTarget, FilterHash, OutPoint, ScriptBuf,
+        Sequence, Transaction, TxIn, TxMerkleNode, TxOut, WPubkeyHash, Witness,
+    };
+
+    use crate::filters::filter::Filter;
+
+    use super::{FilterDispute, DISPUTE_TIMEOUT};
+
+    fn pay_to(script_pubkey: ScriptBuf) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_finds_liar() {
+        let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+        let mut block = Block {
+            header: Header {
+                version: block::Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![pay_to(ScriptBuf::new()), pay_to(script.clone())],
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let block_hash = block.block_hash();
+        let honest = Filter::new(
+            BlockFilter::new_script_filter(&block, |_| Ok(script.clone()))
+                .unwrap()
+                .content,
+            block_hash,
+        );
+        // A filter that omits the outputs of the block
+        let mut empty_block = block.clone();
+        empty_block.txdata.truncate(1);
+        let dishonest = Filter::new(
+            BlockFilter::new_script_filter(&empty_block, |_| Ok(ScriptBuf::new()))
+                .unwrap()
+                .content,
+            block_hash,
+        );
+        let claims = HashMap::from([
+            (1, honest.filter_hash().await),
+            (2, dishonest.filter_hash().await),
+            // Sends a valid filter, but committed to a different one
+            (3, dishonest.filter_hash().await),
+        ]);
+        let mut dispute = FilterDispute::new(10, block_hash, claims, Instant::now());
+        assert!(!dispute.add_filter(1, honest.clone()));
+        assert!(!dispute.add_filter(2, dishonest));
+        // Peers not in the dispute are ignored
+        assert!(!dispute.add_filter(4, honest.clone()));
+        assert!(dispute.add_filter(3, honest));
+        let mut liars = dispute.liars(&block).await;
+        liars.sort();
+        assert_eq!(liars, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_times_out_silent_peers() {
+        let claims = HashMap::from([(1, FilterHash::all_zeros()), (2, FilterHash::all_zeros())]);
+        let now = Instant::now();
+        let mut dispute = FilterDispute::new(10, BlockHash::all_zeros(), claims, now);
+        assert!(!dispute.add_filter(1, Filter::new(Vec::new(), BlockHash::all_zeros())));
+        assert_eq!(dispute.silent_peers(), vec![2]);
+        assert!(!dispute.timed_out(now + DISPUTE_TIMEOUT));
+        assert!(dispute.timed_out(now + DISPUTE_TIMEOUT + Duration::from_secs(1)));
+    }
+}
@@ -16,6 +16,8 @@ pub enum CFHeaderSyncError {
     HeaderChainIndexOverflow,
     #[error("we already had a message from this peer staged in our queue")]
     UnexpectedCFHeaderMessage,
+    #[error("the block sent to resolve a filter dispute was not valid")]
+    InvalidDisputedBlock,
 }
 
 #[derive(Error, Debug)]
@@ -27,7 +27,11 @@ impl Filter {
         FilterHash::from_raw_hash(hash)
     }
 
-    fn block_hash(&self) -> &BlockHash {
+    pub fn contents(&self) -> &[u8] {
+        &self.contents
+    }
+
+    pub fn block_hash(&self) -> &BlockHash {
         &self.block_hash
     }
 
@@ -1,44 +1,237 @@
-use std::collections::HashSet;
-
-use bitcoin::BlockHash;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
 
 use crate::chain::checkpoints::HeaderCheckpoint;
 
-const INITIAL_BUFFER_SIZE: usize = 20_000;
+use super::FILTER_BATCH_SIZE;
+
+// A peer serving a range of filters must send the next filter in the range within this time
+const FILTER_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+// A range of filters requested from a single peer. Peers send the filters in a range in order.
+#[derive(Debug, Clone, Copy)]
+struct FilterRequest {
+    next_height: u32,
+    stop_height: u32,
+    last_progress: Instant,
+}
 
+// The filters we have checked, which may arrive out of order as ranges are downloaded from many peers at once
 #[derive(Debug)]
 pub(crate) struct FilterChain {
     anchor_checkpoint: HeaderCheckpoint,
-    chain: HashSet<BlockHash>,
-    prev_stophash_request: Option<BlockHash>,
+    // Every filter up to and including this height was checked
+    height: u32,
+    // Filters checked above the height
+    ahead: BTreeSet<u32>,
+    // The range each peer is currently sending
+    in_flight: HashMap<u32, FilterRequest>,
+    // Ranges a peer failed to complete, indexed by start height
+    retry: BTreeMap<u32, u32>,
+    // The lowest height that was never requested
+    next_unrequested: u32,
 }
 
 impl FilterChain {
     pub(crate) fn new(anchor_checkpoint: HeaderCheckpoint) -> Self {
         Self {
             anchor_checkpoint,
-            chain: HashSet::with_capacity(INITIAL_BUFFER_SIZE),
-            prev_stophash_request: None,
+            height: anchor_checkpoint.height,
+            ahead: BTreeSet::new(),
+            in_flight: HashMap::new(),
+            retry: BTreeMap::new(),
+            next_unrequested: anchor_checkpoint.height + 1,
         }
     }
 
-    pub(crate) async fn put_hash(&mut self, hash: BlockHash) {
-        self.chain.insert(hash);
+    // Mark the filter at this height as checked
+    pub(crate) fn put_height(&mut self, height: u32) {
+        if height.le(&self.height) {
+            return;
+        }
+        self.ahead.insert(height);
+        while self.ahead.remove(&(self.height + 1)) {
+            self.height += 1;
+        }
+        self.next_unrequested = self.next_unrequested.max(self.height + 1);
     }
 
+    // Forget every filter and request, so the filters are checked again from the anchor
     pub(crate) async fn clear_cache(&mut self) {
-        self.chain.clear()
+        *self = Self::new(self.anchor_checkpoint);
     }
 
     pub(crate) fn height(&self) -> u32 {
-        self.anchor_checkpoint.height + self.chain.len() as u32
+        self.height
+    }
+
+    // Assign a new range of filters, up to the tip height, to each peer without one. Returns the peer,
+    // start height and stop height of each new request.
+    pub(crate) fn assign(
+        &mut self,
+        peers: &[u32],
+        tip_height: u32,
+        now: Instant,
+    ) -> Vec<(u32, u32, u32)> {
+        // Requests to peers that are no longer connected must be sent elsewhere
+        let gone: Vec<u32> = self
+            .in_flight
+            .keys()
+            .filter(|peer| !peers.contains(peer))
+            .copied()
+            .collect();
+        for peer in gone {
+            self.release(peer);
+        }
+        let mut assigned = Vec::new();
+        for peer in peers {
+            if self.in_flight.contains_key(peer) {
+                continue;
+            }
+            let retry = self.retry.keys().next().copied();
+            let (start_height, stop_height) = match retry {
+                Some(start_height) => (
+                    start_height,
+                    self.retry
+                        .remove(&start_height)
+                        .expect("key was just found"),
+                ),
+                None => {
+                    if self.next_unrequested.gt(&tip_height) {
+                        break;
+                    }
+                    let start_height = self.next_unrequested;
+                    let stop_height = (start_height + FILTER_BATCH_SIZE).min(tip_height);
+                    self.next_unrequested = stop_height + 1;
+                    (start_height, stop_height)
+                }
+            };
+            self.in_flight.insert(
+                *peer,
+                FilterRequest {
+                    next_height: start_height,
+                    stop_height,
+                    last_progress: now,
+                },
+            );
+            assigned.push((*peer, start_height, stop_height));
+        }
+        assigned
+    }
+
+    // The height of the next filter this peer should send, if the peer is sending a range
+    pub(crate) fn expected_height(&self, peer: u32) -> Option<u32> {
+        self.in_flight.get(&peer).map(|request| request.next_height)
+    }
+
+    // The peer sent the filter it was expected to send, and the filter was checked
+    pub(crate) fn received(&mut self, peer: u32, now: Instant) {
+        if let Some(request) = self.in_flight.get_mut(&peer) {
+            let height = request.next_height;
+            request.next_height += 1;
+            request.last_progress = now;
+            if request.next_height.gt(&request.stop_height) {
+                self.in_flight.remove(&peer);
+            }
+            self.put_height(height);
+        }
     }
 
-    pub(crate) fn set_last_stop_hash(&mut self, stop_hash: BlockHash) {
-        self.prev_stophash_request = Some(stop_hash)
+    // Release the requests of peers that have not made progress in time, returning the stalled peers
+    pub(crate) fn stalled(&mut self, now: Instant) -> Vec<u32> {
+        let stalled: Vec<u32> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| {
+                now.saturating_duration_since(request.last_progress)
+                    .gt(&FILTER_REQUEST_TIMEOUT)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &stalled {
+            self.release(*peer);
+        }
+        stalled
+    }
+
+    // The filters this peer did not send are requested from another peer
+    fn release(&mut self, peer: u32) {
+        if let Some(request) = self.in_flight.remove(&peer) {
+            self.retry.insert(request.next_height, request.stop_height);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, BlockHash};
+
+    use super::*;
+
+    fn anchor() -> HeaderCheckpoint {
+        HeaderCheckpoint::new(10, BlockHash::all_zeros())
+    }
+
+    #[test]
+    fn test_links_filters_out_of_order() {
+        let mut chain = FilterChain::new(anchor());
+        chain.put_height(12);
+        chain.put_height(13);
+        assert_eq!(chain.height(), 10);
+        chain.put_height(11);
+        assert_eq!(chain.height(), 13);
+        // Filters at or below the height do nothing
+        chain.put_height(5);
+        assert_eq!(chain.height(), 13);
+    }
+
+    #[test]
+    fn test_distributes_ranges_across_peers() {
+        let mut chain = FilterChain::new(anchor());
+        let now = Instant::now();
+        let tip = 10 + 2 * (FILTER_BATCH_SIZE + 1) + 5;
+        let assigned = chain.assign(&[1, 2, 3, 4], tip, now);
+        assert_eq!(
+            assigned,
+            vec![
+                (1, 11, 11 + FILTER_BATCH_SIZE),
+                (2, 12 + FILTER_BATCH_SIZE, 12 + 2 * FILTER_BATCH_SIZE),
+                (3, 13 + 2 * FILTER_BATCH_SIZE, tip),
+            ]
+        );
+        // Peers already sending a range are not given another
+        assert!(chain.assign(&[1, 2, 3, 4], tip, now).is_empty());
+        // The second range arrives first
+        for height in 12 + FILTER_BATCH_SIZE..=12 + 2 * FILTER_BATCH_SIZE {
+            assert_eq!(chain.expected_height(2), Some(height));
+            chain.received(2, now);
+        }
+        assert_eq!(chain.expected_height(2), None);
+        assert_eq!(chain.height(), 10);
+        for _ in 11..=11 + FILTER_BATCH_SIZE {
+            chain.received(1, now);
+        }
+        assert_eq!(chain.height(), 11 + 2 * FILTER_BATCH_SIZE + 1);
     }
 
-    pub(crate) fn last_stop_hash_request(&mut self) -> &Option<BlockHash> {
-        &self.prev_stophash_request
+    #[test]
+    fn test_reassigns_stalled_ranges() {
+        let mut chain = FilterChain::new(anchor());
+        let now = Instant::now();
+        chain.assign(&[1], 20, now);
+        chain.received(1, now);
+        chain.received(1, now);
+        assert!(chain.stalled(now + Duration::from_secs(1)).is_empty());
+        let later = now + FILTER_REQUEST_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(chain.stalled(later), vec![1]);
+        assert_eq!(chain.expected_height(1), None);
+        // Only the filters the peer did not send are requested again
+        assert_eq!(chain.assign(&[2], 20, later), vec![(2, 13, 20)]);
+        // The range of a disconnected peer is handed to the remaining peers
+        assert_eq!(chain.assign(&[3], 20, later), vec![(3, 13, 20)]);
+        // Nothing is left to request past the tip
+        assert!(chain.assign(&[3, 4], 20, later).is_empty());
     }
 }
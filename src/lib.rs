@@ -16,7 +16,12 @@ pub mod node;
 mod peers;
 mod prelude;
 
+use std::net::SocketAddr;
+
+use peers::addrv2;
+
 pub use bitcoin::block::Header;
+pub use bitcoin::p2p::address::AddrV2;
 pub use bitcoin::{Address, Block, BlockHash, Transaction};
 
 /// A Bitcoin [`Transaction`] with additional context.
@@ -95,4 +100,111 @@ pub enum TxBroadcastPolicy {
     AllPeers,
     /// Broadcast the transaction to a single random peer, optimal for user privacy.
     RandomPeer,
+    /// Open a new connection to a peer we are not otherwise connected to, announce only this transaction,
+    /// and disconnect once the peer requests it. The transaction is not linked to the requests made to our other peers.
+    OneShotPeer,
+}
+
+/// How connections to peers on the Bitcoin P2P network are made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionMode {
+    /// Connect to IPv4, IPv6 and CJDNS peers directly. Tor and I2P peers are never selected.
+    Direct,
+    /// Connect to IPv4, IPv6 and Tor peers through a SOCKS5 proxy, such as the one exposed by a Tor daemon.
+    /// I2P and CJDNS peers cannot be reached through a Tor proxy, so they are never selected. DNS seeds cannot
+    /// be queried through the proxy, so they are never queried, and peers must be added to the node or already known.
+    Socks5Proxy(SocketAddr),
+    /// Connect through a SOCKS5 proxy and refuse any IPv4 or IPv6 peer, so only Tor peers are used.
+    /// DNS seeds are never queried, so peers must be added to the node or already known.
+    Socks5ProxyNoClearnet(SocketAddr),
+}
+
+impl ConnectionMode {
+    // The networks a connection can be made to
+    pub(crate) fn networks(&self) -> Vec<PeerNetwork> {
+        match self {
+            ConnectionMode::Direct => {
+                vec![PeerNetwork::Ipv4, PeerNetwork::Ipv6, PeerNetwork::Cjdns]
+            }
+            ConnectionMode::Socks5Proxy(_) => {
+                vec![PeerNetwork::Ipv4, PeerNetwork::Ipv6, PeerNetwork::TorV3]
+            }
+            ConnectionMode::Socks5ProxyNoClearnet(_) => vec![PeerNetwork::TorV3],
+        }
+    }
+
+    // Are connections made through a proxy
+    pub(crate) fn is_proxied(&self) -> bool {
+        !matches!(self, ConnectionMode::Direct)
+    }
+
+    // Can a connection be made to this address
+    pub(crate) fn can_reach(&self, addr: &AddrV2) -> bool {
+        PeerNetwork::from_addr(addr).map_or(false, |network| self.networks().contains(&network))
+    }
+}
+
+/// The [BIP-155](https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki) networks a peer may be
+/// reached on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerNetwork {
+    /// IPv4 addresses.
+    Ipv4,
+    /// IPv6 addresses.
+    Ipv6,
+    /// Version three Tor onion services.
+    TorV3,
+    /// I2P destinations.
+    I2p,
+    /// CJDNS addresses, which are IPv6 addresses in `fc00::/8`.
+    Cjdns,
+}
+
+impl PeerNetwork {
+    pub(crate) const ALL: [PeerNetwork; 5] = [
+        PeerNetwork::Ipv4,
+        PeerNetwork::Ipv6,
+        PeerNetwork::TorV3,
+        PeerNetwork::I2p,
+        PeerNetwork::Cjdns,
+    ];
+
+    // Tor V2 and unknown networks can never be connected to
+    pub(crate) fn from_addr(addr: &AddrV2) -> Option<Self> {
+        match addr {
+            AddrV2::Ipv4(_) => Some(PeerNetwork::Ipv4),
+            AddrV2::Ipv6(_) => Some(PeerNetwork::Ipv6),
+            AddrV2::TorV3(_) => Some(PeerNetwork::TorV3),
+            AddrV2::I2p(_) => Some(PeerNetwork::I2p),
+            AddrV2::Cjdns(_) => Some(PeerNetwork::Cjdns),
+            _ => None,
+        }
+    }
+
+    // The network ID as defined in BIP-155
+    pub(crate) fn bip155_id(&self) -> u8 {
+        match self {
+            PeerNetwork::Ipv4 => addrv2::IPV4_ID,
+            PeerNetwork::Ipv6 => addrv2::IPV6_ID,
+            PeerNetwork::TorV3 => addrv2::TORV3_ID,
+            PeerNetwork::I2p => addrv2::I2P_ID,
+            PeerNetwork::Cjdns => addrv2::CJDNS_ID,
+        }
+    }
+
+    pub(crate) fn is_clearnet(&self) -> bool {
+        matches!(self, PeerNetwork::Ipv4 | PeerNetwork::Ipv6)
+    }
+}
+
+/// The transport used to exchange messages with peers on the Bitcoin P2P network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportPreference {
+    /// Only use the unencrypted V1 transport.
+    V1Only,
+    /// Use the encrypted [BIP-324](https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki) V2 transport,
+    /// falling back to V1 if the remote peer does not advertise or complete the V2 handshake.
+    V2WithFallback,
+    /// Only connect to peers that complete the encrypted V2 handshake.
+    V2Only,
 }
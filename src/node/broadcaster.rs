@@ -1,24 +1,278 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use bitcoin::{Transaction, Txid};
+
 use crate::TxBroadcast;
 
+use super::messages::{FailedBroadcast, TxBroadcastFailureReason};
+
+// Peers request an announced transaction within seconds, so we announce again shortly if none did
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// Once a peer took the transaction, it is announced again in case it was evicted from mempools
+const REBROADCAST_INTERVAL: Duration = Duration::from_secs(60 * 10);
+// The number of announcements before a transaction that was never relayed back is abandoned
+const MAX_ANNOUNCEMENTS: u8 = 5;
+// We do not receive the transactions announced by peers, so we ask a peer for a transaction once a peer took it.
+// Peers only answer a `getdata` for a transaction they did not announce to us once it has been in their mempool
+// for two minutes.
+const SEEN_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 3);
+
+#[derive(Debug, Clone)]
+struct PendingTx {
+    broadcast: TxBroadcast,
+    announcements: u8,
+    last_announced: Option<Instant>,
+    // A peer requested the transaction with a `getdata`
+    requested: bool,
+    seen: bool,
+    next_check: Option<Instant>,
+}
+
+impl PendingTx {
+    fn next_announcement(&self) -> Option<Instant> {
+        let interval = if self.requested {
+            REBROADCAST_INTERVAL
+        } else {
+            ANNOUNCE_INTERVAL
+        };
+        self.last_announced.map(|last| last + interval)
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.next_announcement().map_or(true, |next| now.ge(&next))
+    }
+
+    // A transaction seen relayed back is rebroadcast until it is confirmed
+    fn failure(&self) -> Option<TxBroadcastFailureReason> {
+        if self.seen || self.announcements < MAX_ANNOUNCEMENTS {
+            return None;
+        }
+        if self.requested {
+            Some(TxBroadcastFailureReason::NotRelayed)
+        } else {
+            Some(TxBroadcastFailureReason::NotRequested)
+        }
+    }
+}
+
+// Transactions are announced with an `inv` and sent to the peers that request them. A transaction is announced
+// on a schedule until it is found in a block, or abandoned if no peer took it.
 #[derive(Debug, Clone)]
 pub(crate) struct Broadcaster {
-    queue: Vec<TxBroadcast>,
+    pending: HashMap<Txid, PendingTx>,
 }
 
 impl Broadcaster {
     pub(crate) fn new() -> Self {
-        Self { queue: Vec::new() }
+        Self {
+            pending: HashMap::new(),
+        }
     }
 
     pub(crate) fn add(&mut self, tx: TxBroadcast) {
-        self.queue.push(tx)
+        let txid = tx.tx.compute_txid();
+        self.pending.entry(txid).or_insert(PendingTx {
+            broadcast: tx,
+            announcements: 0,
+            last_announced: None,
+            requested: false,
+            seen: false,
+            next_check: None,
+        });
     }
 
     pub(crate) fn is_empty(&self) -> bool {
-        self.queue.is_empty()
+        self.pending.is_empty()
+    }
+
+    // Remove the transactions that were announced as many times as allowed without being taken by a peer
+    pub(crate) fn abandon(&mut self, now: Instant) -> Vec<FailedBroadcast> {
+        let failed: Vec<FailedBroadcast> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.is_due(now))
+            .filter_map(|(txid, pending)| {
+                pending
+                    .failure()
+                    .map(|reason| FailedBroadcast::new(*txid, reason))
+            })
+            .collect();
+        for failure in &failed {
+            self.pending.remove(&failure.txid);
+        }
+        failed
+    }
+
+    // The transactions that should be announced to peers now
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<TxBroadcast> {
+        self.pending
+            .values_mut()
+            .filter(|pending| pending.is_due(now))
+            .map(|pending| {
+                pending.announcements = pending.announcements.saturating_add(1);
+                pending.last_announced = Some(now);
+                pending.broadcast.clone()
+            })
+            .collect()
+    }
+
+    // A peer requested one of our transactions. Returns the transaction and if this is the first request for it.
+    pub(crate) fn requested(&mut self, txid: &Txid) -> Option<(Transaction, bool)> {
+        let pending = self.pending.get_mut(txid)?;
+        let first = !pending.requested;
+        pending.requested = true;
+        Some((pending.broadcast.tx.clone(), first))
+    }
+
+    // The transactions taken by a peer that should be requested from a peer now, to learn if they are in its
+    // mempool. The first request waits for the transaction to be relayed.
+    pub(crate) fn unseen(&mut self, now: Instant) -> Vec<Txid> {
+        self.pending
+            .iter_mut()
+            .filter(|(_, pending)| pending.requested && !pending.seen)
+            .filter_map(|(txid, pending)| {
+                let check = pending.next_check.map_or(false, |next| now.ge(&next));
+                if check || pending.next_check.is_none() {
+                    pending.next_check = Some(now + SEEN_CHECK_INTERVAL);
+                }
+                check.then_some(*txid)
+            })
+            .collect()
+    }
+
+    // A peer sent one of our transactions. Returns true the first time the transaction is seen.
+    pub(crate) fn seen(&mut self, txid: &Txid) -> bool {
+        match self.pending.get_mut(txid) {
+            Some(pending) if !pending.seen => {
+                pending.seen = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // The transaction was found in a block, so it no longer needs to be broadcast
+    pub(crate) fn confirmed(&mut self, txid: &Txid) -> bool {
+        self.pending.remove(txid).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{absolute::LockTime, transaction::Version};
+
+    use crate::TxBroadcastPolicy;
+
+    use super::*;
+
+    fn transaction(lock_time: u32) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::from_consensus(lock_time),
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_abandons_transactions_never_requested() {
+        let mut broadcaster = Broadcaster::new();
+        let tx = transaction(1);
+        let txid = tx.compute_txid();
+        broadcaster.add(TxBroadcast::new(tx, TxBroadcastPolicy::AllPeers));
+        let mut now = Instant::now();
+        assert_eq!(broadcaster.due(now).len(), 1);
+        // Nothing is announced again before the interval passes
+        assert!(broadcaster.due(now + Duration::from_secs(1)).is_empty());
+        for _ in 1..MAX_ANNOUNCEMENTS {
+            now += ANNOUNCE_INTERVAL;
+            assert!(broadcaster.abandon(now).is_empty());
+            assert_eq!(broadcaster.due(now).len(), 1);
+        }
+        now += ANNOUNCE_INTERVAL;
+        let failed = broadcaster.abandon(now);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].txid, txid);
+        assert_eq!(failed[0].reason, TxBroadcastFailureReason::NotRequested);
+        assert!(broadcaster.is_empty());
+    }
+
+    #[test]
+    fn test_rebroadcasts_until_confirmed() {
+        let mut broadcaster = Broadcaster::new();
+        let tx = transaction(2);
+        let txid = tx.compute_txid();
+        broadcaster.add(TxBroadcast::new(tx.clone(), TxBroadcastPolicy::RandomPeer));
+        let mut now = Instant::now();
+        assert_eq!(broadcaster.due(now).len(), 1);
+        assert_eq!(broadcaster.requested(&txid), Some((tx.clone(), true)));
+        assert_eq!(broadcaster.requested(&txid), Some((tx.clone(), false)));
+        // Requested transactions wait longer before they are announced again
+        now += ANNOUNCE_INTERVAL;
+        assert!(broadcaster.due(now).is_empty());
+        assert!(broadcaster.seen(&txid));
+        assert!(!broadcaster.seen(&txid));
+        // A transaction relayed back by the network is never abandoned
+        for _ in 0..MAX_ANNOUNCEMENTS * 2 {
+            now += REBROADCAST_INTERVAL;
+            assert!(broadcaster.abandon(now).is_empty());
+            assert_eq!(broadcaster.due(now).len(), 1);
+        }
+        assert!(broadcaster.confirmed(&txid));
+        assert!(!broadcaster.confirmed(&txid));
+        assert!(broadcaster.requested(&txid).is_none());
+        assert!(broadcaster.is_empty());
+    }
+
+    #[test]
+    fn test_checks_mempools_for_requested_transactions() {
+        let mut broadcaster = Broadcaster::new();
+        let tx = transaction(4);
+        let txid = tx.compute_txid();
+        broadcaster.add(TxBroadcast::new(tx, TxBroadcastPolicy::AllPeers));
+        let now = Instant::now();
+        broadcaster.due(now);
+        // Nothing to check before a peer takes the transaction
+        assert!(broadcaster.unseen(now).is_empty());
+        broadcaster.requested(&txid);
+        // The transaction is given time to propagate before it is requested
+        assert!(broadcaster.unseen(now).is_empty());
+        assert!(broadcaster
+            .unseen(now + SEEN_CHECK_INTERVAL - Duration::from_secs(1))
+            .is_empty());
+        let later = now + SEEN_CHECK_INTERVAL;
+        assert_eq!(broadcaster.unseen(later), vec![txid]);
+        assert!(broadcaster.unseen(later).is_empty());
+        assert_eq!(broadcaster.unseen(later + SEEN_CHECK_INTERVAL), vec![txid]);
+        // A transaction found in a mempool is not requested again
+        assert!(broadcaster.seen(&txid));
+        assert!(broadcaster
+            .unseen(later + SEEN_CHECK_INTERVAL * 2)
+            .is_empty());
     }
 
-    pub(crate) fn next(&mut self) -> Option<TxBroadcast> {
-        self.queue.pop()
+    #[test]
+    fn test_abandons_transactions_not_relayed() {
+        let mut broadcaster = Broadcaster::new();
+        let tx = transaction(3);
+        let txid = tx.compute_txid();
+        broadcaster.add(TxBroadcast::new(tx, TxBroadcastPolicy::AllPeers));
+        let mut now = Instant::now();
+        broadcaster.due(now);
+        assert_eq!(
+            broadcaster.requested(&txid).map(|(_, first)| first),
+            Some(true)
+        );
+        for _ in 1..MAX_ANNOUNCEMENTS {
+            now += REBROADCAST_INTERVAL;
+            assert_eq!(broadcaster.due(now).len(), 1);
+        }
+        now += REBROADCAST_INTERVAL;
+        let failed = broadcaster.abandon(now);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].reason, TxBroadcastFailureReason::NotRelayed);
     }
 }
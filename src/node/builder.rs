@@ -1,10 +1,14 @@
 use std::{collections::HashSet, net::IpAddr, path::PathBuf};
 
-use bitcoin::{Network, ScriptBuf};
+use bitcoin::{p2p::address::AddrV2, Network, ScriptBuf};
 
 use crate::{
     chain::checkpoints::HeaderCheckpoint,
-    db::traits::{HeaderStore, PeerStore},
+    db::traits::{
+        FilterHeaderStore, FilterStore, HeaderStore, OutPointStore, PeerStore, ProgressStore,
+    },
+    peers::addrv2::from_ip,
+    ConnectionMode, PeerNetwork, TransportPreference,
 };
 
 use super::{client::Client, config::NodeConfig, node::Node};
@@ -25,8 +29,22 @@ impl NodeBuilder {
     }
 
     /// Add preferred and most likely trusted peers to try to connect to.
-    pub fn add_peers(mut self, whitelist: Vec<(IpAddr, u16)>) -> Self {
-        self.config.white_list = Some(whitelist);
+    pub fn add_peers(self, whitelist: Vec<(IpAddr, u16)>) -> Self {
+        self.add_network_peers(
+            whitelist
+                .into_iter()
+                .map(|(ip, port)| (from_ip(ip), port))
+                .collect(),
+        )
+    }
+
+    /// Add preferred peers on any [BIP-155](https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki) network,
+    /// such as Tor peers that are reached through a SOCKS5 proxy.
+    pub fn add_network_peers(mut self, whitelist: Vec<(AddrV2, u16)>) -> Self {
+        self.config
+            .white_list
+            .get_or_insert_with(Vec::new)
+            .extend(whitelist);
         self
     }
 
@@ -57,24 +75,109 @@ impl NodeBuilder {
         self
     }
 
+    /// Set the transport used to communicate with peers. By default, the encrypted [BIP-324](https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki)
+    /// transport is attempted, falling back to the unencrypted V1 transport if the peer does not support it.
+    pub fn transport(mut self, transport: TransportPreference) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    /// Set how connections to peers are made. By default, peers are connected to directly. Connecting through a
+    /// SOCKS5 proxy, like the one exposed by a Tor daemon, allows for connections to Tor peers.
+    pub fn connection_mode(mut self, connection: ConnectionMode) -> Self {
+        self.config.connection = connection;
+        self
+    }
+
+    /// Only select peers from the database that are on these networks, for instance to connect exclusively to
+    /// Tor peers. Networks that cannot be reached with the [`ConnectionMode`] are never selected. By default,
+    /// peers are selected from every reachable network.
+    pub fn target_networks(mut self, networks: Vec<PeerNetwork>) -> Self {
+        self.config.target_networks = Some(networks);
+        self
+    }
+
+    /// Persist the compact filter headers, so the node may resume syncing filter headers where it stopped.
+    /// By default, [`NodeBuilder::build_node`] uses a SQLite database, and [`NodeBuilder::build_node_with_custom_databases`]
+    /// keeps the filter headers in memory.
+    pub fn add_filter_header_store(
+        mut self,
+        filter_header_store: impl FilterHeaderStore + Send + Sync + 'static,
+    ) -> Self {
+        self.config.filter_header_store = Some(Box::new(filter_header_store));
+        self
+    }
+
+    /// Persist the compact block filters, so rescans for new scripts are ran against the stored filters and
+    /// only the matching blocks are downloaded. By default, [`NodeBuilder::build_node`] uses a SQLite database,
+    /// and [`NodeBuilder::build_node_with_custom_databases`] downloads the filters again for every rescan.
+    pub fn add_filter_store(
+        mut self,
+        filter_store: impl FilterStore + Send + Sync + 'static,
+    ) -> Self {
+        self.config.filter_store = Some(Box::new(filter_store));
+        self
+    }
+
+    /// Persist the outputs that pay to the added scripts, so the node may detect when they are spent
+    /// across restarts. By default, these outputs are only kept in memory.
+    pub fn add_outpoint_store(
+        mut self,
+        outpoint_store: impl OutPointStore + Send + Sync + 'static,
+    ) -> Self {
+        self.config.outpoint_store = Some(Box::new(outpoint_store));
+        self
+    }
+
+    /// Persist the last block scanned for the added scripts, along with the scripts themselves, so a restarted
+    /// node resumes scanning where it stopped instead of from the anchor checkpoint. Scripts persisted in a previous
+    /// session are watched again. By default, [`NodeBuilder::build_node`] uses a SQLite database, and
+    /// [`NodeBuilder::build_node_with_custom_databases`] scans from the anchor checkpoint every time the node is ran.
+    pub fn add_progress_store(
+        mut self,
+        progress_store: impl ProgressStore + Send + Sync + 'static,
+    ) -> Self {
+        self.config.progress_store = Some(Box::new(progress_store));
+        self
+    }
+
     /// Consume the node builder and receive a [`Node`] and [`Client`].
     #[cfg(feature = "database")]
-    pub async fn build_node(&self) -> (Node, Client) {
-        use crate::db::sqlite::{header_db::SqliteHeaderDb, peer_db::SqlitePeerDb};
+    pub async fn build_node(mut self) -> (Node, Client) {
+        use crate::db::sqlite::{
+            filter_db::SqliteFilterDb, filter_header_db::SqliteFilterHeaderDb,
+            header_db::SqliteHeaderDb, peer_db::SqlitePeerDb, progress_db::SqliteProgressDb,
+        };
         let peer_store = SqlitePeerDb::new(self.network, self.config.data_path.clone()).unwrap();
         let header_store =
             SqliteHeaderDb::new(self.network, self.config.data_path.clone()).unwrap();
-        Node::new_from_config(&self.config, self.network, peer_store, header_store)
+        if self.config.filter_header_store.is_none() {
+            let filter_header_store =
+                SqliteFilterHeaderDb::new(self.network, self.config.data_path.clone()).unwrap();
+            self.config.filter_header_store = Some(Box::new(filter_header_store));
+        }
+        if self.config.filter_store.is_none() {
+            let filter_store =
+                SqliteFilterDb::new(self.network, self.config.data_path.clone()).unwrap();
+            self.config.filter_store = Some(Box::new(filter_store));
+        }
+        if self.config.progress_store.is_none() {
+            let progress_store =
+                SqliteProgressDb::new(self.network, self.config.data_path.clone()).unwrap();
+            self.config.progress_store = Some(Box::new(progress_store));
+        }
+        Node::new_from_config(self.config, self.network, peer_store, header_store)
             .await
             .unwrap()
     }
 
+    /// Consume the node builder and receive a [`Node`] and [`Client`], using the provided databases.
     pub async fn build_node_with_custom_databases(
-        &self,
+        self,
         peer_store: impl PeerStore + Send + Sync + 'static,
         header_store: impl HeaderStore + Send + Sync + 'static,
     ) -> (Node, Client) {
-        Node::new_from_config(&self.config, self.network, peer_store, header_store)
+        Node::new_from_config(self.config, self.network, peer_store, header_store)
             .await
             .unwrap()
     }
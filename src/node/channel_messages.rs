@@ -1,10 +1,15 @@
+use std::time::Duration;
+
+use super::misbehavior::Misbehavior;
+
 use bitcoin::{
     block::Header,
     p2p::{
+        address::{AddrV2, AddrV2Message},
         message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
-        Address, ServiceFlags,
+        ServiceFlags,
     },
-    Block, BlockHash, Transaction,
+    Block, BlockHash, Transaction, Txid,
 };
 
 #[derive(Debug, Clone)]
@@ -15,7 +20,9 @@ pub(crate) enum MainThreadMessage {
     GetFilters(GetCFilters),
     GetBlock(GetBlockConfig),
     Disconnect,
-    BroadcastTx(Transaction),
+    AnnounceTx(Txid),
+    SendTx(Transaction),
+    RequestTx(Txid),
     // more messages
 }
 
@@ -38,16 +45,30 @@ pub(crate) struct PeerThreadMessage {
 #[derive(Debug)]
 pub(crate) enum PeerMessage {
     Version(RemoteVersion),
-    Addr(Vec<Address>),
+    Addr(Vec<AddrV2Message>),
     Headers(Vec<Header>),
     FilterHeaders(CFHeaders),
     Filter(CFilter),
     Block(Block),
     NewBlocks(Vec<BlockHash>),
+    BlocksNotFound(Vec<BlockHash>),
+    TxRequests(Vec<Txid>),
+    Transaction(Transaction),
     Disconnect,
     Verack,
     Ping(u64),
     Pong(u64),
+    // The round trip time of a ping
+    Latency(Duration),
+    // A transaction was written to the connection
+    TxWritten(Txid),
+    // The peer broke the protocol and the connection was closed
+    Misbehaved {
+        misbehavior: Misbehavior,
+        addr: AddrV2,
+        port: u16,
+        services: ServiceFlags,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
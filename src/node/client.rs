@@ -1,13 +1,19 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    sync::{atomic::AtomicI64, Arc},
+};
 
 use bitcoin::ScriptBuf;
-use tokio::sync::broadcast;
-pub use tokio::sync::broadcast::Receiver;
-pub use tokio::sync::mpsc::Sender;
+pub use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    RwLock,
+};
 
-use crate::{IndexedBlock, IndexedTransaction, TxBroadcast};
+use crate::{chain::checkpoints::HeaderCheckpoint, IndexedBlock, IndexedTransaction, TxBroadcast};
 
 use super::{
+    dialog::SUBSCRIBER_CAPACITY,
     error::ClientError,
     messages::{ClientMessage, NodeMessage},
 };
@@ -15,13 +21,25 @@ use super::{
 /// A [`Client`] allows for communication with a running node.
 #[derive(Debug, Clone)]
 pub struct Client {
-    nrx: broadcast::Sender<NodeMessage>,
+    subscribe: UnboundedSender<Sender<NodeMessage>>,
     ntx: Sender<ClientMessage>,
+    last_synced: Arc<RwLock<Option<HeaderCheckpoint>>>,
+    time_offset: Arc<AtomicI64>,
 }
 
 impl Client {
-    pub(crate) fn new(nrx: broadcast::Sender<NodeMessage>, ntx: Sender<ClientMessage>) -> Self {
-        Self { nrx, ntx }
+    pub(crate) fn new(
+        subscribe: UnboundedSender<Sender<NodeMessage>>,
+        ntx: Sender<ClientMessage>,
+        last_synced: Arc<RwLock<Option<HeaderCheckpoint>>>,
+        time_offset: Arc<AtomicI64>,
+    ) -> Self {
+        Self {
+            subscribe,
+            ntx,
+            last_synced,
+            time_offset,
+        }
     }
 
     /// For a majority of cases, some parts of your program will respond to node events, and other parts of the program
@@ -37,14 +55,34 @@ impl Client {
     }
 
     /// Return a [`Receiver`] to listen for incoming node events.
+    /// Every transaction, block, reorganization and sync event is delivered to every receiver. If a receiver is full,
+    /// the node waits for it to make room, so a receiver must be read continually or dropped. Dialog and warnings
+    /// are instead dropped for a full receiver, and [`NodeMessage::Lagged`] reports how many were missed.
     /// You may call this function as many times as required, however please note
     /// there are memory and performance implications when calling this method. Namely, a clone of the object,
-    /// potentially a large data structure like a [`crate::Block`], is held in memory for _every_ receiver until _all_
-    /// receivers have gotten the message.
+    /// potentially a large data structure like a [`crate::Block`], is held in memory for _every_ receiver until
+    /// that receiver has gotten the message.
     /// You should only call this twice if two separate portions of your application need to process
     /// data differently. For example, a Lightning Network node implementation.
     pub fn receiver(&mut self) -> Receiver<NodeMessage> {
-        self.nrx.subscribe()
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        // The node only stops listening for receivers when it is dropped
+        let _ = self.subscribe.send(tx);
+        rx
+    }
+
+    /// The last block where every block up to and including it was scanned for the watched scripts,
+    /// if the node has synced in this session or resumed from a previous one. When the node is restarted
+    /// with the same scripts, only the blocks after this checkpoint are scanned.
+    pub async fn last_synced(&self) -> Option<HeaderCheckpoint> {
+        *self.last_synced.read().await
+    }
+
+    /// The number of seconds the clocks of connected peers are ahead of the clock of this device, taken as the median
+    /// of the times peers report when connecting. Block headers more than two hours past this network adjusted time
+    /// are rejected. The offset is zero if the clock of this device differs from peers by more than 70 minutes.
+    pub fn time_offset(&self) -> i64 {
+        self.time_offset.load(std::sync::atomic::Ordering::Relaxed)
     }
 
     /// Tell the node to stop running.
@@ -62,19 +100,15 @@ impl Client {
     /// such as Lightning Network nodes, which are expected to be online for long durations.
     pub async fn collect_relevant_tx(&mut self) -> Vec<IndexedTransaction> {
         let mut txs = Vec::new();
-        let mut rec = self.nrx.subscribe();
-        loop {
-            while let Ok(message) = rec.recv().await {
-                match message {
-                    NodeMessage::Transaction(tx) => txs.push(tx),
-                    NodeMessage::Synced(_) => {
-                        drop(rec);
-                        return txs;
-                    }
-                    _ => (),
-                }
+        let mut rec = self.receiver();
+        while let Some(message) = rec.recv().await {
+            match message {
+                NodeMessage::Transaction(tx) => txs.push(tx),
+                NodeMessage::Synced(_) => break,
+                _ => (),
             }
         }
+        txs
     }
 
     /// Collect the blocks received from the node into an in-memory cache,
@@ -83,31 +117,24 @@ impl Client {
     /// such as a server or desktop computer.
     /// For devices like smart phones, see [`Client::collect_relevant_tx`].
     pub async fn collect_relevant_blocks(&mut self) -> Vec<IndexedBlock> {
-        let mut rec = self.nrx.subscribe();
+        let mut rec = self.receiver();
         let mut blocks = Vec::new();
-        loop {
-            while let Ok(message) = rec.recv().await {
-                match message {
-                    NodeMessage::Block(block) => blocks.push(block),
-                    NodeMessage::Synced(_) => {
-                        drop(rec);
-                        return blocks;
-                    }
-                    _ => (),
-                }
+        while let Some(message) = rec.recv().await {
+            match message {
+                NodeMessage::Block(block) => blocks.push(block),
+                NodeMessage::Synced(_) => break,
+                _ => (),
             }
         }
+        blocks
     }
 
     /// Wait until the client's headers and filters are fully synced to connected peers, dropping any block and transaction messages.
     pub async fn wait_until_synced(&mut self) {
-        let mut rec = self.nrx.subscribe();
-        loop {
-            while let Ok(message) = rec.recv().await {
-                if let NodeMessage::Synced(_) = message {
-                    drop(rec);
-                    return;
-                }
+        let mut rec = self.receiver();
+        while let Some(message) = rec.recv().await {
+            if let NodeMessage::Synced(_) = message {
+                return;
             }
         }
     }
@@ -115,18 +142,19 @@ impl Client {
     /// Print a stream of logs to the console. This function continually loops for the duration of the program, and is not particularly helpful in production applications.
     /// See [`Client::receiver`] to listen for events from the node.
     pub async fn print_log_stream(&mut self) {
-        let mut rec = self.nrx.subscribe();
-        loop {
-            while let Ok(message) = rec.recv().await {
-                match message {
-                    NodeMessage::Dialog(message) => {
-                        println!("\x1b[32mInfo\x1b[0m {}", message);
-                    }
-                    NodeMessage::Warning(message) => {
-                        println!("\x1b[93mWarn\x1b[0m {}", message);
-                    }
-                    _ => (),
+        let mut rec = self.receiver();
+        while let Some(message) = rec.recv().await {
+            match message {
+                NodeMessage::Dialog(message) => {
+                    println!("\x1b[32mInfo\x1b[0m {}", message);
+                }
+                NodeMessage::Warning(message) => {
+                    println!("\x1b[93mWarn\x1b[0m {}", message);
+                }
+                NodeMessage::Lagged(num_dropped) => {
+                    println!("\x1b[93mWarn\x1b[0m {} messages were dropped", num_dropped);
                 }
+                _ => (),
             }
         }
     }
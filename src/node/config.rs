@@ -1,15 +1,26 @@
-use std::{collections::HashSet, net::IpAddr, path::PathBuf};
+use std::{collections::HashSet, path::PathBuf};
 
-use bitcoin::ScriptBuf;
+use bitcoin::{p2p::address::AddrV2, ScriptBuf};
 
-use crate::chain::checkpoints::HeaderCheckpoint;
+use crate::{
+    chain::checkpoints::HeaderCheckpoint,
+    db::traits::{FilterHeaderStore, FilterStore, OutPointStore, ProgressStore},
+    ConnectionMode, PeerNetwork, TransportPreference,
+};
 
 pub(crate) struct NodeConfig {
     pub required_peers: u8,
-    pub white_list: Option<Vec<(IpAddr, u16)>>,
+    pub white_list: Option<Vec<(AddrV2, u16)>>,
     pub addresses: HashSet<ScriptBuf>,
     pub data_path: Option<PathBuf>,
     pub header_checkpoint: Option<HeaderCheckpoint>,
+    pub transport: TransportPreference,
+    pub connection: ConnectionMode,
+    pub target_networks: Option<Vec<PeerNetwork>>,
+    pub filter_header_store: Option<Box<dyn FilterHeaderStore + Send + Sync>>,
+    pub filter_store: Option<Box<dyn FilterStore + Send + Sync>>,
+    pub outpoint_store: Option<Box<dyn OutPointStore + Send + Sync>>,
+    pub progress_store: Option<Box<dyn ProgressStore + Send + Sync>>,
 }
 
 impl Default for NodeConfig {
@@ -20,6 +31,13 @@ impl Default for NodeConfig {
             addresses: Default::default(),
             data_path: Default::default(),
             header_checkpoint: Default::default(),
+            transport: TransportPreference::V2WithFallback,
+            connection: ConnectionMode::Direct,
+            target_networks: None,
+            filter_header_store: None,
+            filter_store: None,
+            outpoint_store: None,
+            progress_store: None,
         }
     }
 }
@@ -1,19 +1,57 @@
-use tokio::sync::broadcast::Sender;
+use std::sync::Arc;
+
+use tokio::sync::{
+    mpsc::{error::TrySendError, Sender, UnboundedReceiver},
+    Mutex,
+};
 
 use super::messages::NodeMessage;
 
+// The number of messages a receiver may hold before the node waits on it, or drops dialog for it
+pub(crate) const SUBSCRIBER_CAPACITY: usize = 128;
+
+#[derive(Debug)]
+struct Subscriber {
+    tx: Sender<NodeMessage>,
+    // Dialog and warnings dropped since the receiver last had room
+    dropped: u64,
+}
+
+#[derive(Debug)]
+struct Subscribers {
+    new_subscribers: UnboundedReceiver<Sender<NodeMessage>>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    // Receivers created by a client since we last sent a message
+    fn add_new_subscribers(&mut self) {
+        while let Ok(tx) = self.new_subscribers.try_recv() {
+            self.subscribers.push(Subscriber { tx, dropped: 0 });
+        }
+    }
+}
+
+// Every receiver has a separate queue. Data like transactions, blocks and reorganizations is always delivered,
+// waiting for a full receiver to make room. Human readable dialog is dropped for a full receiver, which is told
+// how many messages it missed once it has room again.
 #[derive(Debug, Clone)]
 pub(crate) struct Dialog {
-    ntx: Sender<NodeMessage>,
+    subscribers: Arc<Mutex<Subscribers>>,
 }
 
 impl Dialog {
-    pub(crate) fn new(ntx: Sender<NodeMessage>) -> Self {
-        Self { ntx }
+    pub(crate) fn new(new_subscribers: UnboundedReceiver<Sender<NodeMessage>>) -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(Subscribers {
+                new_subscribers,
+                subscribers: Vec::new(),
+            })),
+        }
     }
 
     pub(crate) async fn send_dialog(&mut self, dialog: String) {
-        let _ = self.ntx.send(NodeMessage::Dialog(dialog));
+        self.offer(NodeMessage::Dialog(dialog)).await;
     }
 
     pub(crate) async fn chain_update(
@@ -27,14 +65,135 @@ impl Dialog {
             "Headers ({}/{}) Compact Filter Headers ({}/{}) Filters ({}/{})",
             num_headers, best_height, num_cf_headers, best_height, num_filters, best_height
         );
-        let _ = self.ntx.send(NodeMessage::Dialog(message));
+        self.offer(NodeMessage::Dialog(message)).await;
     }
 
     pub(crate) async fn send_warning(&mut self, warning: String) {
-        let _ = self.ntx.send(NodeMessage::Warning(warning));
+        self.offer(NodeMessage::Warning(warning)).await;
     }
 
     pub(crate) async fn send_data(&mut self, message: NodeMessage) {
-        let _ = self.ntx.send(message);
+        let mut lock = self.subscribers.lock().await;
+        lock.add_new_subscribers();
+        let mut closed = Vec::new();
+        for (index, subscriber) in lock.subscribers.iter_mut().enumerate() {
+            if subscriber.dropped > 0 {
+                if subscriber
+                    .tx
+                    .send(NodeMessage::Lagged(subscriber.dropped))
+                    .await
+                    .is_err()
+                {
+                    closed.push(index);
+                    continue;
+                }
+                subscriber.dropped = 0;
+            }
+            if subscriber.tx.send(message.clone()).await.is_err() {
+                closed.push(index);
+            }
+        }
+        for index in closed.into_iter().rev() {
+            lock.subscribers.remove(index);
+        }
+    }
+
+    // Send a message to every receiver with room for it
+    async fn offer(&mut self, message: NodeMessage) {
+        let mut lock = self.subscribers.lock().await;
+        lock.add_new_subscribers();
+        lock.subscribers.retain_mut(|subscriber| {
+            if subscriber.dropped > 0 {
+                match subscriber
+                    .tx
+                    .try_send(NodeMessage::Lagged(subscriber.dropped))
+                {
+                    Ok(_) => subscriber.dropped = 0,
+                    Err(TrySendError::Full(_)) => {
+                        subscriber.dropped += 1;
+                        return true;
+                    }
+                    Err(TrySendError::Closed(_)) => return false,
+                }
+            }
+            match subscriber.tx.try_send(message.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.dropped += 1;
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, BlockHash};
+    use tokio::sync::mpsc;
+
+    use crate::chain::checkpoints::HeaderCheckpoint;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drops_dialog_for_full_receivers() {
+        let (subscribe, new_subscribers) = mpsc::unbounded_channel();
+        let mut dialog = Dialog::new(new_subscribers);
+        let (tx, mut rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        subscribe.send(tx).unwrap();
+        for _ in 0..SUBSCRIBER_CAPACITY + 10 {
+            dialog.send_dialog("dialog".into()).await;
+        }
+        for _ in 0..SUBSCRIBER_CAPACITY {
+            assert!(matches!(rx.recv().await, Some(NodeMessage::Dialog(_))));
+        }
+        dialog.send_warning("warning".into()).await;
+        assert!(matches!(rx.recv().await, Some(NodeMessage::Lagged(10))));
+        assert!(matches!(rx.recv().await, Some(NodeMessage::Warning(_))));
+    }
+
+    #[tokio::test]
+    async fn test_delivers_every_data_message() {
+        let (subscribe, new_subscribers) = mpsc::unbounded_channel();
+        let mut dialog = Dialog::new(new_subscribers);
+        let (tx, mut rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        subscribe.send(tx).unwrap();
+        let num_messages = SUBSCRIBER_CAPACITY as u32 * 4;
+        let sender = tokio::spawn(async move {
+            for height in 0..num_messages {
+                dialog
+                    .send_data(NodeMessage::Synced(HeaderCheckpoint::new(
+                        height,
+                        BlockHash::all_zeros(),
+                    )))
+                    .await;
+            }
+        });
+        for height in 0..num_messages {
+            match rx.recv().await {
+                Some(NodeMessage::Synced(checkpoint)) => assert_eq!(checkpoint.height, height),
+                _ => panic!("expected every checkpoint in order"),
+            }
+        }
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_removes_dropped_receivers() {
+        let (subscribe, new_subscribers) = mpsc::unbounded_channel();
+        let mut dialog = Dialog::new(new_subscribers);
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        subscribe.send(tx).unwrap();
+        drop(rx);
+        // Data is not held for a receiver that no longer exists
+        dialog
+            .send_data(NodeMessage::Synced(HeaderCheckpoint::new(
+                0,
+                BlockHash::all_zeros(),
+            )))
+            .await;
+        assert!(dialog.subscribers.lock().await.subscribers.is_empty());
     }
 }
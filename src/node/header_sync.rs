@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// A peer with more headers to send fills a message with this many
+const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+// The sync peer must extend our chain within this time, or we switch to another peer
+const HEADER_PROGRESS_TIMEOUT: Duration = Duration::from_secs(30);
+// A peer may serve a few blocks less than it advertised if its tip was reorganized after the handshake
+const MAX_HEIGHT_SHORTFALL: u32 = 6;
+
+// What the headers sent by the sync peer tell us about its chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SyncProgress {
+    // The peer may have more headers to send
+    Ongoing,
+    // The peer sent every header it has
+    Exhausted,
+    // The peer sent every header it has, far short of the height it advertised
+    OverstatedHeight { advertised: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SyncPeer {
+    nonce: u32,
+    deadline: Instant,
+}
+
+// Headers are requested from a single peer at a time. The peer advertising the most blocks is selected and
+// replaced if it stops extending our chain.
+#[derive(Debug)]
+pub(crate) struct HeaderSync {
+    peer: Option<SyncPeer>,
+}
+
+impl HeaderSync {
+    pub(crate) fn new() -> Self {
+        Self { peer: None }
+    }
+
+    pub(crate) fn sync_peer(&self) -> Option<u32> {
+        self.peer.map(|peer| peer.nonce)
+    }
+
+    // Select the peer advertising the greatest height if the sync peer disconnected. Returns the new sync peer.
+    pub(crate) fn select(&mut self, heights: &HashMap<u32, u32>, now: Instant) -> Option<u32> {
+        if let Some(peer) = self.peer {
+            if heights.contains_key(&peer.nonce) {
+                return None;
+            }
+        }
+        self.peer = heights
+            .iter()
+            .max_by_key(|(nonce, height)| (**height, std::cmp::Reverse(**nonce)))
+            .map(|(nonce, _)| SyncPeer {
+                nonce: *nonce,
+                deadline: now + HEADER_PROGRESS_TIMEOUT,
+            });
+        self.sync_peer()
+    }
+
+    // The sync peer sent a message with this many headers, and our chain is now at this height
+    pub(crate) fn received(
+        &mut self,
+        num_headers: usize,
+        advertised: u32,
+        height: u32,
+        extended: bool,
+        now: Instant,
+    ) -> SyncProgress {
+        if let Some(peer) = self.peer.as_mut() {
+            if extended {
+                peer.deadline = now + HEADER_PROGRESS_TIMEOUT;
+            }
+        }
+        if num_headers.ge(&MAX_HEADERS_PER_MESSAGE) {
+            return SyncProgress::Ongoing;
+        }
+        if advertised.gt(&(height + MAX_HEIGHT_SHORTFALL)) {
+            SyncProgress::OverstatedHeight { advertised }
+        } else {
+            SyncProgress::Exhausted
+        }
+    }
+
+    // Give up on a sync peer that did not extend our chain in time, returning the stalled peer
+    pub(crate) fn stalled(&mut self, now: Instant) -> Option<u32> {
+        match self.peer {
+            Some(peer) if now.gt(&peer.deadline) => {
+                self.peer = None;
+                Some(peer.nonce)
+            }
+            _ => None,
+        }
+    }
+
+    // Headers are no longer requested from the sync peer
+    pub(crate) fn clear(&mut self) {
+        self.peer = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selects_the_highest_peer() {
+        let mut sync = HeaderSync::new();
+        let now = Instant::now();
+        assert_eq!(sync.select(&HashMap::new(), now), None);
+        let heights = HashMap::from([(1, 100), (2, 120), (3, 120)]);
+        assert_eq!(sync.select(&heights, now), Some(2));
+        // The sync peer is kept while it is connected
+        assert_eq!(sync.select(&heights, now), None);
+        let heights = HashMap::from([(1, 100), (3, 120)]);
+        assert_eq!(sync.select(&heights, now), Some(3));
+        assert_eq!(sync.sync_peer(), Some(3));
+    }
+
+    #[test]
+    fn test_switches_peers_after_a_stall() {
+        let mut sync = HeaderSync::new();
+        let now = Instant::now();
+        let heights = HashMap::from([(1, 100), (2, 100)]);
+        assert_eq!(sync.select(&heights, now), Some(1));
+        let later = now + HEADER_PROGRESS_TIMEOUT - Duration::from_secs(1);
+        assert_eq!(
+            sync.received(MAX_HEADERS_PER_MESSAGE, 100, 10, true, later),
+            SyncProgress::Ongoing
+        );
+        // Progress pushes the deadline back
+        assert_eq!(sync.stalled(now + HEADER_PROGRESS_TIMEOUT), None);
+        // Headers we already have are not progress
+        sync.received(MAX_HEADERS_PER_MESSAGE, 100, 10, false, later);
+        let stall = later + HEADER_PROGRESS_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(sync.stalled(stall), Some(1));
+        assert_eq!(sync.sync_peer(), None);
+        let heights = HashMap::from([(2, 100)]);
+        assert_eq!(sync.select(&heights, stall), Some(2));
+    }
+
+    #[test]
+    fn test_detects_overstated_heights() {
+        let mut sync = HeaderSync::new();
+        let now = Instant::now();
+        sync.select(&HashMap::from([(1, 100)]), now);
+        assert_eq!(
+            sync.received(0, 100, 100, false, now),
+            SyncProgress::Exhausted
+        );
+        // A peer may be a few blocks short of the height it advertised
+        assert_eq!(
+            sync.received(10, 100, 100 - MAX_HEIGHT_SHORTFALL, true, now),
+            SyncProgress::Exhausted
+        );
+        assert_eq!(
+            sync.received(10, 100, 50, true, now),
+            SyncProgress::OverstatedHeight { advertised: 100 }
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashSet;
 
-use bitcoin::ScriptBuf;
+use bitcoin::{ScriptBuf, Txid};
 
 use crate::{
     chain::checkpoints::HeaderCheckpoint, DisconnectedHeader, IndexedBlock, IndexedTransaction,
@@ -22,8 +22,47 @@ pub enum NodeMessage {
     Synced(HeaderCheckpoint),
     /// Blocks were reorganized out of the chain
     BlocksDisconnected(Vec<DisconnectedHeader>),
-    /// A problem occured sending a transaction.
-    TxBroadcastFailure,
+    /// A peer requested a transaction we announced, so it was sent to the network.
+    TxSent(Txid),
+    /// A transaction was sent to a peer over a connection opened only for this transaction.
+    TxSentPrivately(Txid),
+    /// A transaction we broadcast was found in the mempool of a peer.
+    TxSeen(Txid),
+    /// A transaction we broadcast was found in a block and will no longer be rebroadcast. Until it is confirmed,
+    /// the block filters are also checked for the outputs of the transaction, so the block that includes it is
+    /// downloaded even if the transaction pays none of the watched scripts. A transaction with only `OP_RETURN`
+    /// outputs cannot be matched by a filter, so it is never reported as confirmed.
+    TxConfirmed(IndexedTransaction),
+    /// A transaction was abandoned because no peer took it from us.
+    TxBroadcastFailure(FailedBroadcast),
+    /// The receiver fell behind, so this many [`NodeMessage::Dialog`] and [`NodeMessage::Warning`] messages were dropped.
+    /// All other messages are always delivered.
+    Lagged(u64),
+}
+
+/// A transaction that could not be broadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailedBroadcast {
+    /// The transaction ID of the abandoned transaction.
+    pub txid: Txid,
+    /// Why the transaction was abandoned.
+    pub reason: TxBroadcastFailureReason,
+}
+
+impl FailedBroadcast {
+    pub(crate) fn new(txid: Txid, reason: TxBroadcastFailureReason) -> Self {
+        Self { txid, reason }
+    }
+}
+
+/// The reason a transaction broadcast was abandoned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxBroadcastFailureReason {
+    /// The transaction was announced repeatedly and no peer requested it.
+    NotRequested,
+    /// Peers requested the transaction but it was never found in their mempools, so it was most likely
+    /// rejected.
+    NotRelayed,
 }
 
 /// Commands to issue a node.
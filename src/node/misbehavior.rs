@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use bitcoin::p2p::address::AddrV2;
+
+// A peer is banned once its score reaches this threshold
+const BAN_THRESHOLD: u32 = 100;
+
+// The protocol violations we punish a peer for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Misbehavior {
+    // Headers that break a checkpoint or a consensus rule
+    InvalidHeaders,
+    // Filter headers that do not connect to the filter header before them
+    InvalidFilterHeaders,
+    // A filter that does not match the filter hash its peers committed to
+    InvalidFilter,
+    // A filter proven false by the block it commits to
+    FalseFilter,
+    // Headers that end far short of the height the peer advertised
+    OverstatedHeight,
+    // More responses than we requested
+    UnsolicitedMessages,
+    // Messages faster than our rate limit
+    MessageFlood,
+    // Data we requested that never arrived, or a response that it was not found
+    UndeliveredRequest,
+}
+
+impl Misbehavior {
+    // Provably invalid data warrants a ban outright, while other violations may be honest mistakes
+    fn score(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidHeaders
+            | Misbehavior::InvalidFilterHeaders
+            | Misbehavior::InvalidFilter
+            | Misbehavior::FalseFilter => BAN_THRESHOLD,
+            Misbehavior::OverstatedHeight | Misbehavior::MessageFlood => 50,
+            Misbehavior::UnsolicitedMessages | Misbehavior::UndeliveredRequest => 20,
+        }
+    }
+}
+
+impl std::fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Misbehavior::InvalidHeaders => write!(f, "sent invalid headers"),
+            Misbehavior::InvalidFilterHeaders => write!(f, "sent invalid filter headers"),
+            Misbehavior::InvalidFilter => write!(f, "sent an invalid filter"),
+            Misbehavior::FalseFilter => write!(f, "sent a false compact filter"),
+            Misbehavior::OverstatedHeight => write!(f, "advertised blocks it does not have"),
+            Misbehavior::UnsolicitedMessages => write!(f, "sent unsolicited messages"),
+            Misbehavior::MessageFlood => write!(f, "flooded us with messages"),
+            Misbehavior::UndeliveredRequest => write!(f, "did not deliver the data we requested"),
+        }
+    }
+}
+
+// The misbehavior of peers over the life of the node, kept by address so a peer cannot reset its score by
+// reconnecting
+#[derive(Debug, Default)]
+pub(crate) struct PeerScores {
+    scores: HashMap<AddrV2, u32>,
+}
+
+impl PeerScores {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    // Add to the score of a peer. Returns true if the peer should be banned.
+    pub(crate) fn punish(&mut self, addr: AddrV2, misbehavior: Misbehavior) -> bool {
+        let score = self.scores.entry(addr.clone()).or_default();
+        *score += misbehavior.score();
+        if (*score).ge(&BAN_THRESHOLD) {
+            // The ban is persisted, so the score starts over once the ban expires
+            self.scores.remove(&addr);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_bans_at_the_threshold() {
+        let mut scores = PeerScores::new();
+        let addr = AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
+        let other = AddrV2::Ipv4(Ipv4Addr::new(2, 2, 2, 2));
+        assert!(scores.punish(addr.clone(), Misbehavior::InvalidHeaders));
+        for _ in 0..4 {
+            assert!(!scores.punish(addr.clone(), Misbehavior::UnsolicitedMessages));
+        }
+        assert!(!scores.punish(other.clone(), Misbehavior::MessageFlood));
+        assert!(scores.punish(addr.clone(), Misbehavior::UnsolicitedMessages));
+        assert!(scores.punish(other, Misbehavior::OverstatedHeight));
+        // A score starts over after a ban
+        assert!(!scores.punish(addr, Misbehavior::OverstatedHeight));
+    }
+}
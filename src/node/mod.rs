@@ -9,9 +9,12 @@ pub(crate) mod config;
 pub(crate) mod dialog;
 /// Errors associated with a node.
 pub mod error;
+mod header_sync;
 /// Messages the node may send a client.
 pub mod messages;
+pub(crate) mod misbehavior;
 #[allow(clippy::module_inception)]
 /// The structure that communicates with the Bitcoin P2P network and collects data.
 pub mod node;
+mod one_shot;
 mod peer_map;
@@ -1,19 +1,22 @@
 use std::{
     collections::HashSet,
-    net::IpAddr,
-    sync::{atomic::AtomicBool, Arc},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicI64},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use bitcoin::{
     block::Header,
     p2p::{
+        address::{AddrV2, AddrV2Message},
         message_filter::{CFHeaders, CFilter},
-        Address, ServiceFlags,
+        ServiceFlags,
     },
-    Block, Network, ScriptBuf,
+    Block, Network, ScriptBuf, Transaction, Txid,
 };
-use tokio::sync::{broadcast, mpsc::Receiver, Mutex, RwLock};
+use tokio::sync::{mpsc::Receiver, Mutex, RwLock};
 use tokio::{
     select,
     sync::mpsc::{self},
@@ -27,10 +30,15 @@ use crate::{
     },
     db::{
         peer_man::PeerManager,
-        traits::{HeaderStore, PeerStore},
+        traits::{
+            FilterHeaderStore, FilterStore, HeaderStore, OutPointStore, PeerStore, ProgressStore,
+        },
     },
     filters::cfheader_chain::CFHeaderSyncResult,
+    filters::error::{CFHeaderSyncError, CFilterSyncError},
     node::{error::PersistenceError, peer_map::PeerMap},
+    prelude::MAX_TIME_ADJUSTMENT,
+    ConnectionMode, IndexedTransaction, PeerNetwork, TransportPreference, TxBroadcast,
     TxBroadcastPolicy,
 };
 
@@ -44,10 +52,18 @@ use super::{
     config::NodeConfig,
     dialog::Dialog,
     error::NodeError,
+    header_sync::{HeaderSync, SyncProgress},
     messages::{ClientMessage, NodeMessage},
+    misbehavior::{Misbehavior, PeerScores},
+    one_shot::{OneShot, OneShotOutcome},
 };
 
-type Whitelist = Option<Vec<(IpAddr, u16)>>;
+// The number of peers drawn from the database to find one we are not already connected to
+const MAX_ONE_SHOT_PEER_TRIES: usize = 10;
+// Peers are pinged over idle connections, so a round trip longer than this is a slow peer and not a busy one
+const MAX_PEER_LATENCY: Duration = Duration::from_secs(10);
+
+type Whitelist = Option<Vec<(AddrV2, u16)>>;
 
 /// The state of the node with respect to connected peers.
 #[derive(Debug, Clone, Copy)]
@@ -73,47 +89,111 @@ pub struct Node {
     required_peers: usize,
     white_list: Whitelist,
     network: Network,
+    transport: TransportPreference,
+    connection: ConnectionMode,
     dialog: Dialog,
     client_recv: Receiver<ClientMessage>,
     is_running: AtomicBool,
+    progress_db: Box<dyn ProgressStore + Send + Sync>,
+    last_synced: Arc<RwLock<Option<HeaderCheckpoint>>>,
+    time_offset: Arc<AtomicI64>,
+    tx_broadcaster: Broadcaster,
+    header_sync: HeaderSync,
+    peer_scores: PeerScores,
 }
 
 impl Node {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         network: Network,
         white_list: Whitelist,
         scripts: HashSet<ScriptBuf>,
         header_checkpoint: Option<HeaderCheckpoint>,
         required_peers: usize,
+        transport: TransportPreference,
+        connection: ConnectionMode,
+        target_networks: Option<Vec<PeerNetwork>>,
         peer_store: impl PeerStore + Send + Sync + 'static,
         header_store: impl HeaderStore + Send + Sync + 'static,
+        filter_header_store: Box<dyn FilterHeaderStore + Send + Sync>,
+        filter_store: Box<dyn FilterStore + Send + Sync>,
+        outpoint_store: impl OutPointStore + Send + Sync + 'static,
+        mut progress_store: Box<dyn ProgressStore + Send + Sync>,
     ) -> Result<(Self, Client), NodeError> {
         // Set up a communication channel between the node and client
-        let (ntx, _) = broadcast::channel::<NodeMessage>(32);
+        let (subscribe, new_subscribers) = mpsc::unbounded_channel::<mpsc::Sender<NodeMessage>>();
         let (ctx, crx) = mpsc::channel::<ClientMessage>(5);
-        let client = Client::new(ntx.clone(), ctx);
+        let last_synced = Arc::new(RwLock::new(None));
+        let time_offset = Arc::new(AtomicI64::new(0));
+        let client = Client::new(subscribe, ctx, last_synced.clone(), time_offset.clone());
         // We always assume we are behind
         let state = Arc::new(RwLock::new(NodeState::Behind));
         // Configure the address manager
-        let peer_man = Arc::new(Mutex::new(PeerManager::new(peer_store, &network)));
+        let peer_man = Arc::new(Mutex::new(PeerManager::new(
+            peer_store,
+            &network,
+            connection,
+            target_networks,
+        )));
         // Prepare the header checkpoints for the chain source
         let mut checkpoints = HeaderCheckpoints::new(&network);
         let checkpoint = header_checkpoint.unwrap_or_else(|| checkpoints.last());
         checkpoints.prune_up_to(checkpoint);
         // A structured way to talk to the client
-        let mut dialog = Dialog::new(ntx);
+        let mut dialog = Dialog::new(new_subscribers);
+        // Keep watching the scripts from previous sessions
+        let stored_scripts = match progress_store.load_scripts().await {
+            Ok(scripts) => scripts,
+            Err(_) => {
+                dialog
+                    .send_warning("Could not load the watched scripts from the database".into())
+                    .await;
+                HashSet::new()
+            }
+        };
+        let progress = match progress_store.load_checkpoint().await {
+            Ok(progress) => progress,
+            Err(_) => {
+                dialog
+                    .send_warning("Could not load the sync progress from the database".into())
+                    .await;
+                None
+            }
+        };
+        // Scripts that were never scanned for require the filters to be scanned from the anchor
+        let has_new_scripts = !scripts.is_subset(&stored_scripts);
+        let mut scripts = scripts;
+        scripts.extend(stored_scripts);
         // Build the chain
-        let loaded_chain = Chain::new(
+        let mut loaded_chain = Chain::new(
             &network,
             scripts,
             checkpoint,
             checkpoints,
             dialog.clone(),
             header_store,
+            filter_header_store,
+            filter_store,
+            outpoint_store,
             required_peers,
         )
         .await
         .map_err(|_| NodeError::LoadError(PersistenceError::HeaderLoadError))?;
+        if let Some(progress) = progress {
+            if has_new_scripts {
+                dialog
+                    .send_dialog("New scripts were added, scanning filters from the anchor".into())
+                    .await;
+            } else if loaded_chain.resume_filters(progress).await {
+                dialog
+                    .send_dialog(format!(
+                        "Resuming the filter scan after block {}",
+                        progress.height
+                    ))
+                    .await;
+                *last_synced.write().await = Some(progress);
+            }
+        }
         // Initialize the height of the chain
         let best_known_height = loaded_chain.height();
         let chain = Arc::new(Mutex::new(loaded_chain));
@@ -128,28 +208,43 @@ impl Node {
                 required_peers,
                 white_list,
                 network,
+                transport,
+                connection,
                 dialog,
                 client_recv: crx,
                 is_running: AtomicBool::new(false),
+                progress_db: progress_store,
+                last_synced,
+                time_offset,
+                tx_broadcaster: Broadcaster::new(),
+                header_sync: HeaderSync::new(),
+                peer_scores: PeerScores::new(),
             },
             client,
         ))
     }
 
     pub(crate) async fn new_from_config(
-        config: &NodeConfig,
+        config: NodeConfig,
         network: Network,
         peer_store: impl PeerStore + Send + Sync + 'static,
         header_store: impl HeaderStore + Send + Sync + 'static,
     ) -> Result<(Self, Client), NodeError> {
         Node::new(
             network,
-            config.white_list.clone(),
-            config.addresses.clone(),
+            config.white_list,
+            config.addresses,
             config.header_checkpoint,
             config.required_peers as usize,
+            config.transport,
+            config.connection,
+            config.target_networks,
             peer_store,
             header_store,
+            config.filter_header_store.unwrap_or_else(|| Box::new(())),
+            config.filter_store.unwrap_or_else(|| Box::new(())),
+            config.outpoint_store.unwrap_or_else(|| Box::new(())),
+            config.progress_store.unwrap_or_else(|| Box::new(())),
         )
         .await
     }
@@ -165,8 +260,8 @@ impl Node {
         self.is_running
             .store(true, std::sync::atomic::Ordering::Relaxed);
         let (mtx, mut mrx) = mpsc::channel::<PeerThreadMessage>(32);
-        let mut node_map = PeerMap::new(mtx, self.network);
-        let mut tx_broadcaster = Broadcaster::new();
+        let mut node_map = PeerMap::new(mtx, self.network, self.transport, self.connection);
+        let (otx, mut orx) = mpsc::channel::<OneShotOutcome>(32);
         loop {
             // Try to advance the state of the node and remove old connections
             self.advance_state().await;
@@ -184,39 +279,19 @@ impl Node {
                     .send_dialog("Not connected to enough peers, finding one...".into())
                     .await;
                 let ip = self.next_peer().await?;
-                node_map.dispatch(ip.0, ip.1).await
+                node_map.dispatch(ip.0, ip.1, ip.2).await
             }
-            // If there are blocks in the queue, we should request them of a random peer
-            if let Some(block_request) = self.pop_block_queue().await {
-                self.dialog
-                    .send_dialog("Sending block request to a random peer".into())
-                    .await;
-                node_map.send_random(block_request).await;
-            }
-            // If we have a transaction to broadcast and we are connected to peers, we should broadcast it
-            if node_map.live().ge(&self.required_peers) && !tx_broadcaster.is_empty() {
-                let transaction = tx_broadcaster.next().unwrap();
-                match transaction.broadcast_policy {
-                    TxBroadcastPolicy::AllPeers => {
-                        self.dialog
-                            .send_dialog(format!(
-                                "Sending transaction to {} connected peers.",
-                                node_map.live()
-                            ))
-                            .await;
-                        node_map
-                            .broadcast(MainThreadMessage::BroadcastTx(transaction.tx))
-                            .await
-                    }
-                    TxBroadcastPolicy::RandomPeer => {
-                        self.dialog
-                            .send_dialog("Sending transaction to a random peer.".into())
-                            .await;
-                        node_map
-                            .send_random(MainThreadMessage::BroadcastTx(transaction.tx))
-                            .await
-                    }
-                }
+            // If we are behind, a single peer should be sending us headers
+            self.sync_headers(&mut node_map).await;
+            // A filter dispute must not hold up the filter headers forever
+            self.check_filter_dispute(&mut node_map).await;
+            // If we need filters, every peer serving them should be sending a range
+            self.request_filters(&mut node_map).await;
+            // If there are blocks in the queue, we should request them of our peers
+            self.request_blocks(&mut node_map).await;
+            // If we have transactions to broadcast and we are connected to peers, we should announce them
+            if node_map.live().ge(&self.required_peers) && !self.tx_broadcaster.is_empty() {
+                self.broadcast_transactions(&mut node_map, &otx).await;
             }
             // Either handle a message from a remote peer or from our client
             select! {
@@ -226,11 +301,13 @@ impl Node {
                             match peer_thread.message {
                                 PeerMessage::Version(version) => {
                                     node_map.set_offset(peer_thread.nonce, version.timestamp);
+                                    self.adjust_time(node_map.median_time_adjustment()).await;
                                     node_map.set_services(peer_thread.nonce, version.service_flags);
                                     node_map.set_height(peer_thread.nonce, version.height as u32);
                                     let best = *node_map.best_height().unwrap_or(&0);
-                                    let response = self.handle_version(version, best).await;
-                                    node_map.send_message(peer_thread.nonce, response).await;
+                                    if let Some(response) = self.handle_version(version, best).await {
+                                        node_map.send_message(peer_thread.nonce, response).await;
+                                    }
                                     self.dialog.send_dialog(format!("[Peer {}]: version", peer_thread.nonce))
                                         .await;
                                 }
@@ -238,37 +315,71 @@ impl Node {
                                 PeerMessage::Headers(headers) => {
                                     self.dialog.send_dialog(format!("[Peer {}]: headers", peer_thread.nonce))
                                         .await;
-                                    match self.handle_headers(headers).await {
-                                        Some(response) => {
+                                    let advertised = node_map.height(peer_thread.nonce);
+                                    match self.handle_headers(peer_thread.nonce, advertised, headers).await {
+                                        Ok(Some(MainThreadMessage::Disconnect)) => {
+                                            node_map.disconnect(peer_thread.nonce).await;
+                                        }
+                                        Ok(Some(response)) => {
                                             node_map.send_message(peer_thread.nonce, response).await;
                                         }
-                                        None => continue,
+                                        Ok(None) => continue,
+                                        Err(misbehavior) => self.misbehaved(&mut node_map, peer_thread.nonce, misbehavior).await,
                                     }
                                 }
                                 PeerMessage::FilterHeaders(cf_headers) => {
                                     self.dialog.send_dialog(format!("[Peer {}]: filter headers", peer_thread.nonce)).await;
                                     match self.handle_cf_headers(peer_thread.nonce, cf_headers).await {
-                                        Some(response) => {
-                                            // match depending on disconnect
+                                        Ok(Some(MainThreadMessage::Disconnect)) => {
+                                            node_map.disconnect(peer_thread.nonce).await;
+                                        }
+                                        Ok(Some(response)) => {
                                             node_map.broadcast(response).await;
                                         }
-                                        None => continue,
+                                        Ok(None) => continue,
+                                        Err(misbehavior) => self.misbehaved(&mut node_map, peer_thread.nonce, misbehavior).await,
                                     }
                                 }
                                 PeerMessage::Filter(filter) => {
                                     match self.handle_filter(peer_thread.nonce, filter).await {
+                                        Ok(Some(MainThreadMessage::Disconnect)) => {
+                                            node_map.disconnect(peer_thread.nonce).await;
+                                        }
+                                        Ok(Some(response)) => {
+                                            node_map.send_message(peer_thread.nonce, response).await;
+                                        }
+                                        Ok(None) => continue,
+                                        Err(misbehavior) => self.misbehaved(&mut node_map, peer_thread.nonce, misbehavior).await,
+                                    }
+                                }
+                                PeerMessage::Block(block) => {
+                                    if self.chain.lock().await.is_disputed(&block.block_hash()) {
+                                        let block_hash = block.block_hash();
+                                        let (liars, response) = self.handle_disputed_block(block).await;
+                                        for liar in liars {
+                                            self.misbehaved(&mut node_map, liar, Misbehavior::FalseFilter).await;
+                                        }
+                                        match response {
+                                            Some(MainThreadMessage::Disconnect) => {
+                                                // The block did not match the header, so we ask someone else
+                                                node_map.disconnect(peer_thread.nonce).await;
+                                                node_map.send_random(MainThreadMessage::GetBlock(GetBlockConfig { locator: block_hash })).await;
+                                            }
+                                            Some(response) => node_map.broadcast(response).await,
+                                            None => (),
+                                        }
+                                        continue;
+                                    }
+                                    match self.handle_block(peer_thread.nonce, block).await {
+                                        Some(MainThreadMessage::Disconnect) => {
+                                            node_map.disconnect(peer_thread.nonce).await;
+                                        }
                                         Some(response) => {
                                             node_map.send_message(peer_thread.nonce, response).await;
                                         }
                                         None => continue,
                                     }
                                 }
-                                PeerMessage::Block(block) => match self.handle_block(block).await {
-                                    Some(response) => {
-                                        node_map.broadcast(response).await;
-                                    }
-                                    None => continue,
-                                },
                                 PeerMessage::NewBlocks(blocks) => {
                                     self.dialog.send_dialog(format!("[Peer {}]: inv", peer_thread.nonce))
                                         .await;
@@ -278,13 +389,36 @@ impl Node {
                                             .await;
                                     }
                                     let best = *node_map.best_height().unwrap_or(&0);
-                                    match self.handle_inventory_blocks(best).await {
-                                        Some(response) => {
-                                            node_map.broadcast(response).await;
+                                    self.handle_inventory_blocks(best).await;
+                                }
+                                PeerMessage::BlocksNotFound(blocks) => {
+                                    self.dialog.send_dialog(format!("[Peer {}]: does not have {} requested blocks", peer_thread.nonce, blocks.len()))
+                                        .await;
+                                    let requested = self.chain.lock().await.blocks_not_found(peer_thread.nonce, &blocks);
+                                    if requested {
+                                        self.misbehaved(&mut node_map, peer_thread.nonce, Misbehavior::UndeliveredRequest).await;
+                                    }
+                                }
+                                PeerMessage::TxRequests(txids) => {
+                                    for txid in txids {
+                                        if let Some(response) = self.handle_tx_request(peer_thread.nonce, txid).await {
+                                            node_map.send_message(peer_thread.nonce, response).await;
                                         }
-                                        None => continue,
                                     }
                                 }
+                                PeerMessage::Transaction(transaction) => self.handle_transaction(transaction).await,
+                                PeerMessage::Latency(latency) => {
+                                    if latency.gt(&MAX_PEER_LATENCY) {
+                                        self.dialog.send_warning(format!("[Peer {}]: answered a ping after {} ms, disconnecting", peer_thread.nonce, latency.as_millis()))
+                                            .await;
+                                        node_map.disconnect(peer_thread.nonce).await;
+                                        continue;
+                                    }
+                                    node_map.set_latency(peer_thread.nonce, latency);
+                                }
+                                PeerMessage::Misbehaved { misbehavior, addr, port, services } => {
+                                    self.punish(peer_thread.nonce, addr, Some(port), Some(services), misbehavior).await;
+                                }
                                 PeerMessage::Disconnect => {
                                     node_map.clean().await;
                                 }
@@ -294,17 +428,18 @@ impl Node {
                         _ => continue,
                     }
                 },
+                outcome = orx.recv() => {
+                    if let Some(outcome) = outcome {
+                        self.handle_one_shot(outcome).await;
+                    }
+                }
                 message = self.client_recv.recv() => {
                     if let Some(message) = message {
                         match message {
                             ClientMessage::Shutdown => return Ok(()),
-                            ClientMessage::Broadcast(transaction) => tx_broadcaster.add(transaction),
+                            ClientMessage::Broadcast(transaction) => self.add_broadcast(transaction).await,
                             ClientMessage::AddScripts(scripts) =>  self.add_scripts(scripts).await,
-                            ClientMessage::Rescan => {
-                                if let Some(response) = self.rescan().await {
-                                    node_map.broadcast(response).await;
-                                }
-                            },
+                            ClientMessage::Rescan => self.rescan().await,
                         }
                     }
                 }
@@ -347,19 +482,48 @@ impl Node {
                 let header_chain = self.chain.lock().await;
                 if header_chain.block_queue_empty() {
                     *state = NodeState::TransactionsSynced;
-                    let _ = self
-                        .dialog
-                        .send_data(NodeMessage::Synced(HeaderCheckpoint::new(
-                            header_chain.height(),
-                            header_chain.tip(),
-                        )))
-                        .await;
+                    let checkpoint =
+                        HeaderCheckpoint::new(header_chain.height(), header_chain.tip());
+                    // Every block up to the tip was scanned, so a restart may resume from here. Scripts added
+                    // after the scan started were not checked against the earlier filters, so they are left
+                    // out and a restart that watches them again scans from the anchor.
+                    if let Err(e) = self
+                        .progress_db
+                        .write(checkpoint, header_chain.scanned_scripts())
+                        .await
+                    {
+                        self.dialog
+                            .send_warning(format!("Could not persist the sync progress: {}", e))
+                            .await;
+                    }
+                    *self.last_synced.write().await = Some(checkpoint);
+                    self.dialog.send_data(NodeMessage::Synced(checkpoint)).await;
                 }
             }
             NodeState::TransactionsSynced => (),
         }
     }
 
+    // Adjust our clock by the median offset of our peers, unless our clock is too far from theirs to be adjusted
+    async fn adjust_time(&mut self, median_offset: Option<i64>) {
+        let mut offset = match median_offset {
+            Some(offset) => offset,
+            None => return,
+        };
+        if offset.unsigned_abs() > MAX_TIME_ADJUSTMENT as u64 {
+            self.dialog
+                .send_warning(format!(
+                    "The clock of this device differs from the median of our peers by {} seconds. Please check the date and time are correct.",
+                    offset
+                ))
+                .await;
+            offset = 0;
+        }
+        self.time_offset
+            .store(offset, std::sync::atomic::Ordering::Relaxed);
+        self.chain.lock().await.set_time_offset(offset);
+    }
+
     // When syncing headers we are only interested in one peer to start
     async fn next_required_peers(&self) -> usize {
         let state = self.state.read().await;
@@ -374,9 +538,9 @@ impl Node {
         &mut self,
         version_message: RemoteVersion,
         best_height: u32,
-    ) -> MainThreadMessage {
-        let state = self.state.read().await;
-        match *state {
+    ) -> Option<MainThreadMessage> {
+        let state = *self.state.read().await;
+        match state {
             NodeState::Behind => (),
             _ => {
                 if !version_message
@@ -389,7 +553,7 @@ impl Node {
                             "Connected peer does not serve compact filters or blocks".into(),
                         )
                         .await;
-                    return MainThreadMessage::Disconnect;
+                    return Some(MainThreadMessage::Disconnect);
                 }
             }
         }
@@ -397,15 +561,19 @@ impl Node {
         if chain.height().le(&best_height) {
             chain.set_best_known_height(best_height).await;
         }
-        // Even if we start the node as caught up in terms of height, we need to check for reorgs. So we can send this unconditionally.
+        // While we are behind, headers are only requested from the sync peer
+        if let NodeState::Behind = state {
+            return None;
+        }
+        // Even if we are caught up in terms of height, we need to check for reorgs
         let next_headers = GetHeaderConfig {
             locators: chain.locators(),
             stop_hash: None,
         };
-        MainThreadMessage::GetHeaders(next_headers)
+        Some(MainThreadMessage::GetHeaders(next_headers))
     }
 
-    async fn handle_new_addrs(&mut self, new_peers: Vec<Address>) {
+    async fn handle_new_addrs(&mut self, new_peers: Vec<AddrV2Message>) {
         self.dialog
             .send_dialog(format!(
                 "Adding {} new peers to the peer database",
@@ -416,11 +584,10 @@ impl Node {
         for addr in new_peers {
             if let Err(e) = lock
                 .add_new_peer(
-                    addr.socket_addr()
-                        .expect("IP should have been screened")
-                        .ip(),
+                    addr.addr,
                     Some(addr.port),
                     Some(addr.services),
+                    Some(addr.time as u64),
                 )
                 .await
             {
@@ -434,45 +601,81 @@ impl Node {
         }
     }
 
-    // We always send headers to our peers, so our next message depends on our state
-    async fn handle_headers(&mut self, headers: Vec<Header>) -> Option<MainThreadMessage> {
+    // We always send headers to our peers, so our next message depends on our state. While we are behind, only
+    // the sync peer is asked for more headers.
+    async fn handle_headers(
+        &mut self,
+        peer_id: u32,
+        advertised: Option<u32>,
+        headers: Vec<Header>,
+    ) -> Result<Option<MainThreadMessage>, Misbehavior> {
+        let num_headers = headers.len();
         let mut chain = self.chain.lock().await;
-        if let Err(e) = chain.sync_chain(headers).await {
-            match e {
-                HeaderSyncError::EmptyMessage => {
-                    if !chain.is_synced() {
-                        return Some(MainThreadMessage::Disconnect);
-                    } else if !chain.is_cf_headers_synced() {
-                        return Some(MainThreadMessage::GetFilterHeaders(
-                            chain.next_cf_header_message().await,
-                        ));
-                    }
-                    return None;
+        let initial_height = chain.height();
+        match chain.sync_chain(headers).await {
+            Ok(()) | Err(HeaderSyncError::EmptyMessage) => (),
+            Err(
+                e @ (HeaderSyncError::HeadersNotConnected
+                | HeaderSyncError::InvalidHeaderWork
+                | HeaderSyncError::InvalidCheckpoint
+                | HeaderSyncError::MiscalculatedDifficulty
+                | HeaderSyncError::UnexpectedDifficultyChange),
+            ) => {
+                self.dialog
+                    .send_dialog(format!("[Peer {}]: {}", peer_id, e))
+                    .await;
+                return Err(Misbehavior::InvalidHeaders);
+            }
+            Err(e) => {
+                self.dialog
+                    .send_warning(format!("Unexpected header syncing error: {}", e))
+                    .await;
+                return Ok(Some(MainThreadMessage::Disconnect));
+            }
+        }
+        if !chain.is_synced() {
+            if self.header_sync.sync_peer().ne(&Some(peer_id)) {
+                return Ok(None);
+            }
+            let progress = self.header_sync.received(
+                num_headers,
+                advertised.unwrap_or(0),
+                chain.height(),
+                chain.height().gt(&initial_height),
+                Instant::now(),
+            );
+            match progress {
+                SyncProgress::Ongoing => {
+                    let next_headers = GetHeaderConfig {
+                        locators: chain.locators(),
+                        stop_hash: None,
+                    };
+                    return Ok(Some(MainThreadMessage::GetHeaders(next_headers)));
+                }
+                SyncProgress::Exhausted => {
+                    // The peer is at most a few blocks short of what it advertised, so we accept its tip
+                    let height = chain.height();
+                    chain.set_best_known_height(height).await;
                 }
-                _ => {
+                SyncProgress::OverstatedHeight { advertised } => {
                     self.dialog
-                        .send_warning(format!("Unexpected header syncing error: {}", e))
+                        .send_dialog(format!(
+                            "[Peer {}]: advertised a height of {} but only served headers to {}",
+                            peer_id,
+                            advertised,
+                            chain.height()
+                        ))
                         .await;
-                    return Some(MainThreadMessage::Disconnect);
+                    return Err(Misbehavior::OverstatedHeight);
                 }
             }
         }
-        if !chain.is_synced() {
-            let next_headers = GetHeaderConfig {
-                locators: chain.locators(),
-                stop_hash: None,
-            };
-            return Some(MainThreadMessage::GetHeaders(next_headers));
-        } else if !chain.is_cf_headers_synced() {
-            return Some(MainThreadMessage::GetFilterHeaders(
+        if !chain.is_cf_headers_synced() {
+            return Ok(Some(MainThreadMessage::GetFilterHeaders(
                 chain.next_cf_header_message().await,
-            ));
-        } else if !chain.is_filters_synced() {
-            return Some(MainThreadMessage::GetFilters(
-                chain.next_filter_message().await,
-            ));
+            )));
         }
-        None
+        Ok(None)
     }
 
     // Compact filter headers may result in a number of outcomes, including the need to audit filters.
@@ -480,37 +683,33 @@ impl Node {
         &mut self,
         peer_id: u32,
         cf_headers: CFHeaders,
-    ) -> Option<MainThreadMessage> {
+    ) -> Result<Option<MainThreadMessage>, Misbehavior> {
         let mut chain = self.chain.lock().await;
         match chain.sync_cf_headers(peer_id, cf_headers).await {
             Ok(potential_message) => match potential_message {
-                CFHeaderSyncResult::AddedToQueue => None,
+                CFHeaderSyncResult::AddedToQueue => Ok(None),
                 CFHeaderSyncResult::ReadyForNext => {
                     // We added a batch to the queue and still are not at the required height
                     if !chain.is_cf_headers_synced() {
-                        Some(MainThreadMessage::GetFilterHeaders(
+                        Ok(Some(MainThreadMessage::GetFilterHeaders(
                             chain.next_cf_header_message().await,
-                        ))
-                    } else if !chain.is_filters_synced() {
-                        // The header chain and filter header chain are in sync, but we need filters still
-                        Some(MainThreadMessage::GetFilters(
-                            chain.next_filter_message().await,
-                        ))
+                        )))
                     } else {
-                        // Should be unreachable if we just added filter headers
-                        None
+                        // The filters are requested from every peer once the node state advances
+                        Ok(None)
                     }
                 }
-                CFHeaderSyncResult::Dispute(_) => {
-                    // TODO: Request the filter and block from the peer
+                CFHeaderSyncResult::Dispute(filter_message) => {
                     self.dialog
                         .send_warning(
                             "Found a conflict while peers are sending filter headers".into(),
                         )
                         .await;
-                    Some(MainThreadMessage::Disconnect)
+                    // Every peer should send the filter they committed to
+                    Ok(Some(MainThreadMessage::GetFilters(filter_message)))
                 }
             },
+            Err(CFHeaderSyncError::PrevHeaderMismatch) => Err(Misbehavior::InvalidFilterHeaders),
             Err(e) => {
                 self.dialog
                     .send_warning(format!(
@@ -518,15 +717,26 @@ impl Node {
                         e
                     ))
                     .await;
-                Some(MainThreadMessage::Disconnect)
+                Ok(Some(MainThreadMessage::Disconnect))
             }
         }
     }
 
-    async fn handle_filter(&mut self, _peer_id: u32, filter: CFilter) -> Option<MainThreadMessage> {
+    async fn handle_filter(
+        &mut self,
+        peer_id: u32,
+        filter: CFilter,
+    ) -> Result<Option<MainThreadMessage>, Misbehavior> {
         let mut chain = self.chain.lock().await;
-        match chain.sync_filter(filter).await {
-            Ok(potential_message) => potential_message.map(MainThreadMessage::GetFilters),
+        if chain.is_disputed(&filter.block_hash) {
+            // Once every peer in the dispute sent a filter, we need the block to compare them
+            return Ok(chain
+                .add_disputed_filter(peer_id, filter)
+                .map(|locator| MainThreadMessage::GetBlock(GetBlockConfig { locator })));
+        }
+        match chain.sync_filter(peer_id, filter).await {
+            Ok(()) => Ok(None),
+            Err(CFilterSyncError::MisalignedFilterHash) => Err(Misbehavior::InvalidFilter),
             Err(e) => {
                 self.dialog
                     .send_warning(format!(
@@ -534,78 +744,383 @@ impl Node {
                         e
                     ))
                     .await;
-                Some(MainThreadMessage::Disconnect)
+                Ok(Some(MainThreadMessage::Disconnect))
             }
         }
     }
 
-    async fn handle_block(&mut self, block: Block) -> Option<MainThreadMessage> {
+    async fn handle_block(&mut self, peer_id: u32, block: Block) -> Option<MainThreadMessage> {
         let state = *self.state.read().await;
         let mut chain = self.chain.lock().await;
         match state {
             NodeState::Behind => Some(MainThreadMessage::Disconnect),
-            NodeState::HeadersSynced => {
-                // do something with the block to resolve a conflict
-                None
-            }
-            NodeState::FilterHeadersSynced => None,
+            // Blocks are only requested once the filters are synced, so a block at this stage was not asked for
+            NodeState::HeadersSynced | NodeState::FilterHeadersSynced => None,
             NodeState::FiltersSynced => {
-                if let Err(e) = chain.scan_block(&block).await {
+                if let Err(e) = chain.scan_block(peer_id, &block).await {
                     self.dialog
                         .send_warning(format!("Unexpected block scanning error: {}", e))
                         .await;
                     return Some(MainThreadMessage::Disconnect);
                 }
+                let height = chain.height_of_hash(block.block_hash()).await;
+                drop(chain);
+                if let Some(height) = height {
+                    self.check_confirmations(&block, height).await;
+                }
                 None
             }
             NodeState::TransactionsSynced => None,
         }
     }
 
-    // The block queue holds all the block hashes we may be interested in
-    async fn pop_block_queue(&mut self) -> Option<MainThreadMessage> {
-        let state = self.state.read().await;
-        let mut chain = self.chain.lock().await;
-        // Do we actually need to wait for the headers to sync?
-        match *state {
-            NodeState::FiltersSynced => {
-                let next_block_hash = chain.next_block();
-                match next_block_hash {
-                    Some(block_hash) => {
+    // Announce the transactions due for a broadcast and give up on the ones no peer would take
+    async fn broadcast_transactions(
+        &mut self,
+        node_map: &mut PeerMap,
+        one_shots: &mpsc::Sender<OneShotOutcome>,
+    ) {
+        let now = Instant::now();
+        for failure in self.tx_broadcaster.abandon(now) {
+            self.chain.lock().await.unwatch_broadcast(&failure.txid);
+            self.dialog
+                .send_warning(format!(
+                    "Abandoning the broadcast of transaction {}",
+                    failure.txid
+                ))
+                .await;
+            self.dialog
+                .send_data(NodeMessage::TxBroadcastFailure(failure))
+                .await;
+        }
+        // Ask a peer for the transactions that were taken by a peer but not yet found in a mempool
+        for txid in self.tx_broadcaster.unseen(now) {
+            node_map
+                .send_random(MainThreadMessage::RequestTx(txid))
+                .await;
+        }
+        for transaction in self.tx_broadcaster.due(now) {
+            let txid = transaction.tx.compute_txid();
+            match transaction.broadcast_policy {
+                TxBroadcastPolicy::AllPeers => {
+                    self.dialog
+                        .send_dialog(format!(
+                            "Announcing transaction {} to {} connected peers.",
+                            txid,
+                            node_map.live()
+                        ))
+                        .await;
+                    node_map
+                        .broadcast(MainThreadMessage::AnnounceTx(txid))
+                        .await
+                }
+                TxBroadcastPolicy::RandomPeer => {
+                    self.dialog
+                        .send_dialog(format!("Announcing transaction {} to a random peer.", txid))
+                        .await;
+                    node_map
+                        .send_random(MainThreadMessage::AnnounceTx(txid))
+                        .await
+                }
+                TxBroadcastPolicy::OneShotPeer => match self.one_shot_peer(node_map).await {
+                    Some((addr, port, services)) => {
+                        self.dialog
+                            .send_dialog(format!(
+                                "Announcing transaction {} over a one-shot connection.",
+                                txid
+                            ))
+                            .await;
+                        let one_shot = OneShot::new(
+                            addr,
+                            Some(port),
+                            services,
+                            self.transport,
+                            self.connection,
+                            self.network,
+                        );
+                        let one_shots = one_shots.clone();
+                        tokio::spawn(async move {
+                            let outcome = one_shot.broadcast(transaction.tx).await;
+                            let _ = one_shots.send(outcome).await;
+                        });
+                    }
+                    None => {
                         self.dialog
-                            .send_dialog(format!("Next block in queue: {}", block_hash))
+                            .send_warning(format!(
+                                "Could not find an unused peer to send transaction {}",
+                                txid
+                            ))
                             .await;
-                        Some(MainThreadMessage::GetBlock(GetBlockConfig {
-                            locator: block_hash,
-                        }))
                     }
-                    None => None,
+                },
+            }
+        }
+    }
+
+    // A peer from the database we are not connected to
+    async fn one_shot_peer(&mut self, node_map: &PeerMap) -> Option<(AddrV2, u16, ServiceFlags)> {
+        let mut peer_manager = self.peer_man.lock().await;
+        for _ in 0..MAX_ONE_SHOT_PEER_TRIES {
+            match peer_manager.next_peer().await {
+                Ok((addr, port, services)) => {
+                    if !node_map.is_connected(&addr) {
+                        return Some((addr, port, services));
+                    }
                 }
+                Err(_) => return None,
             }
-            _ => None,
         }
+        None
     }
 
-    // If new inventory came in, we need to download the headers and update the node state
-    async fn handle_inventory_blocks(&mut self, new_height: u32) -> Option<MainThreadMessage> {
-        let mut state = self.state.write().await;
-        match *state {
-            NodeState::Behind => None,
-            _ => {
-                *state = NodeState::Behind;
-                let mut chain = self.chain.lock().await;
-                let next_headers = GetHeaderConfig {
-                    locators: chain.locators(),
-                    stop_hash: None,
-                };
-                if chain.height().le(&new_height) {
-                    chain.set_best_known_height(new_height).await;
+    // A one-shot connection finished, successfully or otherwise
+    async fn handle_one_shot(&mut self, outcome: OneShotOutcome) {
+        match outcome.result {
+            Ok(()) => {
+                // A rebroadcast is not reported again
+                if let Some((_, true)) = self.tx_broadcaster.requested(&outcome.txid) {
+                    self.dialog
+                        .send_data(NodeMessage::TxSentPrivately(outcome.txid))
+                        .await;
                 }
-                Some(MainThreadMessage::GetHeaders(next_headers))
+            }
+            Err(e) => {
+                self.dialog
+                    .send_warning(format!(
+                        "One-shot broadcast of transaction {} failed: {}",
+                        outcome.txid, e
+                    ))
+                    .await;
+            }
+        }
+    }
+
+    // A peer requested a transaction we announced
+    async fn handle_tx_request(&mut self, nonce: u32, txid: Txid) -> Option<MainThreadMessage> {
+        let (transaction, first) = self.tx_broadcaster.requested(&txid)?;
+        self.dialog
+            .send_dialog(format!("[Peer {}]: requested transaction {}", nonce, txid))
+            .await;
+        if first {
+            self.dialog.send_data(NodeMessage::TxSent(txid)).await;
+        }
+        Some(MainThreadMessage::SendTx(transaction))
+    }
+
+    // A peer answered our request for one of our transactions, so it is in the mempool of that peer
+    async fn handle_transaction(&mut self, transaction: Transaction) {
+        let txid = transaction.compute_txid();
+        if self.tx_broadcaster.seen(&txid) {
+            self.dialog.send_data(NodeMessage::TxSeen(txid)).await;
+        }
+    }
+
+    // Give a range of filters to each peer serving filters that is not sending one, and penalize the
+    // peers that stopped sending their range so it is requested from another peer
+    async fn request_filters(&mut self, node_map: &mut PeerMap) {
+        if !matches!(*self.state.read().await, NodeState::FilterHeadersSynced) {
+            return;
+        }
+        let stalled = self.chain.lock().await.stalled_filter_requests();
+        for peer in stalled {
+            self.misbehaved(node_map, peer, Misbehavior::UndeliveredRequest)
+                .await;
+        }
+        let mut chain = self.chain.lock().await;
+        for (peer, message) in chain.next_filter_requests(&node_map.filter_peers()).await {
+            node_map
+                .send_message(peer, MainThreadMessage::GetFilters(message))
+                .await;
+        }
+    }
+
+    // Abandon a filter dispute that a peer stopped responding to, disconnecting the peers that did not
+    // send their filter and requesting the batch of filter headers again
+    async fn check_filter_dispute(&mut self, node_map: &mut PeerMap) {
+        let mut chain = self.chain.lock().await;
+        let silent = match chain.stalled_dispute(&node_map.filter_peers()) {
+            Some(silent) => silent,
+            None => return,
+        };
+        self.dialog
+            .send_warning("A filter dispute stalled, requesting the filter headers again".into())
+            .await;
+        for peer in silent {
+            node_map.disconnect(peer).await;
+        }
+        if !chain.is_cf_headers_synced() {
+            let message = chain.next_cf_header_message().await;
+            node_map
+                .broadcast(MainThreadMessage::GetFilterHeaders(message))
+                .await;
+        }
+    }
+
+    // Broadcast a transaction, and check the filters for its outputs so we learn when it is confirmed
+    async fn add_broadcast(&mut self, transaction: TxBroadcast) {
+        self.chain.lock().await.watch_broadcast(&transaction.tx);
+        self.tx_broadcaster.add(transaction);
+    }
+
+    // Stop broadcasting the transactions that were included in a block
+    async fn check_confirmations(&mut self, block: &Block, height: u32) {
+        for tx in &block.txdata {
+            let txid = tx.compute_txid();
+            if self.tx_broadcaster.confirmed(&txid) {
+                self.chain.lock().await.unwatch_broadcast(&txid);
+                self.dialog
+                    .send_data(NodeMessage::TxConfirmed(IndexedTransaction::new(
+                        tx.clone(),
+                        height,
+                        block.block_hash(),
+                    )))
+                    .await;
+            }
+        }
+    }
+
+    // A block that resolves a filter dispute, returning the peers that lied about the filter
+    async fn handle_disputed_block(
+        &mut self,
+        block: Block,
+    ) -> (Vec<u32>, Option<MainThreadMessage>) {
+        let mut chain = self.chain.lock().await;
+        match chain.resolve_dispute(&block).await {
+            Ok((liars, result)) => {
+                let response = match result {
+                    CFHeaderSyncResult::AddedToQueue => None,
+                    CFHeaderSyncResult::ReadyForNext => {
+                        if !chain.is_cf_headers_synced() {
+                            Some(MainThreadMessage::GetFilterHeaders(
+                                chain.next_cf_header_message().await,
+                            ))
+                        } else {
+                            None
+                        }
+                    }
+                    CFHeaderSyncResult::Dispute(filter_message) => {
+                        Some(MainThreadMessage::GetFilters(filter_message))
+                    }
+                };
+                (liars, response)
+            }
+            Err(e) => {
+                self.dialog
+                    .send_warning(format!("Could not resolve the filter dispute: {}", e))
+                    .await;
+                (Vec::new(), Some(MainThreadMessage::Disconnect))
             }
         }
     }
 
+    // Punish a connected peer for a protocol violation and disconnect from it
+    async fn misbehaved(&mut self, node_map: &mut PeerMap, nonce: u32, misbehavior: Misbehavior) {
+        if let Some((addr, port)) = node_map.address(nonce) {
+            let services = node_map.services(nonce);
+            self.punish(nonce, addr, port, services, misbehavior).await;
+        }
+        node_map.disconnect(nonce).await;
+    }
+
+    // Add to the misbehavior score of a peer, and ban the peer once the score reaches the threshold
+    async fn punish(
+        &mut self,
+        nonce: u32,
+        addr: AddrV2,
+        port: Option<u16>,
+        services: Option<ServiceFlags>,
+        misbehavior: Misbehavior,
+    ) {
+        if !self.peer_scores.punish(addr.clone(), misbehavior) {
+            self.dialog
+                .send_warning(format!("[Peer {}]: {}, disconnecting", nonce, misbehavior))
+                .await;
+            return;
+        }
+        self.dialog
+            .send_warning(format!("[Peer {}]: {}, banning", nonce, misbehavior))
+            .await;
+        let mut peer_manager = self.peer_man.lock().await;
+        if let Err(e) = peer_manager.ban_peer(addr, port, services).await {
+            self.dialog
+                .send_warning(format!("Encountered error banning a peer: {}", e))
+                .await;
+        }
+    }
+
+    // Request the blocks in the queue from our peers, and penalize the peers that did not send a block
+    // in time so it is requested from another peer. Peers serving compact filters also serve the blocks
+    // they commit to.
+    async fn request_blocks(&mut self, node_map: &mut PeerMap) {
+        if !matches!(*self.state.read().await, NodeState::FiltersSynced) {
+            return;
+        }
+        let stalled = self.chain.lock().await.stalled_block_requests();
+        for peer in stalled {
+            self.misbehaved(node_map, peer, Misbehavior::UndeliveredRequest)
+                .await;
+        }
+        let mut chain = self.chain.lock().await;
+        for (peer, block_hash) in chain.next_block_requests(&node_map.filter_peers()) {
+            self.dialog
+                .send_dialog(format!("[Peer {}]: requesting block {}", peer, block_hash))
+                .await;
+            node_map
+                .send_message(
+                    peer,
+                    MainThreadMessage::GetBlock(GetBlockConfig {
+                        locator: block_hash,
+                    }),
+                )
+                .await;
+        }
+    }
+
+    // If new inventory came in, we need to download the headers from a sync peer and update the node state
+    async fn handle_inventory_blocks(&mut self, new_height: u32) {
+        let mut state = self.state.write().await;
+        if let NodeState::Behind = *state {
+            return;
+        }
+        *state = NodeState::Behind;
+        let mut chain = self.chain.lock().await;
+        if chain.height().le(&new_height) {
+            chain.set_best_known_height(new_height).await;
+        }
+    }
+
+    // Request headers from the peer advertising the most blocks, and switch to another peer if the sync peer
+    // stops extending our chain
+    async fn sync_headers(&mut self, node_map: &mut PeerMap) {
+        if !matches!(*self.state.read().await, NodeState::Behind) {
+            self.header_sync.clear();
+            return;
+        }
+        let now = Instant::now();
+        if let Some(peer) = self.header_sync.stalled(now) {
+            self.dialog
+                .send_warning(format!(
+                    "[Peer {}]: stopped sending headers, switching to another peer",
+                    peer
+                ))
+                .await;
+            node_map.disconnect(peer).await;
+        }
+        if let Some(peer) = self.header_sync.select(node_map.heights(), now) {
+            self.dialog
+                .send_dialog(format!("[Peer {}]: syncing headers", peer))
+                .await;
+            let mut chain = self.chain.lock().await;
+            let next_headers = GetHeaderConfig {
+                locators: chain.locators(),
+                stop_hash: None,
+            };
+            node_map
+                .send_message(peer, MainThreadMessage::GetHeaders(next_headers))
+                .await;
+        }
+    }
+
     // Add more scripts to the chain to look for. Does not imply a rescan.
     async fn add_scripts(&mut self, scripts: HashSet<ScriptBuf>) {
         let mut chain = self.chain.lock().await;
@@ -613,43 +1128,49 @@ impl Node {
     }
 
     // Clear the filter hash cache and redownload the filters.
-    async fn rescan(&mut self) -> Option<MainThreadMessage> {
+    async fn rescan(&mut self) {
         let mut state = self.state.write().await;
         let mut chain = self.chain.lock().await;
-        match *state {
-            NodeState::Behind => None,
-            NodeState::HeadersSynced => None,
-            _ => {
-                chain.clear_filters().await;
-                *state = NodeState::FilterHeadersSynced;
-                Some(MainThreadMessage::GetFilters(
-                    chain.next_filter_message().await,
-                ))
-            }
+        if matches!(*state, NodeState::Behind | NodeState::HeadersSynced) {
+            return;
+        }
+        *state = NodeState::FilterHeadersSynced;
+        // Only download the filters we do not have on disk
+        if chain.scan_cached_filters().await {
+            self.dialog
+                .send_dialog("Rescanned the block filters from storage".into())
+                .await;
         }
     }
 
     // First we search the whitelist for peers that we trust. If we don't have any more whitelisted peers,
     // we try to get a new peer from the peer manager. If that fails and our database is empty, we try DNS.
     // Otherwise, the node throws an error.
-    async fn next_peer(&mut self) -> Result<(IpAddr, Option<u16>), NodeError> {
+    async fn next_peer(&mut self) -> Result<(AddrV2, Option<u16>, ServiceFlags), NodeError> {
         if let Some(whitelist) = &mut self.white_list {
-            if let Some((ip, port)) = whitelist.pop() {
-                return {
+            while let Some((addr, port)) = whitelist.pop() {
+                if !self.connection.can_reach(&addr) {
                     self.dialog
-                        .send_dialog("Using a peer from the white list".into())
+                        .send_warning(format!(
+                            "Skipping a peer from the white list that cannot be reached: {:?}",
+                            addr
+                        ))
                         .await;
-                    Ok((ip, Some(port)))
-                };
+                    continue;
+                }
+                self.dialog
+                    .send_dialog("Using a peer from the white list".into())
+                    .await;
+                return Ok((addr, Some(port), ServiceFlags::NONE));
             }
         }
         let mut peer_manager = self.peer_man.lock().await;
         match peer_manager.next_peer().await {
-            Ok((ip, port)) => {
+            Ok((ip, port, services)) => {
                 self.dialog
                     .send_dialog("Found an existing peer in the database".into())
                     .await;
-                Ok((ip, Some(port)))
+                Ok((ip, Some(port), services))
             }
             Err(_) => {
                 let current_count = peer_manager
@@ -660,6 +1181,10 @@ impl Node {
                     self.dialog
                         .send_warning("There are no peers in the database".into())
                         .await;
+                    // The DNS seeds only return IP addresses, and querying them outside the proxy would reveal we run a node
+                    if !peer_manager.can_bootstrap() {
+                        return Err(NodeError::LoadError(PersistenceError::PeerLoadFailure));
+                    }
                     #[cfg(feature = "dns")]
                     self.dialog
                         .send_dialog("Using DNS to find new peers".into())
@@ -674,7 +1199,7 @@ impl Node {
                         .next_peer()
                         .await
                         .map_err(|_| NodeError::LoadError(PersistenceError::PeerLoadFailure))?;
-                    return Ok((next_peer.0, Some(next_peer.1)));
+                    return Ok((next_peer.0, Some(next_peer.1), next_peer.2));
                 }
                 self.dialog
                     .send_warning("An error occured while finding a new peer".into())
@@ -684,3 +1209,82 @@ impl Node {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use bitcoin::{hashes::Hash, WPubkeyHash};
+
+    use crate::db::error::DatabaseError;
+
+    use super::*;
+
+    // Sync progress that outlives a session of the node
+    #[derive(Clone, Default)]
+    struct SharedProgress(Arc<StdMutex<(Option<HeaderCheckpoint>, HashSet<ScriptBuf>)>>);
+
+    #[async_trait::async_trait]
+    impl ProgressStore for SharedProgress {
+        async fn load_checkpoint(&mut self) -> Result<Option<HeaderCheckpoint>, DatabaseError> {
+            Ok(self.0.lock().unwrap().0)
+        }
+
+        async fn load_scripts(&mut self) -> Result<HashSet<ScriptBuf>, DatabaseError> {
+            Ok(self.0.lock().unwrap().1.clone())
+        }
+
+        async fn write<'a>(
+            &mut self,
+            checkpoint: HeaderCheckpoint,
+            scripts: &'a HashSet<ScriptBuf>,
+        ) -> Result<(), DatabaseError> {
+            *self.0.lock().unwrap() = (Some(checkpoint), scripts.clone());
+            Ok(())
+        }
+    }
+
+    fn script(byte: u8) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(&[byte]))
+    }
+
+    async fn start(scripts: &[ScriptBuf], progress: &SharedProgress) -> (Node, Client) {
+        Node::new(
+            Network::Regtest,
+            None,
+            scripts.iter().cloned().collect(),
+            None,
+            1,
+            TransportPreference::V1Only,
+            ConnectionMode::Direct,
+            None,
+            (),
+            (),
+            Box::new(()),
+            Box::new(()),
+            (),
+            Box::new(progress.clone()),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rescans_for_scripts_added_while_syncing() {
+        let progress = SharedProgress::default();
+        let (mut node, _) = start(&[script(1)], &progress).await;
+        // The filters checked before this script was added never looked for it
+        node.add_scripts(HashSet::from([script(2)])).await;
+        *node.state.write().await = NodeState::FiltersSynced;
+        node.advance_state().await;
+        let (checkpoint, scripts) = progress.0.lock().unwrap().clone();
+        assert!(checkpoint.is_some());
+        assert_eq!(scripts, HashSet::from([script(1)]));
+        // Watching only the scanned scripts resumes from the checkpoint
+        let (_, client) = start(&[script(1)], &progress).await;
+        assert_eq!(client.last_synced().await, checkpoint);
+        // Watching the script added midway scans from the anchor
+        let (_, client) = start(&[script(1), script(2)], &progress).await;
+        assert!(client.last_synced().await.is_none());
+    }
+}
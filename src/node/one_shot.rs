@@ -0,0 +1,229 @@
+use std::time::Duration;
+
+use bitcoin::{
+    p2p::{address::AddrV2, ServiceFlags},
+    Network, Transaction, Txid,
+};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::{peers::peer::Peer, ConnectionMode, TransportPreference};
+
+use super::channel_messages::{MainThreadMessage, PeerMessage, PeerThreadMessage};
+
+// The time a one-shot connection has to complete the handshake and request the transaction
+const ONE_SHOT_TIMEOUT: Duration = Duration::from_secs(30);
+// One-shot connections are not tracked by the peer map, so the nonce is never used to route messages
+const ONE_SHOT_NONCE: u32 = 0;
+
+// The outcome of announcing a transaction over a one-shot connection
+#[derive(Debug)]
+pub(crate) struct OneShotOutcome {
+    pub txid: Txid,
+    pub result: Result<(), OneShotError>,
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum OneShotError {
+    #[error("the peer did not request the transaction in time")]
+    Timeout,
+    #[error("the peer disconnected before the transaction was sent")]
+    Disconnected,
+}
+
+// A connection to a peer used for nothing but a single transaction, so the transaction is not linked to the
+// requests sent to our long-lived peers.
+pub(crate) struct OneShot {
+    addr: AddrV2,
+    port: Option<u16>,
+    services: ServiceFlags,
+    transport: TransportPreference,
+    connection: ConnectionMode,
+    network: Network,
+}
+
+impl OneShot {
+    pub(crate) fn new(
+        addr: AddrV2,
+        port: Option<u16>,
+        services: ServiceFlags,
+        transport: TransportPreference,
+        connection: ConnectionMode,
+        network: Network,
+    ) -> Self {
+        Self {
+            addr,
+            port,
+            services,
+            transport,
+            connection,
+            network,
+        }
+    }
+
+    // Complete the handshake, announce the transaction, send it once it is requested and disconnect once it is
+    // written to the connection
+    pub(crate) async fn broadcast(self, transaction: Transaction) -> OneShotOutcome {
+        let txid = transaction.compute_txid();
+        let (mtx, mut mrx) = mpsc::channel::<PeerThreadMessage>(32);
+        let (ptx, prx) = mpsc::channel::<MainThreadMessage>(32);
+        let mut peer = Peer::new(
+            ONE_SHOT_NONCE,
+            self.addr,
+            self.port,
+            self.services,
+            self.transport,
+            self.connection,
+            self.network,
+            mtx,
+            prx,
+        );
+        let handle = tokio::spawn(async move { peer.connect().await });
+        let exchange = async {
+            while let Some(peer_thread) = mrx.recv().await {
+                match peer_thread.message {
+                    // The verack is written as soon as the version is received, so we may announce right away
+                    PeerMessage::Version(_) => {
+                        ptx.send(MainThreadMessage::AnnounceTx(txid))
+                            .await
+                            .map_err(|_| OneShotError::Disconnected)?;
+                    }
+                    PeerMessage::TxRequests(txids) if txids.contains(&txid) => {
+                        ptx.send(MainThreadMessage::SendTx(transaction.clone()))
+                            .await
+                            .map_err(|_| OneShotError::Disconnected)?;
+                    }
+                    PeerMessage::TxWritten(written) if written.eq(&txid) => return Ok(()),
+                    PeerMessage::Disconnect => return Err(OneShotError::Disconnected),
+                    _ => continue,
+                }
+            }
+            Err(OneShotError::Disconnected)
+        };
+        let result = match tokio::time::timeout(ONE_SHOT_TIMEOUT, exchange).await {
+            Ok(result) => result,
+            Err(_) => Err(OneShotError::Timeout),
+        };
+        let _ = ptx.send(MainThreadMessage::Disconnect).await;
+        if result.is_err() {
+            handle.abort();
+        }
+        OneShotOutcome { txid, result }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcoin::{
+        absolute::LockTime,
+        consensus::{deserialize, serialize},
+        p2p::{
+            message::{NetworkMessage, RawNetworkMessage},
+            message_blockdata::Inventory,
+        },
+        transaction::Version,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::peers::outbound_messages::{MessageGenerator, V1OutboundMessage};
+
+    use super::*;
+
+    const NETWORK: Network = Network::Signet;
+
+    async fn read_message(stream: &mut TcpStream) -> NetworkMessage {
+        let mut header = [0_u8; 24];
+        stream.read_exact(&mut header).await.unwrap();
+        let length = u32::from_le_bytes(header[16..20].try_into().unwrap());
+        let mut message = header.to_vec();
+        message.resize(24 + length as usize, 0);
+        stream.read_exact(&mut message[24..]).await.unwrap();
+        let raw: RawNetworkMessage = deserialize(&message).unwrap();
+        raw.payload().clone()
+    }
+
+    fn one_shot(port: u16) -> OneShot {
+        OneShot::new(
+            AddrV2::Ipv4(Ipv4Addr::LOCALHOST),
+            Some(port),
+            ServiceFlags::NONE,
+            TransportPreference::V1Only,
+            ConnectionMode::Direct,
+            NETWORK,
+        )
+    }
+
+    fn transaction() -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sends_only_the_requested_transaction() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tx = transaction();
+        let txid = tx.compute_txid();
+        let broadcast = tokio::spawn(one_shot(port).broadcast(tx.clone()));
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut generator = V1OutboundMessage::new(NETWORK);
+        assert!(matches!(
+            read_message(&mut stream).await,
+            NetworkMessage::Version(_)
+        ));
+        stream
+            .write_all(&generator.new_version_message(None).unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_message(&mut stream).await,
+            NetworkMessage::SendAddrV2
+        ));
+        assert!(matches!(
+            read_message(&mut stream).await,
+            NetworkMessage::Verack
+        ));
+        match read_message(&mut stream).await {
+            NetworkMessage::Inv(inventory) => {
+                assert_eq!(inventory, vec![Inventory::Transaction(txid)])
+            }
+            _ => panic!("expected the transaction to be announced"),
+        }
+        let getdata = RawNetworkMessage::new(
+            NETWORK.magic(),
+            NetworkMessage::GetData(vec![Inventory::WitnessTransaction(txid)]),
+        );
+        stream.write_all(&serialize(&getdata)).await.unwrap();
+        match read_message(&mut stream).await {
+            NetworkMessage::Tx(sent) => assert_eq!(sent, tx),
+            _ => panic!("expected the transaction"),
+        }
+        let outcome = broadcast.await.unwrap();
+        assert_eq!(outcome.txid, txid);
+        assert!(outcome.result.is_ok());
+        // Nothing else is sent over the connection before it is closed
+        let mut buf = [0_u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reports_peers_that_disconnect() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broadcast = tokio::spawn(one_shot(port).broadcast(transaction()));
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_message(&mut stream).await;
+        drop(stream);
+        let outcome = broadcast.await.unwrap();
+        assert!(matches!(outcome.result, Err(OneShotError::Disconnected)));
+    }
+}
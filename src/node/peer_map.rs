@@ -1,10 +1,12 @@
 use std::{
     collections::HashMap,
-    net::IpAddr,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use bitcoin::{p2p::ServiceFlags, Network};
+use bitcoin::{
+    p2p::{address::AddrV2, ServiceFlags},
+    Network,
+};
 use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
 use tokio::{
     sync::mpsc::{self, Sender},
@@ -13,14 +15,18 @@ use tokio::{
 
 use crate::{
     peers::peer::{Peer, PeerError},
-    prelude::Median,
+    prelude::{Median, MAX_FUTURE_BLOCK_TIME, MIN_TIME_SAMPLES},
+    ConnectionMode, TransportPreference,
 };
 
 use super::channel_messages::{MainThreadMessage, PeerThreadMessage};
 
 pub(crate) struct ManagedPeer {
-    net_time: i64,
+    addr: AddrV2,
+    port: Option<u16>,
+    net_time: Option<i64>,
     service_flags: Option<ServiceFlags>,
+    latency: Option<Duration>,
     ptx: Sender<MainThreadMessage>,
     handle: JoinHandle<Result<(), PeerError>>,
 }
@@ -29,16 +35,25 @@ pub(crate) struct PeerMap {
     num_peers: u32,
     heights: HashMap<u32, u32>,
     network: Network,
+    transport: TransportPreference,
+    connection: ConnectionMode,
     mtx: Sender<PeerThreadMessage>,
     map: HashMap<u32, ManagedPeer>,
 }
 
 impl PeerMap {
-    pub fn new(mtx: Sender<PeerThreadMessage>, network: Network) -> Self {
+    pub fn new(
+        mtx: Sender<PeerThreadMessage>,
+        network: Network,
+        transport: TransportPreference,
+        connection: ConnectionMode,
+    ) -> Self {
         Self {
             num_peers: 0,
             heights: HashMap::new(),
             network,
+            transport,
+            connection,
             mtx,
             map: HashMap::new(),
         }
@@ -70,12 +85,35 @@ impl PeerMap {
             .count()
     }
 
-    pub fn median_time_adjustment(&self) -> i64 {
-        if self.map.values().len() > 0 {
-            let mut time_offsets: Vec<i64> = self.map.values().map(|peer| peer.net_time).collect();
-            return time_offsets.median().unwrap();
+    // The peers that completed the handshake and serve compact filters, fastest first. Peers we have not
+    // measured the latency of come last.
+    pub fn filter_peers(&self) -> Vec<u32> {
+        let mut peers: Vec<(u32, Option<Duration>)> = self
+            .map
+            .iter()
+            .filter(|(_, peer)| !peer.handle.is_finished())
+            .filter(|(_, peer)| {
+                peer.service_flags
+                    .map_or(false, |flags| flags.has(ServiceFlags::COMPACT_FILTERS))
+            })
+            .map(|(nonce, peer)| (*nonce, peer.latency))
+            .collect();
+        peers.sort_by_key(|(nonce, latency)| (latency.is_none(), *latency, *nonce));
+        peers.into_iter().map(|(nonce, _)| nonce).collect()
+    }
+
+    // The median offset of our peers' clocks from ours, counting each address that told us its time once.
+    // Too few samples are not enough to trust over our own clock.
+    pub fn median_time_adjustment(&self) -> Option<i64> {
+        let offsets: HashMap<&AddrV2, i64> = self
+            .map
+            .values()
+            .filter_map(|peer| peer.net_time.map(|offset| (&peer.addr, offset)))
+            .collect();
+        if offsets.len() < MIN_TIME_SAMPLES {
+            return None;
         }
-        0
+        offsets.into_values().collect::<Vec<i64>>().median()
     }
 
     pub fn set_offset(&mut self, peer: u32, time: i64) {
@@ -84,33 +122,71 @@ impl PeerMap {
                 .duration_since(UNIX_EPOCH)
                 .expect("time went backwards")
                 .as_secs();
-            peer.net_time = time - now as i64;
+            // A peer may report any timestamp, so the offset is bounded instead of allowed to overflow
+            let offset = time
+                .saturating_sub(now as i64)
+                .clamp(-MAX_FUTURE_BLOCK_TIME, MAX_FUTURE_BLOCK_TIME);
+            peer.net_time = Some(offset);
         }
     }
 
-    pub async fn dispatch(&mut self, ip: IpAddr, port: Option<u16>) {
+    pub async fn dispatch(&mut self, addr: AddrV2, port: Option<u16>, services: ServiceFlags) {
         let (ptx, prx) = mpsc::channel::<MainThreadMessage>(32);
         let peer_num = self.num_peers + 1;
         self.num_peers = peer_num;
-        let mut peer = Peer::new(peer_num, ip, port, self.network, self.mtx.clone(), prx);
+        let mut peer = Peer::new(
+            peer_num,
+            addr.clone(),
+            port,
+            services,
+            self.transport,
+            self.connection,
+            self.network,
+            self.mtx.clone(),
+            prx,
+        );
         let handle = tokio::spawn(async move { peer.connect().await });
         self.map.insert(
             peer_num,
             ManagedPeer {
+                addr,
+                port,
                 service_flags: None,
-                net_time: 0,
+                latency: None,
+                net_time: None,
                 ptx,
                 handle,
             },
         );
     }
 
+    pub fn set_latency(&mut self, nonce: u32, latency: Duration) {
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.latency = Some(latency)
+        }
+    }
+
     pub fn set_services(&mut self, nonce: u32, flags: ServiceFlags) {
         if let Some(peer) = self.map.get_mut(&nonce) {
             peer.service_flags = Some(flags)
         }
     }
 
+    pub fn address(&self, nonce: u32) -> Option<(AddrV2, Option<u16>)> {
+        self.map
+            .get(&nonce)
+            .map(|peer| (peer.addr.clone(), peer.port))
+    }
+
+    // The services a peer signaled in its version message
+    pub fn services(&self, nonce: u32) -> Option<ServiceFlags> {
+        self.map.get(&nonce).and_then(|peer| peer.service_flags)
+    }
+
+    pub fn is_connected(&self, addr: &AddrV2) -> bool {
+        self.map.values().any(|peer| peer.addr.eq(addr))
+    }
+
     pub fn set_height(&mut self, nonce: u32, height: u32) {
         self.heights.insert(nonce, height);
     }
@@ -121,6 +197,15 @@ impl PeerMap {
         }
     }
 
+    pub fn height(&self, nonce: u32) -> Option<u32> {
+        self.heights.get(&nonce).copied()
+    }
+
+    // The heights advertised by the peers that completed a handshake
+    pub fn heights(&self) -> &HashMap<u32, u32> {
+        &self.heights
+    }
+
     pub fn best_height(&self) -> Option<&u32> {
         self.heights.values().max()
     }
@@ -131,6 +216,14 @@ impl PeerMap {
         }
     }
 
+    // Stop routing messages to this peer, so requests are sent elsewhere while it shuts down
+    pub async fn disconnect(&mut self, nonce: u32) {
+        if let Some(peer) = self.map.remove(&nonce) {
+            let _ = peer.ptx.send(MainThreadMessage::Disconnect).await;
+        }
+        self.heights.remove(&nonce);
+    }
+
     pub async fn broadcast(&mut self, message: MainThreadMessage) {
         let active = self.map.values().filter(|peer| !peer.handle.is_finished());
         for peer in active {
@@ -145,3 +238,57 @@ impl PeerMap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn add_peer(peer_map: &mut PeerMap, nonce: u32, addr: AddrV2, offset: i64) {
+        let (ptx, _) = mpsc::channel(1);
+        peer_map.map.insert(
+            nonce,
+            ManagedPeer {
+                addr,
+                port: None,
+                net_time: Some(offset),
+                service_flags: None,
+                latency: None,
+                ptx,
+                handle: tokio::spawn(async { Ok(()) }),
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requires_enough_distinct_time_samples() {
+        let (mtx, _) = mpsc::channel(1);
+        let mut peer_map = PeerMap::new(
+            mtx,
+            Network::Signet,
+            TransportPreference::V1Only,
+            ConnectionMode::Direct,
+        );
+        for nonce in 1..MIN_TIME_SAMPLES as u32 {
+            let addr = AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, nonce as u8));
+            add_peer(&mut peer_map, nonce, addr, 60);
+        }
+        assert_eq!(peer_map.median_time_adjustment(), None);
+        // A second connection to a known address is not another sample
+        add_peer(
+            &mut peer_map,
+            100,
+            AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1)),
+            60,
+        );
+        assert_eq!(peer_map.median_time_adjustment(), None);
+        add_peer(
+            &mut peer_map,
+            101,
+            AddrV2::Ipv4(Ipv4Addr::new(2, 2, 2, 2)),
+            60,
+        );
+        assert_eq!(peer_map.median_time_adjustment(), Some(60));
+    }
+}
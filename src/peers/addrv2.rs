@@ -0,0 +1,235 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use bitcoin::p2p::address::AddrV2;
+use sha3::{Digest, Sha3_256};
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+const ONION_CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+const ONION_VERSION: u8 = 0x03;
+const ONION_SUFFIX: &str = ".onion";
+const I2P_SUFFIX: &str = ".b32.i2p";
+// CJDNS addresses are always in fc00::/8
+const CJDNS_PREFIX: u8 = 0xfc;
+// BIP-155 network IDs
+pub(crate) const IPV4_ID: u8 = 0x01;
+pub(crate) const IPV6_ID: u8 = 0x02;
+const TORV2_ID: u8 = 0x03;
+pub(crate) const TORV3_ID: u8 = 0x04;
+pub(crate) const I2P_ID: u8 = 0x05;
+pub(crate) const CJDNS_ID: u8 = 0x06;
+
+// Peers learned through a legacy `addr` message or DNS only have an IP address
+pub(crate) fn from_ip(ip: IpAddr) -> AddrV2 {
+    match ip {
+        IpAddr::V4(ip) => AddrV2::Ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => AddrV2::Ipv4(ip),
+            None => AddrV2::Ipv6(ip),
+        },
+    }
+}
+
+// Addresses that may be dialed without a proxy
+pub(crate) fn to_ip(addr: &AddrV2) -> Option<IpAddr> {
+    match addr {
+        AddrV2::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+        AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => Some(IpAddr::V6(*ip)),
+        _ => None,
+    }
+}
+
+// The human readable address a SOCKS5 proxy or a user understands
+pub(crate) fn to_host(addr: &AddrV2) -> Option<String> {
+    match addr {
+        AddrV2::Ipv4(ip) => Some(ip.to_string()),
+        AddrV2::Ipv6(ip) => Some(ip.to_string()),
+        AddrV2::Cjdns(ip) => Some(ip.to_string()),
+        AddrV2::TorV3(pubkey) => {
+            let mut data = pubkey.to_vec();
+            data.extend_from_slice(&onion_checksum(pubkey));
+            data.push(ONION_VERSION);
+            Some(format!("{}{}", base32_encode(&data), ONION_SUFFIX))
+        }
+        AddrV2::I2p(hash) => Some(format!("{}{}", base32_encode(hash), I2P_SUFFIX)),
+        _ => None,
+    }
+}
+
+// Parse an address written by `to_host`. Addresses in fc00::/8 are assumed to be CJDNS.
+pub(crate) fn from_host(host: &str) -> Option<AddrV2> {
+    if let Some(encoded) = host.strip_suffix(ONION_SUFFIX) {
+        let data = base32_decode(encoded)?;
+        if data.len() != 35 || data[34] != ONION_VERSION {
+            return None;
+        }
+        let pubkey: [u8; 32] = data[..32].try_into().ok()?;
+        if onion_checksum(&pubkey) != data[32..34] {
+            return None;
+        }
+        return Some(AddrV2::TorV3(pubkey));
+    }
+    if let Some(encoded) = host.strip_suffix(I2P_SUFFIX) {
+        let hash: [u8; 32] = base32_decode(encoded)?.try_into().ok()?;
+        return Some(AddrV2::I2p(hash));
+    }
+    match host.parse::<IpAddr>().ok()? {
+        IpAddr::V6(ip) if is_cjdns(&ip) => Some(AddrV2::Cjdns(ip)),
+        ip => Some(from_ip(ip)),
+    }
+}
+
+// The BIP-155 network ID and address bytes, as they are sent in an `addrv2` message
+pub(crate) fn to_bytes(addr: &AddrV2) -> (u8, Vec<u8>) {
+    match addr {
+        AddrV2::Ipv4(ip) => (IPV4_ID, ip.octets().to_vec()),
+        AddrV2::Ipv6(ip) => (IPV6_ID, ip.octets().to_vec()),
+        AddrV2::TorV2(id) => (TORV2_ID, id.to_vec()),
+        AddrV2::TorV3(pubkey) => (TORV3_ID, pubkey.to_vec()),
+        AddrV2::I2p(hash) => (I2P_ID, hash.to_vec()),
+        AddrV2::Cjdns(ip) => (CJDNS_ID, ip.octets().to_vec()),
+        AddrV2::Unknown(id, bytes) => (*id, bytes.clone()),
+    }
+}
+
+// Returns `None` if the length of the address is invalid for a known network
+pub(crate) fn from_bytes(network: u8, bytes: &[u8]) -> Option<AddrV2> {
+    match network {
+        IPV4_ID => {
+            let octets: [u8; 4] = bytes.try_into().ok()?;
+            Some(AddrV2::Ipv4(octets.into()))
+        }
+        IPV6_ID => {
+            let octets: [u8; 16] = bytes.try_into().ok()?;
+            Some(AddrV2::Ipv6(octets.into()))
+        }
+        TORV2_ID => Some(AddrV2::TorV2(bytes.try_into().ok()?)),
+        TORV3_ID => Some(AddrV2::TorV3(bytes.try_into().ok()?)),
+        I2P_ID => Some(AddrV2::I2p(bytes.try_into().ok()?)),
+        CJDNS_ID => {
+            let octets: [u8; 16] = bytes.try_into().ok()?;
+            Some(AddrV2::Cjdns(octets.into()))
+        }
+        _ => Some(AddrV2::Unknown(network, bytes.to_vec())),
+    }
+}
+
+fn is_cjdns(ip: &Ipv6Addr) -> bool {
+    ip.octets()[0] == CJDNS_PREFIX
+}
+
+// The checksum of a version three onion address. Tor commits to onion addresses with SHA3, which
+// `bitcoin_hashes` does not provide.
+fn onion_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut preimage = ONION_CHECKSUM_PREFIX.to_vec();
+    preimage.extend_from_slice(pubkey);
+    preimage.push(ONION_VERSION);
+    let hash = Sha3_256::digest(&preimage);
+    [hash[0], hash[1]]
+}
+
+// Lowercase RFC 4648 base32 without padding
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| a.eq(&c.to_ascii_lowercase()))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_base32() {
+        // The test vectors of RFC 4648, in lowercase and without padding
+        let vectors = [
+            ("", ""),
+            ("f", "my"),
+            ("fo", "mzxq"),
+            ("foo", "mzxw6"),
+            ("foob", "mzxw6yq"),
+            ("fooba", "mzxw6ytb"),
+            ("foobar", "mzxw6ytboi"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), data.as_bytes());
+        }
+        assert_eq!(base32_decode("MZXW6").unwrap(), b"foo");
+        assert!(base32_decode("mzxw1").is_none());
+    }
+
+    #[test]
+    fn test_host_round_trips() {
+        let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let addr = from_host(onion).unwrap();
+        assert!(matches!(addr, AddrV2::TorV3(_)));
+        assert_eq!(to_host(&addr).unwrap(), onion);
+        // A single changed character fails the checksum
+        assert!(
+            from_host("duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczae.onion").is_none()
+        );
+        let i2p = AddrV2::I2p([7; 32]);
+        assert_eq!(from_host(&to_host(&i2p).unwrap()).unwrap(), i2p);
+        let cjdns = AddrV2::Cjdns("fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa".parse().unwrap());
+        assert_eq!(from_host(&to_host(&cjdns).unwrap()).unwrap(), cjdns);
+        assert_eq!(
+            from_host("95.217.198.121").unwrap(),
+            AddrV2::Ipv4(Ipv4Addr::new(95, 217, 198, 121))
+        );
+        assert_eq!(
+            from_ip(IpAddr::V6(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped())),
+            AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert!(to_host(&AddrV2::TorV2([0; 10])).is_none());
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let addrs = vec![
+            AddrV2::Ipv4(Ipv4Addr::new(95, 217, 198, 121)),
+            AddrV2::Ipv6("2001:db8::1".parse().unwrap()),
+            AddrV2::TorV2([1; 10]),
+            AddrV2::TorV3([2; 32]),
+            AddrV2::I2p([3; 32]),
+            AddrV2::Cjdns("fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa".parse().unwrap()),
+            AddrV2::Unknown(0x42, vec![4; 7]),
+        ];
+        for addr in addrs {
+            let (network, bytes) = to_bytes(&addr);
+            assert_eq!(from_bytes(network, &bytes).unwrap(), addr);
+        }
+        assert!(from_bytes(TORV3_ID, &[0; 31]).is_none());
+    }
+}
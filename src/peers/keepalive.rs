@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+// A connection that has been quiet for this long is pinged to check the peer is still there
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+// The peer must answer a ping within this time
+pub(crate) const PONG_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+struct PendingPing {
+    nonce: u64,
+    sent: Instant,
+}
+
+// Pings a peer when the connection is idle and measures the round trip time of the pings. Pings are only
+// sent over idle connections, so the round trip is not inflated by responses queued ahead of the pong.
+#[derive(Debug)]
+pub(crate) struct KeepAlive {
+    last_heard: Instant,
+    pending: Option<PendingPing>,
+    latency: Option<Duration>,
+    pong_timeout: Duration,
+}
+
+impl KeepAlive {
+    pub(crate) fn new(now: Instant, pong_timeout: Duration) -> Self {
+        Self {
+            last_heard: now,
+            pending: None,
+            latency: None,
+            pong_timeout,
+        }
+    }
+
+    // The peer sent us a message
+    pub(crate) fn heard(&mut self, now: Instant) {
+        self.last_heard = now;
+    }
+
+    // A ping should be sent if the connection is idle or the latency was never measured. Returns the nonce
+    // of the ping to send.
+    pub(crate) fn next_ping(&mut self, now: Instant) -> Option<u64> {
+        if self.pending.is_some() {
+            return None;
+        }
+        if self.latency.is_some() && now.lt(&(self.last_heard + PING_INTERVAL)) {
+            return None;
+        }
+        let nonce = rand::random();
+        self.pending = Some(PendingPing { nonce, sent: now });
+        Some(nonce)
+    }
+
+    // The peer answered a ping. Returns the round trip time if the pong answers our pending ping.
+    pub(crate) fn pong(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        match self.pending {
+            Some(ping) if ping.nonce.eq(&nonce) => {
+                self.pending = None;
+                let latency = now.saturating_duration_since(ping.sent);
+                self.latency = Some(latency);
+                Some(latency)
+            }
+            _ => None,
+        }
+    }
+
+    // The peer did not answer our ping in time
+    pub(crate) fn timed_out(&self, now: Instant) -> bool {
+        self.pending
+            .map_or(false, |ping| now.gt(&(ping.sent + self.pong_timeout)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pings_idle_connections() {
+        let now = Instant::now();
+        let mut keep_alive = KeepAlive::new(now, PONG_TIMEOUT);
+        // The latency is measured as soon as possible
+        let nonce = keep_alive.next_ping(now).unwrap();
+        assert_eq!(keep_alive.next_ping(now), None);
+        // A pong for another ping is not a response
+        assert_eq!(keep_alive.pong(nonce + 1, now), None);
+        let later = now + Duration::from_millis(150);
+        assert_eq!(
+            keep_alive.pong(nonce, later),
+            Some(Duration::from_millis(150))
+        );
+        keep_alive.heard(later);
+        assert_eq!(keep_alive.next_ping(later + Duration::from_secs(1)), None);
+        assert!(keep_alive.next_ping(later + PING_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn test_times_out_unanswered_pings() {
+        let now = Instant::now();
+        let mut keep_alive = KeepAlive::new(now, PONG_TIMEOUT);
+        assert!(!keep_alive.timed_out(now));
+        keep_alive.next_ping(now).unwrap();
+        // Other messages do not answer a ping
+        keep_alive.heard(now + PONG_TIMEOUT);
+        assert!(!keep_alive.timed_out(now + PONG_TIMEOUT));
+        assert!(keep_alive.timed_out(now + PONG_TIMEOUT + Duration::from_secs(1)));
+    }
+}
@@ -1,9 +1,13 @@
+pub(crate) mod addrv2;
 pub(crate) mod counter;
 #[cfg(feature = "dns")]
 pub(crate) mod dns;
+pub(crate) mod keepalive;
 pub(crate) mod outbound_messages;
+pub(crate) mod parsers;
 pub(crate) mod peer;
 pub(crate) mod reader;
+pub(crate) mod socks;
 
 #[cfg(test)]
 mod tests {
@@ -3,6 +3,7 @@ use std::{
     time::{SystemTime, UNIX_EPOCH},
 };
 
+use bip324::PacketWriter;
 use bitcoin::{
     consensus::serialize,
     hashes::Hash,
@@ -13,13 +14,72 @@ use bitcoin::{
         message_network::VersionMessage,
         Address, ServiceFlags,
     },
-    BlockHash, Network, Transaction,
+    BlockHash, Network, Transaction, Txid,
 };
 
 use crate::{node::channel_messages::GetBlockConfig, prelude::default_port_from_network};
 
+use super::peer::PeerError;
+
 pub const PROTOCOL_VERSION: u32 = 70015;
 
+// Serialize messages to send to a remote peer, independent of the transport.
+pub(crate) trait MessageGenerator: Send + Sync {
+    fn new_version_message(&mut self, port: Option<u16>) -> Result<Vec<u8>, PeerError>;
+
+    fn new_verack(&mut self) -> Result<Vec<u8>, PeerError>;
+
+    fn new_send_addr_v2(&mut self) -> Result<Vec<u8>, PeerError>;
+
+    fn new_get_addr(&mut self) -> Result<Vec<u8>, PeerError>;
+
+    fn new_get_headers(
+        &mut self,
+        locator_hashes: Vec<BlockHash>,
+        stop_hash: Option<BlockHash>,
+    ) -> Result<Vec<u8>, PeerError>;
+
+    fn new_cf_headers(&mut self, message: GetCFHeaders) -> Result<Vec<u8>, PeerError>;
+
+    fn new_filters(&mut self, message: GetCFilters) -> Result<Vec<u8>, PeerError>;
+
+    fn new_block(&mut self, config: GetBlockConfig) -> Result<Vec<u8>, PeerError>;
+
+    fn new_ping(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError>;
+
+    fn new_pong(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError>;
+
+    fn new_transaction(&mut self, transaction: Transaction) -> Result<Vec<u8>, PeerError>;
+
+    fn new_tx_inv(&mut self, txid: Txid) -> Result<Vec<u8>, PeerError>;
+
+    fn new_tx_request(&mut self, txid: Txid) -> Result<Vec<u8>, PeerError>;
+}
+
+pub(crate) fn make_version(network: &Network, port: Option<u16>) -> VersionMessage {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs();
+    let default_port = default_port_from_network(network);
+    let ip = SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        port.unwrap_or(default_port),
+    );
+    let from_and_recv = Address::new(&ip, ServiceFlags::NONE);
+    VersionMessage {
+        version: PROTOCOL_VERSION,
+        services: ServiceFlags::NONE,
+        timestamp: now as i64,
+        receiver: from_and_recv.clone(),
+        sender: from_and_recv,
+        nonce: 1,
+        user_agent: "kyoto".to_string(),
+        start_height: 0,
+        relay: false,
+    }
+}
+
 pub(crate) struct V1OutboundMessage {
     network: Network,
 }
@@ -29,84 +89,156 @@ impl V1OutboundMessage {
         Self { network }
     }
 
-    pub(crate) fn new_version_message(&self, port: Option<u16>) -> Vec<u8> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards")
-            .as_secs();
-        let default_port = default_port_from_network(&self.network);
-        let ip = SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            port.unwrap_or(default_port),
-        );
-        let from_and_recv = Address::new(&ip, ServiceFlags::NONE);
-        let msg = VersionMessage {
-            version: PROTOCOL_VERSION,
-            services: ServiceFlags::NONE,
-            timestamp: now as i64,
-            receiver: from_and_recv.clone(),
-            sender: from_and_recv,
-            nonce: 1,
-            user_agent: "kyoto".to_string(),
-            start_height: 0,
-            relay: false,
-        };
-        let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::Version(msg));
+    fn serialize(&self, msg: NetworkMessage) -> Vec<u8> {
+        let data = RawNetworkMessage::new(self.network.magic(), msg);
         serialize(&data)
     }
+}
 
-    pub(crate) fn new_verack(&self) -> Vec<u8> {
-        let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::Verack);
-        serialize(&data)
+impl MessageGenerator for V1OutboundMessage {
+    fn new_version_message(&mut self, port: Option<u16>) -> Result<Vec<u8>, PeerError> {
+        let msg = make_version(&self.network, port);
+        Ok(self.serialize(NetworkMessage::Version(msg)))
     }
 
-    pub(crate) fn new_get_addr(&self) -> Vec<u8> {
-        let data = RawNetworkMessage::new(self.network.magic(), NetworkMessage::GetAddr);
-        serialize(&data)
+    fn new_verack(&mut self) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::Verack))
+    }
+
+    fn new_send_addr_v2(&mut self) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::SendAddrV2))
     }
 
-    pub(crate) fn new_get_headers(
-        &self,
+    fn new_get_addr(&mut self) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::GetAddr))
+    }
+
+    fn new_get_headers(
+        &mut self,
         locator_hashes: Vec<BlockHash>,
         stop_hash: Option<BlockHash>,
-    ) -> Vec<u8> {
+    ) -> Result<Vec<u8>, PeerError> {
         let msg =
             GetHeadersMessage::new(locator_hashes, stop_hash.unwrap_or(BlockHash::all_zeros()));
-        let data =
-            &mut RawNetworkMessage::new(self.network.magic(), NetworkMessage::GetHeaders(msg));
-        serialize(&data)
+        Ok(self.serialize(NetworkMessage::GetHeaders(msg)))
     }
 
-    pub(crate) fn new_cf_headers(&self, message: GetCFHeaders) -> Vec<u8> {
-        let data = &mut RawNetworkMessage::new(
-            self.network.magic(),
-            NetworkMessage::GetCFHeaders(message),
-        );
-        serialize(&data)
+    fn new_cf_headers(&mut self, message: GetCFHeaders) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::GetCFHeaders(message)))
     }
 
-    pub(crate) fn new_filters(&self, message: GetCFilters) -> Vec<u8> {
-        let data =
-            &mut RawNetworkMessage::new(self.network.magic(), NetworkMessage::GetCFilters(message));
-        serialize(&data)
+    fn new_filters(&mut self, message: GetCFilters) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::GetCFilters(message)))
     }
 
-    pub(crate) fn new_block(&self, config: GetBlockConfig) -> Vec<u8> {
+    fn new_block(&mut self, config: GetBlockConfig) -> Result<Vec<u8>, PeerError> {
         let inv = Inventory::Block(config.locator);
-        let data =
-            &mut RawNetworkMessage::new(self.network.magic(), NetworkMessage::GetData(vec![inv]));
-        serialize(&data)
+        Ok(self.serialize(NetworkMessage::GetData(vec![inv])))
     }
 
-    pub(crate) fn new_pong(&self, nonce: u64) -> Vec<u8> {
-        let msg = NetworkMessage::Pong(nonce);
-        let data = &mut RawNetworkMessage::new(self.network.magic(), msg);
-        serialize(&data)
+    fn new_ping(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::Ping(nonce)))
     }
 
-    pub(crate) fn new_transaction(&self, transaction: Transaction) -> Vec<u8> {
-        let msg = NetworkMessage::Tx(transaction);
-        let data = &mut RawNetworkMessage::new(self.network.magic(), msg);
-        serialize(&data)
+    fn new_pong(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::Pong(nonce)))
+    }
+
+    fn new_transaction(&mut self, transaction: Transaction) -> Result<Vec<u8>, PeerError> {
+        Ok(self.serialize(NetworkMessage::Tx(transaction)))
+    }
+
+    fn new_tx_inv(&mut self, txid: Txid) -> Result<Vec<u8>, PeerError> {
+        let inv = Inventory::Transaction(txid);
+        Ok(self.serialize(NetworkMessage::Inv(vec![inv])))
+    }
+
+    fn new_tx_request(&mut self, txid: Txid) -> Result<Vec<u8>, PeerError> {
+        let inv = Inventory::Transaction(txid);
+        Ok(self.serialize(NetworkMessage::GetData(vec![inv])))
+    }
+}
+
+// Messages are encoded with short IDs and encrypted with the session keys from the handshake.
+pub(crate) struct V2OutboundMessage {
+    network: Network,
+    encryptor: PacketWriter,
+}
+
+impl V2OutboundMessage {
+    pub(crate) fn new(network: Network, encryptor: PacketWriter) -> Self {
+        Self { network, encryptor }
+    }
+
+    fn serialize(&mut self, msg: NetworkMessage) -> Result<Vec<u8>, PeerError> {
+        let plaintext =
+            bip324::serde::serialize(msg).map_err(|_| PeerError::MessageSerialization)?;
+        self.encryptor
+            .prepare_packet_with_alloc(&plaintext, None, false)
+            .map_err(|_| PeerError::MessageEncryption)
+    }
+}
+
+impl MessageGenerator for V2OutboundMessage {
+    fn new_version_message(&mut self, port: Option<u16>) -> Result<Vec<u8>, PeerError> {
+        let msg = make_version(&self.network, port);
+        self.serialize(NetworkMessage::Version(msg))
+    }
+
+    fn new_verack(&mut self) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::Verack)
+    }
+
+    fn new_send_addr_v2(&mut self) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::SendAddrV2)
+    }
+
+    fn new_get_addr(&mut self) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::GetAddr)
+    }
+
+    fn new_get_headers(
+        &mut self,
+        locator_hashes: Vec<BlockHash>,
+        stop_hash: Option<BlockHash>,
+    ) -> Result<Vec<u8>, PeerError> {
+        let msg =
+            GetHeadersMessage::new(locator_hashes, stop_hash.unwrap_or(BlockHash::all_zeros()));
+        self.serialize(NetworkMessage::GetHeaders(msg))
+    }
+
+    fn new_cf_headers(&mut self, message: GetCFHeaders) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::GetCFHeaders(message))
+    }
+
+    fn new_filters(&mut self, message: GetCFilters) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::GetCFilters(message))
+    }
+
+    fn new_block(&mut self, config: GetBlockConfig) -> Result<Vec<u8>, PeerError> {
+        let inv = Inventory::Block(config.locator);
+        self.serialize(NetworkMessage::GetData(vec![inv]))
+    }
+
+    fn new_ping(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::Ping(nonce))
+    }
+
+    fn new_pong(&mut self, nonce: u64) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::Pong(nonce))
+    }
+
+    fn new_transaction(&mut self, transaction: Transaction) -> Result<Vec<u8>, PeerError> {
+        self.serialize(NetworkMessage::Tx(transaction))
+    }
+
+    fn new_tx_inv(&mut self, txid: Txid) -> Result<Vec<u8>, PeerError> {
+        let inv = Inventory::Transaction(txid);
+        self.serialize(NetworkMessage::Inv(vec![inv]))
+    }
+
+    fn new_tx_request(&mut self, txid: Txid) -> Result<Vec<u8>, PeerError> {
+        let inv = Inventory::Transaction(txid);
+        self.serialize(NetworkMessage::GetData(vec![inv]))
     }
 }
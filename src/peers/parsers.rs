@@ -0,0 +1,152 @@
+use async_trait::async_trait;
+use bip324::{PacketReader, ReceivedMessage};
+use bitcoin::{
+    consensus::{deserialize, deserialize_partial, Decodable},
+    io::BufRead,
+    p2p::{
+        message::{NetworkMessage, RawNetworkMessage},
+        Magic,
+    },
+    Network,
+};
+use tokio::{io::AsyncReadExt, net::tcp::OwnedReadHalf};
+
+use super::reader::PeerReadError;
+
+// Messages larger than 32MB are nonsense
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024 * 32;
+// V2 packets begin with a three byte encrypted length
+const V2_LENGTH_BYTES: usize = 3;
+
+// Read the next message off the stream from a remote peer, independent of the transport.
+#[async_trait]
+pub(crate) trait MessageParser: Send + Sync {
+    // Returns `None` if the peer sent a message that may be safely ignored
+    async fn read_message(
+        &mut self,
+        stream: &mut OwnedReadHalf,
+    ) -> Result<Option<NetworkMessage>, PeerReadError>;
+}
+
+pub(crate) struct V1MessageParser {
+    network: Network,
+}
+
+impl V1MessageParser {
+    pub(crate) fn new(network: Network) -> Self {
+        Self { network }
+    }
+}
+
+#[async_trait]
+impl MessageParser for V1MessageParser {
+    async fn read_message(
+        &mut self,
+        stream: &mut OwnedReadHalf,
+    ) -> Result<Option<NetworkMessage>, PeerReadError> {
+        // v1 headers are 24 bytes
+        let mut message_buf = vec![0_u8; 24];
+        let _ = stream
+            .read_exact(&mut message_buf)
+            .await
+            .map_err(|_| PeerReadError::ReadBuffer)?;
+        let header: V1Header = deserialize_partial(&message_buf)
+            .map_err(|_| PeerReadError::Deserialization)?
+            .0;
+        // Nonsense for our network
+        if header.magic != self.network.magic() {
+            return Err(PeerReadError::Deserialization);
+        }
+        // Message is too long
+        if header.length > MAX_MESSAGE_BYTES as u32 {
+            return Err(PeerReadError::Deserialization);
+        }
+        let mut contents_buf = vec![0_u8; header.length as usize];
+        let _ = stream
+            .read_exact(&mut contents_buf)
+            .await
+            .map_err(|_| PeerReadError::ReadBuffer)?;
+        message_buf.extend_from_slice(&contents_buf);
+        let message: RawNetworkMessage =
+            deserialize(&message_buf).map_err(|_| PeerReadError::Deserialization)?;
+        Ok(Some(message.payload().clone()))
+    }
+}
+
+pub(crate) struct V2MessageParser {
+    decryptor: PacketReader,
+}
+
+impl V2MessageParser {
+    pub(crate) fn new(decryptor: PacketReader) -> Self {
+        Self { decryptor }
+    }
+}
+
+#[async_trait]
+impl MessageParser for V2MessageParser {
+    async fn read_message(
+        &mut self,
+        stream: &mut OwnedReadHalf,
+    ) -> Result<Option<NetworkMessage>, PeerReadError> {
+        let mut length_bytes = [0_u8; V2_LENGTH_BYTES];
+        let _ = stream
+            .read_exact(&mut length_bytes)
+            .await
+            .map_err(|_| PeerReadError::ReadBuffer)?;
+        // The length includes the decoy flag and authentication tag
+        let packet_length = self.decryptor.decypt_len(length_bytes);
+        if packet_length > MAX_MESSAGE_BYTES {
+            return Err(PeerReadError::Deserialization);
+        }
+        let mut packet_buf = vec![0_u8; packet_length];
+        let _ = stream
+            .read_exact(&mut packet_buf)
+            .await
+            .map_err(|_| PeerReadError::ReadBuffer)?;
+        let contents = self
+            .decryptor
+            .decrypt_contents_with_alloc(&packet_buf, None)
+            .map_err(|_| PeerReadError::Decryption)?;
+        let message = ReceivedMessage::new(&contents).map_err(|_| PeerReadError::Decryption)?;
+        match message.message {
+            Some(plaintext) => {
+                // An empty message or a truncated command would not decode
+                match plaintext.first() {
+                    Some(0) if plaintext.len() < 13 => return Err(PeerReadError::Deserialization),
+                    None => return Err(PeerReadError::Deserialization),
+                    _ => (),
+                }
+                let message = bip324::serde::deserialize(&plaintext)
+                    .map_err(|_| PeerReadError::Deserialization)?;
+                Ok(Some(message))
+            }
+            // The peer sent a decoy packet
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct V1Header {
+    magic: Magic,
+    _command: [u8; 12],
+    length: u32,
+    _checksum: u32,
+}
+
+impl Decodable for V1Header {
+    fn consensus_decode<R: BufRead + ?Sized>(
+        reader: &mut R,
+    ) -> Result<Self, bitcoin::consensus::encode::Error> {
+        let magic = Magic::consensus_decode(reader)?;
+        let _command = <[u8; 12]>::consensus_decode(reader)?;
+        let length = u32::consensus_decode(reader)?;
+        let _checksum = u32::consensus_decode(reader)?;
+        Ok(Self {
+            magic,
+            _command,
+            length,
+            _checksum,
+        })
+    }
+}
@@ -1,38 +1,74 @@
 extern crate tokio;
-use std::{net::IpAddr, time::Duration};
+use std::time::{Duration, Instant};
 
-use bitcoin::Network;
+use bip324::{Handshake, PacketHandler, Role};
+use bitcoin::{
+    p2p::{address::AddrV2, ServiceFlags},
+    Network,
+};
 use thiserror::Error;
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     net::{tcp::OwnedWriteHalf, TcpStream},
     select,
     sync::mpsc::{self, Receiver, Sender},
 };
 
 use crate::{
-    node::channel_messages::{MainThreadMessage, PeerMessage, PeerThreadMessage},
-    peers::outbound_messages::V1OutboundMessage,
+    node::{
+        channel_messages::{MainThreadMessage, PeerMessage, PeerThreadMessage},
+        misbehavior::Misbehavior,
+    },
+    peers::outbound_messages::{MessageGenerator, V1OutboundMessage, V2OutboundMessage},
     prelude::default_port_from_network,
+    ConnectionMode, TransportPreference,
+};
+
+use super::{
+    addrv2::to_ip,
+    counter::MessageCounter,
+    keepalive::{KeepAlive, PONG_TIMEOUT},
+    parsers::{MessageParser, V1MessageParser, V2MessageParser},
+    reader::{PeerReadError, Reader},
+    socks,
 };
 
-use super::{counter::MessageCounter, reader::Reader};
+// The ElligatorSwift encoding of a public key is 64 bytes
+const ELLSWIFT_BYTES: usize = 64;
+// The garbage terminator and an empty version packet
+const GARBAGE_TERMINATOR_AND_VERSION_BYTES: usize = 36;
+// The remote may send up to 4095 bytes of garbage before the terminator
+const MAX_GARBAGE_BYTES: usize = 4095;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// The connection is checked at least this often, even if no messages arrive
+const KEEPALIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub(crate) struct Peer {
     nonce: u32,
-    ip_addr: IpAddr,
+    addr: AddrV2,
     port: u16,
+    services: ServiceFlags,
+    transport: TransportPreference,
+    connection: ConnectionMode,
     main_thread_sender: Sender<PeerThreadMessage>,
     main_thread_recv: Receiver<MainThreadMessage>,
     network: Network,
     message_counter: MessageCounter,
+    // Set once the handshake is complete
+    keep_alive: Option<KeepAlive>,
+    // How long the peer has to answer a ping
+    pong_timeout: Duration,
 }
 
 impl Peer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         nonce: u32,
-        ip_addr: IpAddr,
+        addr: AddrV2,
         port: Option<u16>,
+        services: ServiceFlags,
+        transport: TransportPreference,
+        connection: ConnectionMode,
         network: Network,
         main_thread_sender: Sender<PeerThreadMessage>,
         main_thread_recv: Receiver<MainThreadMessage>,
@@ -41,63 +77,75 @@ impl Peer {
         let message_counter = MessageCounter::new();
         Self {
             nonce,
-            ip_addr,
+            addr,
             port: port.unwrap_or(default_port),
+            services,
+            transport,
+            connection,
             main_thread_sender,
             main_thread_recv,
             network,
             message_counter,
+            keep_alive: None,
+            pong_timeout: PONG_TIMEOUT,
         }
     }
 
     pub async fn connect(&mut self) -> Result<(), PeerError> {
-        let timeout = tokio::time::timeout(
-            Duration::from_secs(5),
-            TcpStream::connect((self.ip_addr, self.port)),
-        )
-        .await
-        .map_err(|_| PeerError::TcpConnectionFailed)?;
-        let mut stream: TcpStream;
-        if let Ok(tcp) = timeout {
-            stream = tcp;
+        let mut stream = self.tcp_connect().await?;
+        let (mut outbound_messages, message_parser) = if self.should_try_v2() {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.try_v2_handshake(&mut stream)).await
+            {
+                Ok(Ok(packet_handler)) => {
+                    let (decryptor, encryptor) = packet_handler.into_split();
+                    let outbound_messages: Box<dyn MessageGenerator> =
+                        Box::new(V2OutboundMessage::new(self.network, encryptor));
+                    let message_parser: Box<dyn MessageParser> =
+                        Box::new(V2MessageParser::new(decryptor));
+                    (outbound_messages, message_parser)
+                }
+                _ => {
+                    if self.transport.eq(&TransportPreference::V2Only) {
+                        self.send_disconnect().await;
+                        return Err(PeerError::V2HandshakeFailed);
+                    }
+                    // The remote most likely closed the connection because it does not understand V2
+                    stream = self.tcp_connect().await?;
+                    self.v1_transport()
+                }
+            }
         } else {
-            let _ = self
-                .main_thread_sender
-                .send(PeerThreadMessage {
-                    nonce: self.nonce,
-                    message: PeerMessage::Disconnect,
-                })
-                .await;
-            return Err(PeerError::TcpConnectionFailed);
-        }
-        let outbound_messages = V1OutboundMessage::new(self.network);
-        let version_message = outbound_messages.new_version_message(None);
+            self.v1_transport()
+        };
+        let version_message = outbound_messages.new_version_message(None)?;
         stream
             .write_all(&version_message)
             .await
             .map_err(|_| PeerError::BufferWrite)?;
         let (reader, mut writer) = stream.into_split();
         let (tx, mut rx) = mpsc::channel(32);
-        let mut peer_reader = Reader::new(reader, tx, self.network);
-        let read_handle = tokio::spawn(async move {
-            match peer_reader.read_from_remote().await {
-                Ok(_) => Ok(()),
-                Err(_) => Err(PeerError::Reader),
-            }
-        });
+        let mut peer_reader = Reader::new(reader, tx, message_parser);
+        let mut read_handle = tokio::spawn(async move { peer_reader.read_from_remote().await });
         loop {
             if read_handle.is_finished() {
+                if let Ok(Err(PeerReadError::TooManyMessages)) = (&mut read_handle).await {
+                    self.send_misbehavior(Misbehavior::MessageFlood).await;
+                }
                 return Ok(());
             }
             if self.message_counter.unsolicited() {
+                self.send_misbehavior(Misbehavior::UnsolicitedMessages)
+                    .await;
                 return Ok(());
             }
+            self.keep_alive(&mut writer, outbound_messages.as_mut())
+                .await?;
             select! {
                 // The peer sent us a message
                 peer_message = rx.recv() => {
                     match peer_message {
                         Some(message) => {
-                            match self.handle_peer_message(message, &mut writer, &outbound_messages).await {
+                            match self.handle_peer_message(message, &mut writer, outbound_messages.as_mut()).await {
                                 Ok(()) => continue,
                                 Err(e) => {
                                     match e {
@@ -115,7 +163,7 @@ impl Peer {
                 node_message = self.main_thread_recv.recv() => {
                     match node_message {
                         Some(message) => {
-                            match self.main_thread_request(message, &mut writer, &outbound_messages).await {
+                            match self.main_thread_request(message, &mut writer, outbound_messages.as_mut()).await {
                                 Ok(()) => continue,
                                 Err(e) => {
                                     match e {
@@ -129,19 +177,182 @@ impl Peer {
                         None => continue,
                     }
                 }
+                _ = tokio::time::sleep(KEEPALIVE_CHECK_INTERVAL) => continue,
+            }
+        }
+    }
+
+    // Ping the peer if the connection is idle, and give up on a peer that did not answer a ping in time
+    async fn keep_alive(
+        &mut self,
+        writer: &mut OwnedWriteHalf,
+        message_generator: &mut dyn MessageGenerator,
+    ) -> Result<(), PeerError> {
+        let now = Instant::now();
+        if self
+            .keep_alive
+            .as_ref()
+            .map_or(false, |keep_alive| keep_alive.timed_out(now))
+        {
+            self.send_disconnect().await;
+            return Err(PeerError::PeerTimeout);
+        }
+        let keep_alive = match self.keep_alive.as_mut() {
+            Some(keep_alive) => keep_alive,
+            None => return Ok(()),
+        };
+        if let Some(nonce) = keep_alive.next_ping(now) {
+            writer
+                .write_all(&message_generator.new_ping(nonce)?)
+                .await
+                .map_err(|_| PeerError::BufferWrite)?;
+        }
+        Ok(())
+    }
+
+    async fn tcp_connect(&mut self) -> Result<TcpStream, PeerError> {
+        if !self.connection.can_reach(&self.addr) {
+            self.send_disconnect().await;
+            return Err(PeerError::UnreachableNetwork);
+        }
+        let stream = match self.connection {
+            ConnectionMode::Direct => {
+                let ip = to_ip(&self.addr).ok_or(PeerError::UnreachableNetwork)?;
+                match tokio::time::timeout(
+                    Duration::from_secs(5),
+                    TcpStream::connect((ip, self.port)),
+                )
+                .await
+                {
+                    Ok(Ok(stream)) => Ok(stream),
+                    _ => Err(PeerError::TcpConnectionFailed),
+                }
+            }
+            ConnectionMode::Socks5Proxy(proxy) | ConnectionMode::Socks5ProxyNoClearnet(proxy) => {
+                socks::connect(proxy, &self.addr, self.port)
+                    .await
+                    .map_err(|_| PeerError::Proxy)
+            }
+        };
+        if stream.is_err() {
+            self.send_disconnect().await;
+        }
+        stream
+    }
+
+    async fn send_disconnect(&mut self) {
+        let _ = self
+            .main_thread_sender
+            .send(PeerThreadMessage {
+                nonce: self.nonce,
+                message: PeerMessage::Disconnect,
+            })
+            .await;
+    }
+
+    // The connection is closed, so the main thread learns who broke the protocol from this message
+    async fn send_misbehavior(&mut self, misbehavior: Misbehavior) {
+        let _ = self
+            .main_thread_sender
+            .send(PeerThreadMessage {
+                nonce: self.nonce,
+                message: PeerMessage::Misbehaved {
+                    misbehavior,
+                    addr: self.addr.clone(),
+                    port: self.port,
+                    services: self.services,
+                },
+            })
+            .await;
+    }
+
+    // Peers we have not learned the services of are given the benefit of the doubt
+    fn should_try_v2(&self) -> bool {
+        match self.transport {
+            TransportPreference::V1Only => false,
+            TransportPreference::V2WithFallback => {
+                self.services.eq(&ServiceFlags::NONE) || self.services.has(ServiceFlags::P2P_V2)
+            }
+            TransportPreference::V2Only => true,
+        }
+    }
+
+    fn v1_transport(&self) -> (Box<dyn MessageGenerator>, Box<dyn MessageParser>) {
+        (
+            Box::new(V1OutboundMessage::new(self.network)),
+            Box::new(V1MessageParser::new(self.network)),
+        )
+    }
+
+    // Exchange public keys, derive the session secrets, and authenticate the garbage and version packets.
+    async fn try_v2_handshake(
+        &mut self,
+        stream: &mut TcpStream,
+    ) -> Result<PacketHandler, PeerError> {
+        let mut public_key = [0_u8; ELLSWIFT_BYTES];
+        let mut handshake = Handshake::new(self.network, Role::Initiator, None, &mut public_key)
+            .map_err(|_| PeerError::V2HandshakeFailed)?;
+        stream
+            .write_all(&public_key)
+            .await
+            .map_err(|_| PeerError::BufferWrite)?;
+        let mut remote_public_key = [0_u8; ELLSWIFT_BYTES];
+        stream
+            .read_exact(&mut remote_public_key)
+            .await
+            .map_err(|_| PeerError::V2HandshakeFailed)?;
+        let mut local_terminator_and_version = [0_u8; GARBAGE_TERMINATOR_AND_VERSION_BYTES];
+        handshake
+            .complete_materials(remote_public_key, &mut local_terminator_and_version)
+            .map_err(|_| PeerError::V2HandshakeFailed)?;
+        stream
+            .write_all(&local_terminator_and_version)
+            .await
+            .map_err(|_| PeerError::BufferWrite)?;
+        let mut remote_garbage_and_version = vec![0_u8; GARBAGE_TERMINATOR_AND_VERSION_BYTES];
+        stream
+            .read_exact(&mut remote_garbage_and_version)
+            .await
+            .map_err(|_| PeerError::V2HandshakeFailed)?;
+        // Read one byte at a time so we never consume any messages after the version packet
+        loop {
+            match handshake.authenticate_garbage_and_version(&remote_garbage_and_version) {
+                Ok(()) => break,
+                Err(bip324::Error::MessageLengthTooSmall) => {
+                    if remote_garbage_and_version.len()
+                        > MAX_GARBAGE_BYTES + GARBAGE_TERMINATOR_AND_VERSION_BYTES
+                    {
+                        return Err(PeerError::V2HandshakeFailed);
+                    }
+                    let mut next = [0_u8; 1];
+                    stream
+                        .read_exact(&mut next)
+                        .await
+                        .map_err(|_| PeerError::V2HandshakeFailed)?;
+                    remote_garbage_and_version.push(next[0]);
+                }
+                Err(_) => return Err(PeerError::V2HandshakeFailed),
             }
         }
+        handshake
+            .finalize()
+            .map_err(|_| PeerError::V2HandshakeFailed)
     }
 
     async fn handle_peer_message(
         &mut self,
         message: PeerMessage,
         writer: &mut OwnedWriteHalf,
-        message_generator: &V1OutboundMessage,
+        message_generator: &mut dyn MessageGenerator,
     ) -> Result<(), PeerError> {
+        if let Some(keep_alive) = self.keep_alive.as_mut() {
+            keep_alive.heard(Instant::now());
+        }
         match message {
             PeerMessage::Version(version) => {
                 self.message_counter.got_version();
+                // The services the peer signals now are more recent than the ones we stored
+                self.services = version.service_flags;
                 self.main_thread_sender
                     .send(PeerThreadMessage {
                         nonce: self.nonce,
@@ -149,8 +360,13 @@ impl Peer {
                     })
                     .await
                     .map_err(|_| PeerError::ThreadChannel)?;
+                // BIP-155 requires `sendaddrv2` to be sent before `verack`
                 writer
-                    .write_all(&message_generator.new_verack())
+                    .write_all(&message_generator.new_send_addr_v2()?)
+                    .await
+                    .map_err(|_| PeerError::BufferWrite)?;
+                writer
+                    .write_all(&message_generator.new_verack()?)
                     .await
                     .map_err(|_| PeerError::BufferWrite)?;
                 Ok(())
@@ -210,6 +426,20 @@ impl Peer {
                     .map_err(|_| PeerError::ThreadChannel)?;
                 Ok(())
             }
+            PeerMessage::BlocksNotFound(block_hashes) => {
+                // A `notfound` is the response to the request for these blocks
+                for _ in &block_hashes {
+                    self.message_counter.got_block();
+                }
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::BlocksNotFound(block_hashes),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+                Ok(())
+            }
             PeerMessage::NewBlocks(block_hashes) => {
                 self.main_thread_sender
                     .send(PeerThreadMessage {
@@ -220,18 +450,59 @@ impl Peer {
                     .map_err(|_| PeerError::ThreadChannel)?;
                 Ok(())
             }
+            PeerMessage::TxRequests(txids) => {
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::TxRequests(txids),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+                Ok(())
+            }
+            PeerMessage::Transaction(transaction) => {
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::Transaction(transaction),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+                Ok(())
+            }
             PeerMessage::Verack => {
                 self.message_counter.got_verack();
+                // Once the handshake is complete, the connection is kept alive with pings
+                self.keep_alive = Some(KeepAlive::new(Instant::now(), self.pong_timeout));
                 Ok(())
             }
             PeerMessage::Ping(nonce) => {
                 writer
-                    .write_all(&message_generator.new_pong(nonce))
+                    .write_all(&message_generator.new_pong(nonce)?)
                     .await
                     .map_err(|_| PeerError::BufferWrite)?;
                 Ok(())
             }
-            PeerMessage::Pong(_) => Ok(()),
+            PeerMessage::Pong(nonce) => {
+                let latency = self
+                    .keep_alive
+                    .as_mut()
+                    .and_then(|keep_alive| keep_alive.pong(nonce, Instant::now()));
+                if let Some(latency) = latency {
+                    self.main_thread_sender
+                        .send(PeerThreadMessage {
+                            nonce: self.nonce,
+                            message: PeerMessage::Latency(latency),
+                        })
+                        .await
+                        .map_err(|_| PeerError::ThreadChannel)?;
+                }
+                Ok(())
+            }
+            // Only sent by this thread
+            PeerMessage::Latency(_)
+            | PeerMessage::Misbehaved { .. }
+            | PeerMessage::TxWritten(_) => Ok(()),
             PeerMessage::Disconnect => {
                 self.main_thread_sender
                     .send(PeerThreadMessage {
@@ -249,19 +520,20 @@ impl Peer {
         &mut self,
         request: MainThreadMessage,
         writer: &mut OwnedWriteHalf,
-        message_generator: &V1OutboundMessage,
+        message_generator: &mut dyn MessageGenerator,
     ) -> Result<(), PeerError> {
         match request {
             MainThreadMessage::GetAddr => {
                 self.message_counter.sent_addrs();
                 writer
-                    .write_all(&message_generator.new_get_addr())
+                    .write_all(&message_generator.new_get_addr()?)
                     .await
                     .map_err(|_| PeerError::BufferWrite)?;
             }
             MainThreadMessage::GetHeaders(config) => {
                 self.message_counter.sent_header();
-                let message = message_generator.new_get_headers(config.locators, config.stop_hash);
+                let message =
+                    message_generator.new_get_headers(config.locators, config.stop_hash)?;
                 writer
                     .write_all(&message)
                     .await
@@ -269,7 +541,7 @@ impl Peer {
             }
             MainThreadMessage::GetFilterHeaders(config) => {
                 self.message_counter.sent_filter_header();
-                let message = message_generator.new_cf_headers(config);
+                let message = message_generator.new_cf_headers(config)?;
                 writer
                     .write_all(&message)
                     .await
@@ -277,7 +549,7 @@ impl Peer {
             }
             MainThreadMessage::GetFilters(config) => {
                 self.message_counter.sent_filters();
-                let message = message_generator.new_filters(config);
+                let message = message_generator.new_filters(config)?;
                 writer
                     .write_all(&message)
                     .await
@@ -285,14 +557,36 @@ impl Peer {
             }
             MainThreadMessage::GetBlock(message) => {
                 self.message_counter.sent_block();
-                let message = message_generator.new_block(message);
+                let message = message_generator.new_block(message)?;
                 writer
                     .write_all(&message)
                     .await
                     .map_err(|_| PeerError::BufferWrite)?;
             }
-            MainThreadMessage::BroadcastTx(transaction) => {
-                let message = message_generator.new_transaction(transaction);
+            MainThreadMessage::AnnounceTx(txid) => {
+                let message = message_generator.new_tx_inv(txid)?;
+                writer
+                    .write_all(&message)
+                    .await
+                    .map_err(|_| PeerError::BufferWrite)?;
+            }
+            MainThreadMessage::SendTx(transaction) => {
+                let txid = transaction.compute_txid();
+                let message = message_generator.new_transaction(transaction)?;
+                writer
+                    .write_all(&message)
+                    .await
+                    .map_err(|_| PeerError::BufferWrite)?;
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::TxWritten(txid),
+                    })
+                    .await
+                    .map_err(|_| PeerError::ThreadChannel)?;
+            }
+            MainThreadMessage::RequestTx(txid) => {
+                let message = message_generator.new_tx_request(txid)?;
                 writer
                     .write_all(&message)
                     .await
@@ -332,4 +626,341 @@ pub enum PeerError {
     DisconnectCommand,
     #[error("the ereading thread encountered an error")]
     Reader,
+    #[error("the remote peer did not complete the encrypted V2 handshake")]
+    V2HandshakeFailed,
+    #[error("a message could not be serialized")]
+    MessageSerialization,
+    #[error("a message could not be encrypted")]
+    MessageEncryption,
+    #[error("the peer's network cannot be reached with the configured connection mode")]
+    UnreachableNetwork,
+    #[error("the proxy could not connect to the peer")]
+    Proxy,
+    #[error("the peer did not answer a ping in time")]
+    PeerTimeout,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use bip324::{Handshake, ReceivedMessage, Role};
+    use bitcoin::{
+        consensus::deserialize,
+        p2p::{
+            address::AddrV2,
+            message::{NetworkMessage, RawNetworkMessage},
+            ServiceFlags,
+        },
+        Network,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc::{self, Receiver},
+    };
+
+    use crate::{
+        node::channel_messages::{PeerMessage, PeerThreadMessage},
+        peers::outbound_messages::{make_version, MessageGenerator, V1OutboundMessage},
+        ConnectionMode, TransportPreference,
+    };
+
+    use super::{Peer, PeerError};
+
+    const NETWORK: Network = Network::Signet;
+
+    fn spawn_peer(
+        port: u16,
+        services: ServiceFlags,
+        transport: TransportPreference,
+    ) -> (
+        Receiver<PeerThreadMessage>,
+        tokio::task::JoinHandle<Result<(), PeerError>>,
+    ) {
+        spawn_peer_with_connection(port, services, transport, ConnectionMode::Direct)
+    }
+
+    fn spawn_peer_with_connection(
+        port: u16,
+        services: ServiceFlags,
+        transport: TransportPreference,
+        connection: ConnectionMode,
+    ) -> (
+        Receiver<PeerThreadMessage>,
+        tokio::task::JoinHandle<Result<(), PeerError>>,
+    ) {
+        spawn_peer_with(port, services, transport, connection, |_| ())
+    }
+
+    // Spawn a peer after adjusting it for the test
+    fn spawn_peer_with(
+        port: u16,
+        services: ServiceFlags,
+        transport: TransportPreference,
+        connection: ConnectionMode,
+        configure: impl FnOnce(&mut Peer),
+    ) -> (
+        Receiver<PeerThreadMessage>,
+        tokio::task::JoinHandle<Result<(), PeerError>>,
+    ) {
+        let (mtx, mrx) = mpsc::channel(32);
+        let (ptx, prx) = mpsc::channel(32);
+        let mut peer = Peer::new(
+            1,
+            AddrV2::Ipv4(Ipv4Addr::LOCALHOST),
+            Some(port),
+            services,
+            transport,
+            connection,
+            NETWORK,
+            mtx,
+            prx,
+        );
+        configure(&mut peer);
+        let handle = tokio::spawn(async move {
+            // Hold the main thread sender for the lifetime of the connection
+            let _ptx = ptx;
+            peer.connect().await
+        });
+        (mrx, handle)
+    }
+
+    // Act as a V1 remote node: read the version message and reply with our own
+    async fn respond_v1(stream: &mut TcpStream) {
+        let mut header = [0_u8; 24];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[..4], NETWORK.magic().to_bytes());
+        let length = u32::from_le_bytes(header[16..20].try_into().unwrap());
+        let mut payload = vec![0_u8; length as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        let version = V1OutboundMessage::new(NETWORK)
+            .new_version_message(None)
+            .unwrap();
+        stream.write_all(&version).await.unwrap();
+    }
+
+    // Read a V1 message from the node under test
+    async fn read_v1(stream: &mut TcpStream) -> NetworkMessage {
+        let mut message = vec![0_u8; 24];
+        stream.read_exact(&mut message).await.unwrap();
+        let length = u32::from_le_bytes(message[16..20].try_into().unwrap());
+        let mut payload = vec![0_u8; length as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        message.extend(payload);
+        deserialize::<RawNetworkMessage>(&message)
+            .unwrap()
+            .payload()
+            .clone()
+    }
+
+    async fn expect_version(mrx: &mut Receiver<PeerThreadMessage>) {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), mrx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(message.message, PeerMessage::Version(_)));
+    }
+
+    #[tokio::test]
+    async fn test_v2_handshake() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut mrx, _handle) = spawn_peer(
+            port,
+            ServiceFlags::P2P_V2,
+            TransportPreference::V2WithFallback,
+        );
+        let (mut stream, _) = listener.accept().await.unwrap();
+        // Act as a V2 responder
+        let mut remote_key = [0_u8; 64];
+        stream.read_exact(&mut remote_key).await.unwrap();
+        // The public key is followed by some garbage the initiator must skip
+        let garbage = [7_u8; 42];
+        let mut key_and_garbage = [0_u8; 64 + 42];
+        let mut handshake = Handshake::new(
+            NETWORK,
+            Role::Responder,
+            Some(&garbage),
+            &mut key_and_garbage,
+        )
+        .unwrap();
+        stream.write_all(&key_and_garbage).await.unwrap();
+        let mut terminator_and_version = [0_u8; 36];
+        handshake
+            .complete_materials(remote_key, &mut terminator_and_version)
+            .unwrap();
+        stream.write_all(&terminator_and_version).await.unwrap();
+        let mut remote_terminator_and_version = [0_u8; 36];
+        stream
+            .read_exact(&mut remote_terminator_and_version)
+            .await
+            .unwrap();
+        handshake
+            .authenticate_garbage_and_version(&remote_terminator_and_version)
+            .unwrap();
+        let (mut decryptor, mut encryptor) = handshake.finalize().unwrap().into_split();
+        // The first encrypted message should be a version message
+        let mut length_bytes = [0_u8; 3];
+        stream.read_exact(&mut length_bytes).await.unwrap();
+        let mut packet = vec![0_u8; decryptor.decypt_len(length_bytes)];
+        stream.read_exact(&mut packet).await.unwrap();
+        let contents = decryptor
+            .decrypt_contents_with_alloc(&packet, None)
+            .unwrap();
+        let plaintext = ReceivedMessage::new(&contents).unwrap().message.unwrap();
+        let message = bip324::serde::deserialize(&plaintext).unwrap();
+        assert!(matches!(message, NetworkMessage::Version(_)));
+        // Reply with a decoy and then our own version
+        let decoy = encryptor
+            .prepare_packet_with_alloc(&[], None, true)
+            .unwrap();
+        stream.write_all(&decoy).await.unwrap();
+        let version =
+            bip324::serde::serialize(NetworkMessage::Version(make_version(&NETWORK, None)))
+                .unwrap();
+        let packet = encryptor
+            .prepare_packet_with_alloc(&version, None, false)
+            .unwrap();
+        stream.write_all(&packet).await.unwrap();
+        expect_version(&mut mrx).await;
+    }
+
+    #[tokio::test]
+    async fn test_v2_falls_back_to_v1() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut mrx, _handle) = spawn_peer(
+            port,
+            ServiceFlags::NONE,
+            TransportPreference::V2WithFallback,
+        );
+        // A V1 node will interpret the public key as a bad header and disconnect
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut header = [0_u8; 24];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_ne!(header[..4], NETWORK.magic().to_bytes());
+        drop(stream);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        respond_v1(&mut stream).await;
+        expect_version(&mut mrx).await;
+    }
+
+    #[tokio::test]
+    async fn test_v1_only_skips_handshake() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut mrx, _handle) =
+            spawn_peer(port, ServiceFlags::P2P_V2, TransportPreference::V1Only);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        respond_v1(&mut stream).await;
+        expect_version(&mut mrx).await;
+    }
+
+    #[tokio::test]
+    async fn test_pings_after_handshake() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut mrx, _handle) = spawn_peer(port, ServiceFlags::NONE, TransportPreference::V1Only);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        respond_v1(&mut stream).await;
+        expect_version(&mut mrx).await;
+        let mut outbound = V1OutboundMessage::new(NETWORK);
+        stream
+            .write_all(&outbound.new_verack().unwrap())
+            .await
+            .unwrap();
+        let nonce = loop {
+            match read_v1(&mut stream).await {
+                NetworkMessage::Ping(nonce) => break nonce,
+                NetworkMessage::SendAddrV2 | NetworkMessage::Verack => continue,
+                message => panic!("unexpected message {:?}", message),
+            }
+        };
+        stream
+            .write_all(&outbound.new_pong(nonce).unwrap())
+            .await
+            .unwrap();
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), mrx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(message.message, PeerMessage::Latency(_)));
+    }
+
+    #[tokio::test]
+    async fn test_disconnects_when_pong_never_arrives() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut mrx, handle) = spawn_peer_with(
+            port,
+            ServiceFlags::NONE,
+            TransportPreference::V1Only,
+            ConnectionMode::Direct,
+            |peer| peer.pong_timeout = std::time::Duration::from_millis(100),
+        );
+        let (mut stream, _) = listener.accept().await.unwrap();
+        respond_v1(&mut stream).await;
+        expect_version(&mut mrx).await;
+        stream
+            .write_all(&V1OutboundMessage::new(NETWORK).new_verack().unwrap())
+            .await
+            .unwrap();
+        // Read the ping and never answer it
+        loop {
+            match read_v1(&mut stream).await {
+                NetworkMessage::Ping(_) => break,
+                NetworkMessage::SendAddrV2 | NetworkMessage::Verack => continue,
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), mrx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(message.message, PeerMessage::Disconnect));
+        assert!(matches!(handle.await.unwrap(), Err(PeerError::PeerTimeout)));
+    }
+
+    #[tokio::test]
+    async fn test_v2_only_refuses_v1() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (mut mrx, handle) = spawn_peer(port, ServiceFlags::NONE, TransportPreference::V2Only);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut header = [0_u8; 24];
+        stream.read_exact(&mut header).await.unwrap();
+        drop(stream);
+        let message = mrx.recv().await.unwrap();
+        assert!(matches!(message.message, PeerMessage::Disconnect));
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(PeerError::V2HandshakeFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_clearnet_refuses_ip_peers() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let proxy = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let (mut mrx, handle) = spawn_peer_with_connection(
+            port,
+            ServiceFlags::NONE,
+            TransportPreference::V1Only,
+            ConnectionMode::Socks5ProxyNoClearnet(proxy),
+        );
+        let message = mrx.recv().await.unwrap();
+        assert!(matches!(message.message, PeerMessage::Disconnect));
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(PeerError::UnreachableNetwork)
+        ));
+        // Not even the proxy was contacted
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), listener.accept())
+                .await
+                .is_err()
+        );
+    }
 }
@@ -1,25 +1,21 @@
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
-use bitcoin::consensus::deserialize;
-use bitcoin::consensus::deserialize_partial;
-use bitcoin::consensus::Decodable;
-use bitcoin::io::BufRead;
+use bitcoin::p2p::address::{AddrV2, AddrV2Message};
 use bitcoin::p2p::message::NetworkMessage;
-use bitcoin::p2p::message::RawNetworkMessage;
 use bitcoin::p2p::message_blockdata::Inventory;
-use bitcoin::p2p::Address;
-use bitcoin::p2p::Magic;
 use bitcoin::p2p::ServiceFlags;
-use bitcoin::Network;
+use bitcoin::{BlockHash, Txid};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
 use tokio::net::tcp::OwnedReadHalf;
 use tokio::sync::mpsc::Sender;
 
 use crate::node::channel_messages::PeerMessage;
 use crate::node::channel_messages::RemoteVersion;
 
+use super::addrv2::from_ip;
+use super::parsers::MessageParser;
+
 const ONE_MONTH: u64 = 2_500_000;
 const ONE_MINUTE: u64 = 60;
 // The peer must have sent at least 10 messages to trigger DOS
@@ -32,11 +28,15 @@ pub(crate) struct Reader {
     start_time: u64,
     stream: OwnedReadHalf,
     tx: Sender<PeerMessage>,
-    network: Network,
+    parser: Box<dyn MessageParser>,
 }
 
 impl Reader {
-    pub fn new(stream: OwnedReadHalf, tx: Sender<PeerMessage>, network: Network) -> Self {
+    pub fn new(
+        stream: OwnedReadHalf,
+        tx: Sender<PeerMessage>,
+        parser: Box<dyn MessageParser>,
+    ) -> Self {
         let start_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("time went backwards")
@@ -46,30 +46,13 @@ impl Reader {
             start_time,
             stream,
             tx,
-            network,
+            parser,
         }
     }
 
     pub(crate) async fn read_from_remote(&mut self) -> Result<(), PeerReadError> {
         loop {
-            // v1 headers are 24 bytes
-            let mut message_buf = vec![0_u8; 24];
-            let _ = self
-                .stream
-                .read_exact(&mut message_buf)
-                .await
-                .map_err(|_| PeerReadError::ReadBuffer)?;
-            let header: V1Header = deserialize_partial(&message_buf)
-                .map_err(|_| PeerReadError::Deserialization)?
-                .0;
-            // Nonsense for our network
-            if header.magic != self.network.magic() {
-                return Err(PeerReadError::Deserialization);
-            }
-            // Message is too long
-            if header.length > (1024 * 1024 * 32) as u32 {
-                return Err(PeerReadError::Deserialization);
-            }
+            let message = self.parser.read_message(&mut self.stream).await?;
             // DOS protection
             self.num_messages += 1;
             let now = SystemTime::now()
@@ -82,12 +65,11 @@ impl Reader {
             {
                 return Err(PeerReadError::TooManyMessages);
             }
-            let mut contents_buf = vec![0_u8; header.length as usize];
-            let _ = self.stream.read_exact(&mut contents_buf).await.unwrap();
-            message_buf.extend_from_slice(&contents_buf);
-            let message: RawNetworkMessage =
-                deserialize(&message_buf).map_err(|_| PeerReadError::Deserialization)?;
-            let cleaned_message = parse_message(message.payload());
+            let cleaned_message = match message {
+                Some(message) => parse_message(&message),
+                // Decoy messages are ignored
+                None => continue,
+            };
             match cleaned_message {
                 Some(message) => self
                     .tx
@@ -114,15 +96,21 @@ fn parse_message(message: &NetworkMessage) -> Option<PeerMessage> {
                 .expect("time went backwards")
                 .as_secs()
                 - ONE_MONTH;
-            let addresses: Vec<Address> = addresses
+            let addresses: Vec<AddrV2Message> = addresses
                 .iter()
                 .filter(|f| {
                     f.1.services.has(ServiceFlags::COMPACT_FILTERS)
                         && f.1.services.has(ServiceFlags::WITNESS)
                 })
-                .filter(|f| f.1.socket_addr().is_ok())
                 .filter(|f| f.0 > last_month as u32)
-                .map(|(_, addr)| addr.clone())
+                .filter_map(|(time, addr)| {
+                    addr.socket_addr().ok().map(|socket_addr| AddrV2Message {
+                        time: *time,
+                        services: addr.services,
+                        addr: from_ip(socket_addr.ip()),
+                        port: addr.port,
+                    })
+                })
                 .collect();
             Some(PeerMessage::Addr(addresses))
         }
@@ -142,12 +130,40 @@ fn parse_message(message: &NetworkMessage) -> Option<PeerMessage> {
                 None
             }
         }
-        NetworkMessage::GetData(_) => None,
-        NetworkMessage::NotFound(_) => None,
+        NetworkMessage::GetData(inventory) => {
+            let txids: Vec<Txid> = inventory
+                .iter()
+                .filter_map(|i| match i {
+                    Inventory::Transaction(txid) => Some(*txid),
+                    Inventory::WitnessTransaction(txid) => Some(*txid),
+                    _ => None,
+                })
+                .collect();
+            if !txids.is_empty() {
+                Some(PeerMessage::TxRequests(txids))
+            } else {
+                None
+            }
+        }
+        NetworkMessage::NotFound(inventory) => {
+            let hashes: Vec<BlockHash> = inventory
+                .iter()
+                .filter_map(|i| match i {
+                    Inventory::Block(hash) => Some(*hash),
+                    Inventory::WitnessBlock(hash) => Some(*hash),
+                    _ => None,
+                })
+                .collect();
+            if !hashes.is_empty() {
+                Some(PeerMessage::BlocksNotFound(hashes))
+            } else {
+                None
+            }
+        }
         NetworkMessage::GetBlocks(_) => None,
         NetworkMessage::GetHeaders(_) => None,
         NetworkMessage::MemPool => None,
-        NetworkMessage::Tx(_) => None,
+        NetworkMessage::Tx(transaction) => Some(PeerMessage::Transaction(transaction.clone())),
         NetworkMessage::Block(block) => Some(PeerMessage::Block(block.clone())),
         NetworkMessage::Headers(headers) => Some(PeerMessage::Headers(headers.clone())),
         NetworkMessage::SendHeaders => None,
@@ -180,26 +196,16 @@ fn parse_message(message: &NetworkMessage) -> Option<PeerMessage> {
                 .expect("time went backwards")
                 .as_secs()
                 - ONE_MONTH;
-            let addresses: Vec<Address> = addresses
+            let addresses: Vec<AddrV2Message> = addresses
                 .iter()
                 .filter(|f| {
                     f.services.has(ServiceFlags::COMPACT_FILTERS)
                         && f.services.has(ServiceFlags::WITNESS)
                 })
-                .filter(|f| f.socket_addr().is_ok())
                 .filter(|f| f.time > last_month as u32)
-                .map(|addr| match addr.socket_addr().unwrap().ip() {
-                    std::net::IpAddr::V4(ip) => Address {
-                        services: addr.services,
-                        address: ip.to_ipv6_mapped().segments(),
-                        port: addr.port,
-                    },
-                    std::net::IpAddr::V6(ip) => Address {
-                        services: addr.services,
-                        address: ip.segments(),
-                        port: addr.port,
-                    },
-                })
+                // Tor V2 addresses are no longer reachable
+                .filter(|f| !matches!(f.addr, AddrV2::TorV2(_) | AddrV2::Unknown(_, _)))
+                .cloned()
                 .collect();
             Some(PeerMessage::Addr(addresses))
         }
@@ -209,40 +215,16 @@ fn parse_message(message: &NetworkMessage) -> Option<PeerMessage> {
     }
 }
 
-pub struct V1Header {
-    magic: Magic,
-    _command: [u8; 12],
-    length: u32,
-    _checksum: u32,
-}
-
-impl Decodable for V1Header {
-    fn consensus_decode<R: BufRead + ?Sized>(
-        reader: &mut R,
-    ) -> Result<Self, bitcoin::consensus::encode::Error> {
-        let magic = Magic::consensus_decode(reader)?;
-        let _command = <[u8; 12]>::consensus_decode(reader)?;
-        let length = u32::consensus_decode(reader)?;
-        let _checksum = u32::consensus_decode(reader)?;
-        Ok(Self {
-            magic,
-            _command,
-            length,
-            _checksum,
-        })
-    }
-}
-
 #[derive(Error, Debug)]
 pub enum PeerReadError {
     #[error("reading bytes off the stream failed")]
     ReadBuffer,
     #[error("the message could not be properly deserialized")]
     Deserialization,
+    #[error("the message could not be decrypted or authenticated")]
+    Decryption,
     #[error("DOS protection")]
     TooManyMessages,
-    #[error("peer timeout")]
-    PeerTimeout,
     #[error("sending over the channel failed")]
     MpscChannel,
 }
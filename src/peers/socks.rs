@@ -0,0 +1,209 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bitcoin::p2p::address::AddrV2;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::addrv2::to_host;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const CONNECT: u8 = 0x01;
+const RESERVED: u8 = 0x00;
+const SUCCEEDED: u8 = 0x00;
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+// Tor may take a while to build a circuit to a hidden service
+const PROXY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Open a TCP stream to a peer through a SOCKS5 proxy, as described in RFC 1928
+pub(crate) async fn connect(
+    proxy: SocketAddr,
+    addr: &AddrV2,
+    port: u16,
+) -> Result<TcpStream, Socks5Error> {
+    tokio::time::timeout(PROXY_TIMEOUT, handshake(proxy, addr, port))
+        .await
+        .map_err(|_| Socks5Error::Timeout)?
+}
+
+async fn handshake(proxy: SocketAddr, addr: &AddrV2, port: u16) -> Result<TcpStream, Socks5Error> {
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .map_err(|_| Socks5Error::ProxyUnreachable)?;
+    stream
+        .write_all(&[SOCKS5_VERSION, 1, NO_AUTHENTICATION])
+        .await
+        .map_err(|_| Socks5Error::Io)?;
+    let mut method = [0_u8; 2];
+    stream
+        .read_exact(&mut method)
+        .await
+        .map_err(|_| Socks5Error::Io)?;
+    if method[0] != SOCKS5_VERSION || method[1] != NO_AUTHENTICATION {
+        return Err(Socks5Error::AuthenticationRequired);
+    }
+    let mut request = vec![SOCKS5_VERSION, CONNECT, RESERVED];
+    match addr {
+        AddrV2::Ipv4(ip) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        AddrV2::Ipv6(ip) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        // The proxy would route a CJDNS address over the clearnet as if it were IPv6
+        AddrV2::Cjdns(_) => return Err(Socks5Error::UnsupportedAddress),
+        _ => {
+            let host = to_host(addr).ok_or(Socks5Error::UnsupportedAddress)?;
+            request.push(DOMAIN_NAME);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|_| Socks5Error::Io)?;
+    let mut reply = [0_u8; 4];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(|_| Socks5Error::Io)?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(Socks5Error::Io);
+    }
+    if reply[1] != SUCCEEDED {
+        return Err(Socks5Error::ConnectionRefused(reply[1]));
+    }
+    // The address the proxy bound to is of no use to us, but must be read before the peer's first message
+    let bound_len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => {
+            let mut len = [0_u8; 1];
+            stream
+                .read_exact(&mut len)
+                .await
+                .map_err(|_| Socks5Error::Io)?;
+            len[0] as usize
+        }
+        _ => return Err(Socks5Error::Io),
+    };
+    let mut bound = vec![0_u8; bound_len + 2];
+    stream
+        .read_exact(&mut bound)
+        .await
+        .map_err(|_| Socks5Error::Io)?;
+    Ok(stream)
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum Socks5Error {
+    #[error("the proxy could not be reached")]
+    ProxyUnreachable,
+    #[error("the proxy requires authentication")]
+    AuthenticationRequired,
+    #[error("the address cannot be sent to a proxy")]
+    UnsupportedAddress,
+    #[error("the proxy could not connect to the peer, reply code {0}")]
+    ConnectionRefused(u8),
+    #[error("the proxy did not respond in time")]
+    Timeout,
+    #[error("reading or writing to the proxy failed")]
+    Io,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio::net::TcpListener;
+
+    use crate::peers::addrv2::from_host;
+
+    use super::*;
+
+    const ONION: &str = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    // Act as a SOCKS5 proxy for a single connection, returning the requested destination
+    async fn proxy_once(listener: TcpListener, reply: u8) -> (u8, Vec<u8>, u16) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0_u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [SOCKS5_VERSION, 1, NO_AUTHENTICATION]);
+        stream
+            .write_all(&[SOCKS5_VERSION, NO_AUTHENTICATION])
+            .await
+            .unwrap();
+        let mut request = [0_u8; 4];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..3], [SOCKS5_VERSION, CONNECT, RESERVED]);
+        let len = match request[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            _ => stream.read_u8().await.unwrap() as usize,
+        };
+        let mut destination = vec![0_u8; len];
+        stream.read_exact(&mut destination).await.unwrap();
+        let port = stream.read_u16().await.unwrap();
+        stream
+            .write_all(&[SOCKS5_VERSION, reply, RESERVED, IPV6])
+            .await
+            .unwrap();
+        stream.write_all(&[0; 18]).await.unwrap();
+        if reply == SUCCEEDED {
+            // Everything after the reply belongs to the peer
+            stream.write_all(b"peer").await.unwrap();
+        }
+        (request[3], destination, port)
+    }
+
+    #[tokio::test]
+    async fn test_connects_to_onion_peers() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let stand_in = tokio::spawn(proxy_once(listener, SUCCEEDED));
+        let addr = from_host(ONION).unwrap();
+        let mut stream = connect(proxy, &addr, 8333).await.unwrap();
+        let mut first_message = [0_u8; 4];
+        stream.read_exact(&mut first_message).await.unwrap();
+        assert_eq!(&first_message, b"peer");
+        let (address_type, destination, port) = stand_in.await.unwrap();
+        assert_eq!(address_type, DOMAIN_NAME);
+        assert_eq!(destination, ONION.as_bytes());
+        assert_eq!(port, 8333);
+    }
+
+    #[tokio::test]
+    async fn test_sends_ip_addresses() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let stand_in = tokio::spawn(proxy_once(listener, SUCCEEDED));
+        let ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        connect(proxy, &AddrV2::Ipv6(ip), 38333).await.unwrap();
+        let (address_type, destination, port) = stand_in.await.unwrap();
+        assert_eq!(address_type, IPV6);
+        assert_eq!(destination, ip.octets());
+        assert_eq!(port, 38333);
+    }
+
+    #[tokio::test]
+    async fn test_reports_refused_connections() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        // Host unreachable
+        tokio::spawn(proxy_once(listener, 0x04));
+        let addr = AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4));
+        assert!(matches!(
+            connect(proxy, &addr, 8333).await,
+            Err(Socks5Error::ConnectionRefused(0x04))
+        ));
+    }
+}
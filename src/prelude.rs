@@ -1,9 +1,13 @@
 use std::net::IpAddr;
 
-use bitcoin::{params::Params, Network};
+use bitcoin::{p2p::address::AddrV2, params::Params, Network};
 
 pub const MAX_FUTURE_BLOCK_TIME: i64 = 60 * 60 * 2;
+// Peers may not adjust our clock by more than 70 minutes
+pub const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
 pub const MEDIAN_TIME_PAST: usize = 11;
+// The clock is only adjusted once this many peers told us their time
+pub const MIN_TIME_SAMPLES: usize = 5;
 pub trait Median<T> {
     fn median(&mut self) -> Option<T>;
 }
@@ -18,7 +22,8 @@ impl Median<i64> for Vec<i64> {
             Some(self[len / 2])
         } else {
             let mid = len / 2;
-            Some((self[mid - 1] + self[mid]) / 2)
+            // Widen before adding so the sum of the middle values cannot overflow
+            Some(((self[mid - 1] as i128 + self[mid] as i128) / 2) as i64)
         }
     }
 }
@@ -33,7 +38,7 @@ impl Median<u64> for Vec<u64> {
             Some(self[len / 2])
         } else {
             let mid = len / 2;
-            Some((self[mid - 1] + self[mid]) / 2)
+            Some(((self[mid - 1] as u128 + self[mid] as u128) / 2) as u64)
         }
     }
 }
@@ -48,7 +53,7 @@ impl Median<u32> for Vec<u32> {
             Some(self[len / 2])
         } else {
             let mid = len / 2;
-            Some((self[mid - 1] + self[mid]) / 2)
+            Some(((self[mid - 1] as u64 + self[mid] as u64) / 2) as u32)
         }
     }
 }
@@ -71,9 +76,23 @@ impl SlashSixteen for IpAddr {
     }
 }
 
+impl SlashSixteen for AddrV2 {
+    fn slash_sixteen(&mut self) -> String {
+        match self {
+            AddrV2::Ipv4(ip) => IpAddr::V4(*ip).slash_sixteen(),
+            AddrV2::Ipv6(ip) => IpAddr::V6(*ip).slash_sixteen(),
+            // These addresses say nothing about who operates the peer, so peers are grouped by their leading bits
+            AddrV2::TorV3(pubkey) => format!("ONION{}", pubkey[0] >> 4),
+            AddrV2::I2p(hash) => format!("I2P{}", hash[0] >> 4),
+            AddrV2::Cjdns(ip) => format!("CJDNS{}", ip.octets()[1] >> 4),
+            _ => "UNKNOWN".to_string(),
+        }
+    }
+}
+
 pub(crate) fn params_from_network(network: &Network) -> Params {
     match network {
-        Network::Bitcoin => panic!("unimplemented network"),
+        Network::Bitcoin => Params::new(*network),
         Network::Testnet => Params::new(*network),
         Network::Signet => Params::new(*network),
         Network::Regtest => Params::new(*network),
@@ -90,3 +109,17 @@ pub(crate) fn default_port_from_network(network: &Network) -> u16 {
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median_of_extreme_values() {
+        assert_eq!(vec![i64::MAX, i64::MAX].median(), Some(i64::MAX));
+        assert_eq!(vec![i64::MIN, i64::MIN].median(), Some(i64::MIN));
+        assert_eq!(vec![i64::MIN, i64::MAX].median(), Some(0));
+        assert_eq!(vec![u64::MAX, u64::MAX - 2].median(), Some(u64::MAX - 1));
+        assert_eq!(vec![u32::MAX, u32::MAX].median(), Some(u32::MAX));
+    }
+}